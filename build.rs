@@ -0,0 +1,25 @@
+use std::env::var;
+use std::path::Path;
+use wayland_scanner::{generate_code, Side};
+
+// Protocols that are too new to be shipped with wayland-protocols 0.29
+static PROTOCOLS: &[&str] = &[
+    "ext-idle-notify-v1",
+    "ext-image-capture-source-v1",
+    "ext-image-copy-capture-v1",
+];
+
+fn main() {
+    let out_dir = var("OUT_DIR").unwrap();
+
+    for name in PROTOCOLS {
+        let protocol_file = Path::new("./protocols").join(format!("{}.xml", name));
+        println!("cargo:rerun-if-changed={}", protocol_file.display());
+
+        generate_code(
+            protocol_file,
+            Path::new(&out_dir).join(format!("{}_client_api.rs", name)),
+            Side::Client,
+        );
+    }
+}
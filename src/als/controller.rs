@@ -1,18 +1,156 @@
-use super::Als;
-use std::sync::mpsc::Sender;
+use super::{find_profile, Als, Lux, Scale, WAITING_SLEEP_MS};
+use itertools::Itertools;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
 use std::time::Duration;
 
-const WAITING_SLEEP_MS: u64 = 100;
+// Smooths out noise of raw readings before they get mapped
+#[derive(Debug, Clone)]
+pub enum Filter {
+    None,
+    Ema {
+        alpha: f64,
+        value: Option<f64>,
+    },
+    Median {
+        window: usize,
+        values: VecDeque<u64>,
+    },
+}
 
-pub struct Controller {
+impl Filter {
+    pub fn ema(alpha: f64) -> Self {
+        Filter::Ema { alpha, value: None }
+    }
+
+    pub fn median(window: usize) -> Self {
+        Filter::Median {
+            window,
+            values: VecDeque::with_capacity(window),
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            Filter::None => {}
+            Filter::Ema { value, .. } => *value = None,
+            Filter::Median { values, .. } => values.clear(),
+        }
+    }
+
+    fn apply(&mut self, raw: u64) -> u64 {
+        match self {
+            Filter::None => raw,
+            Filter::Ema { alpha, value } => {
+                let smoothed = value.map_or(raw as f64, |value| {
+                    *alpha * raw as f64 + (1.0 - *alpha) * value
+                });
+                *value = Some(smoothed);
+                smoothed.round() as u64
+            }
+            Filter::Median { window, values } => {
+                if values.len() == *window {
+                    values.pop_front();
+                }
+                values.push_back(raw);
+
+                let mut sorted = values.iter().copied().collect::<Vec<_>>();
+                sorted.sort_unstable();
+                sorted[(sorted.len() - 1) / 2]
+            }
+        }
+    }
+}
+
+// One of the sensors, the most preferred ones (lowest priority) that work are fused by weight
+pub struct Source {
     als: Box<dyn Als>,
-    value_txs: Vec<Sender<String>>,
+    scale: Scale,
+    weight: f64,
+    priority: u32,
+    color_thresholds: HashMap<u64, String>,
+    filter: Filter,
+    // Hysteresis applies to the profiles of the thresholds only
+    last_value: Option<Lux>,
+}
+
+impl Source {
+    pub fn new(als: Box<dyn Als>, scale: Scale, weight: f64, priority: u32) -> Self {
+        Self {
+            als,
+            scale,
+            weight,
+            priority,
+            color_thresholds: HashMap::new(),
+            filter: Filter::None,
+            last_value: None,
+        }
+    }
+
+    // Profiles get split further by the color temperature of the light, e.g. "dim warm"
+    pub fn with_color_thresholds(mut self, color_thresholds: HashMap<u64, String>) -> Self {
+        self.color_thresholds = color_thresholds;
+        self
+    }
+
+    fn colored(&self, value: Lux) -> Lux {
+        let color = || {
+            if self.color_thresholds.is_empty() {
+                return None;
+            }
+            let cct = self.als.color_temperature()?;
+            let color = find_profile(cct, &self.color_thresholds).ok()?;
+            log::trace!("ALS color temperature: {}K ({})", cct, color);
+            Some(color)
+        };
+
+        match value {
+            Lux::Profile(profile) => match color() {
+                Some(color) => Lux::Profile(format!("{} {}", profile, color)),
+                None => Lux::Profile(profile),
+            },
+            value => value,
+        }
+    }
+
+    // Reading smoothed and mapped through the scale of this source
+    fn read(&mut self) -> Result<Lux, Box<dyn Error>> {
+        let raw = self.als.get()?;
+        let smoothed = self.filter.apply(raw);
+        let value = self.scale.lux(smoothed, self.last_value.as_ref())?;
+        log::trace!("ALS: {} ({}, raw {})", value, smoothed, raw);
+
+        self.last_value = Some(value.clone());
+        Ok(value)
+    }
+}
+
+pub struct Controller {
+    sources: Vec<Source>,
+    // Priority of the sources in use
+    active: Option<u32>,
+    value_txs: Vec<Sender<Lux>>,
+    subscribe_rx: Receiver<Sender<Lux>>,
 }
 
 impl Controller {
-    pub fn new(als: Box<dyn Als>, value_txs: Vec<Sender<String>>) -> Self {
-        Self { als, value_txs }
+    pub fn new(
+        mut sources: Vec<Source>,
+        filter: Filter,
+        subscribe_rx: Receiver<Sender<Lux>>,
+    ) -> Self {
+        for source in &mut sources {
+            source.filter = filter.clone();
+        }
+
+        Self {
+            sources,
+            active: None,
+            value_txs: Vec::new(),
+            subscribe_rx,
+        }
     }
 
     pub fn run(&mut self) {
@@ -22,16 +160,389 @@ impl Controller {
     }
 
     fn step(&mut self) {
-        match self.als.get() {
-            Ok(value) => {
-                self.value_txs.iter().for_each(|chan| {
-                    chan.send(value.clone())
-                        .expect("Unable to send new ALS value, channel is dead")
-                });
+        self.value_txs.extend(self.subscribe_rx.try_iter());
+
+        match self.read() {
+            Some(value) => {
+                // Channel of a disconnected output is dead, stop sending values to it
+                self.value_txs
+                    .retain(|chan| chan.send(value.clone()).is_ok());
+            }
+            None => log::error!("Unable to get ALS value from any source"),
+        }
+
+        // Push-based sensors are only worth waiting for when they are the main source
+        let priority = self
+            .active
+            .or_else(|| self.sources.iter().map(|source| source.priority).min());
+        match self
+            .sources
+            .iter()
+            .find(|source| Some(source.priority) == priority)
+        {
+            Some(source) => source.als.wait(),
+            None => thread::sleep(Duration::from_millis(WAITING_SLEEP_MS)),
+        }
+    }
+
+    // Fused value of the most preferred sources that work, lower priority ones are only asked
+    // when all of those fail. Continuous values are averaged by their level, as perceived, while
+    // profiles are voted on.
+    fn read(&mut self) -> Option<Lux> {
+        let priorities = self
+            .sources
+            .iter()
+            .map(|source| source.priority)
+            .sorted()
+            .dedup()
+            .collect_vec();
+
+        for priority in priorities {
+            let active = self.active;
+            let readings = self
+                .sources
+                .iter_mut()
+                .enumerate()
+                .filter(|(_, source)| source.priority == priority)
+                .filter_map(|(i, source)| {
+                    // Readings of other sources are not necessarily comparable
+                    if active != Some(priority) {
+                        source.filter.reset();
+                    }
+                    match source.read() {
+                        Ok(value) => Some((i, source.weight, value)),
+                        Err(err) => {
+                            log::debug!("Unable to get value of ALS source #{}: {}", i + 1, err);
+                            None
+                        }
+                    }
+                })
+                .collect_vec();
+
+            if readings.is_empty() {
+                continue;
             }
-            Err(err) => log::error!("Unable to get ALS value: {:?}", err),
+
+            if self.active != Some(priority) {
+                log::debug!("Using ALS sources of priority {}", priority);
+                self.active = Some(priority);
+            }
+
+            return Some(self.fuse(readings));
+        }
+
+        None
+    }
+
+    fn fuse(&self, readings: Vec<(usize, f64, Lux)>) -> Lux {
+        let levels = readings
+            .iter()
+            .filter_map(|(_, weight, value)| match value {
+                Lux::Value(lux) => Some((*weight, Lux::level(*lux))),
+                Lux::Profile(_) => None,
+            })
+            .collect_vec();
+
+        // Sources of the same priority are either all continuous or not, see config validation
+        if levels.len() == readings.len() {
+            let weights = levels.iter().map(|(weight, _)| weight).sum::<f64>();
+            let level = levels
+                .iter()
+                .map(|(weight, level)| weight * level)
+                .sum::<f64>()
+                / weights;
+            return Lux::Value(Lux::from_level(level));
+        }
+
+        // Ties go to the source listed first, which also tells the color of the light
+        let mut votes: Vec<(usize, &String, f64)> = Vec::new();
+        for (i, weight, value) in &readings {
+            if let Lux::Profile(profile) = value {
+                match votes.iter_mut().find(|(_, p, _)| *p == profile) {
+                    Some(vote) => vote.2 += weight,
+                    None => votes.push((*i, profile, *weight)),
+                }
+            }
+        }
+        let (i, profile, _) = votes
+            .into_iter()
+            .rev()
+            .max_by(|a, b| a.2.total_cmp(&b.2))
+            .expect("Profiles are voted on only when there are some");
+
+        self.sources[i].colored(Lux::Profile(profile.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::als::MockAls;
+    use std::collections::HashMap;
+    use std::sync::mpsc;
+
+    fn thresholds() -> HashMap<u64, String> {
+        HashMap::from([
+            (0, "night".to_string()),
+            (20, "dark".to_string()),
+            (80, "dim".to_string()),
+            (250, "normal".to_string()),
+        ])
+    }
+
+    // Source returning the given readings in order, where None is an error
+    fn source(readings: Vec<Option<u64>>, scale: Scale, weight: f64, priority: u32) -> Source {
+        let mut readings = readings.into_iter();
+        let mut als = MockAls::new();
+        als.expect_get().returning(move || {
+            readings
+                .next()
+                .unwrap()
+                .ok_or_else(|| "Sensor is gone".into())
+        });
+        als.expect_wait().return_const(());
+        Source::new(Box::new(als), scale, weight, priority)
+    }
+
+    // Values sent for the given number of steps
+    fn values(sources: Vec<Source>, filter: Filter, steps: usize) -> Vec<String> {
+        let (subscribe_tx, subscribe_rx) = mpsc::channel();
+        let (value_tx, value_rx) = mpsc::channel();
+        subscribe_tx.send(value_tx).unwrap();
+
+        let mut controller = Controller::new(sources, filter, subscribe_rx);
+        for _ in 0..steps {
+            controller.step();
+        }
+
+        value_rx.try_iter().map(|value| value.to_string()).collect()
+    }
+
+    // Profiles sent for the given sequence of raw readings of a single source
+    fn profiles(raw: Vec<u64>, scale: Scale, filter: Filter) -> Vec<String> {
+        let steps = raw.len();
+        let readings = raw.into_iter().map(Some).collect();
+        values(vec![source(readings, scale, 1.0, 0)], filter, steps)
+    }
+
+    #[test]
+    fn test_profile_flaps_around_threshold_without_hysteresis() {
+        let scale = Scale::Profiles {
+            thresholds: thresholds(),
+            hysteresis: HashMap::new(),
+        };
+
+        assert_eq!(
+            vec!["dark", "dim", "dark", "dim", "dark"],
+            profiles(vec![78, 82, 77, 83, 79], scale, Filter::None)
+        );
+    }
+
+    #[test]
+    fn test_hysteresis_keeps_profile_around_threshold() {
+        let scale = Scale::Profiles {
+            thresholds: thresholds(),
+            hysteresis: HashMap::from([(80, 10)]),
+        };
+
+        assert_eq!(
+            vec!["dark", "dark", "dark", "dark", "dim", "dim", "dim", "dark"],
+            profiles(vec![78, 82, 77, 89, 90, 75, 71, 69], scale, Filter::None)
+        );
+    }
+
+    #[test]
+    fn test_hysteresis_does_not_delay_distant_changes() {
+        let scale = Scale::Profiles {
+            thresholds: thresholds(),
+            hysteresis: HashMap::from([(80, 10), (250, 30)]),
+        };
+
+        assert_eq!(
+            vec!["dark", "normal", "night"],
+            profiles(vec![50, 500, 5], scale, Filter::None)
+        );
+    }
+
+    #[test]
+    fn test_median_filter_ignores_spikes() {
+        let scale = Scale::Profiles {
+            thresholds: thresholds(),
+            hysteresis: HashMap::new(),
+        };
+
+        assert_eq!(
+            vec!["dark", "dark", "dark", "dark", "dark", "dark", "dim"],
+            profiles(
+                vec![50, 50, 300, 50, 50, 100, 100],
+                scale,
+                Filter::median(3)
+            )
+        );
+    }
+
+    #[test]
+    fn test_ema_filter_smooths_out_noise() {
+        let scale = Scale::Profiles {
+            thresholds: thresholds(),
+            hysteresis: HashMap::new(),
+        };
+
+        assert_eq!(
+            vec!["dark", "dark", "dark", "dark", "dark"],
+            profiles(vec![70, 90, 70, 90, 70], scale, Filter::ema(0.3))
+        );
+    }
+
+    #[test]
+    fn test_filter_apply() {
+        let mut ema = Filter::ema(0.5);
+        assert_eq!(
+            vec![100, 50, 25, 63],
+            [100, 0, 0, 100].map(|raw| ema.apply(raw)).to_vec()
+        );
+
+        let mut median = Filter::median(3);
+        assert_eq!(
+            vec![5, 5, 5, 9, 9],
+            [5, 100, 1, 9, 10].map(|raw| median.apply(raw)).to_vec()
+        );
+    }
+
+    #[test]
+    fn test_sources_with_same_priority_are_averaged_by_weight() {
+        // Levels of 99, 999 and 9999 lux are 40, 60 and 80
+        let sources = vec![
+            source(vec![Some(99), Some(999)], Scale::Continuous, 3.0, 0),
+            source(vec![Some(9999), Some(999)], Scale::Continuous, 1.0, 0),
+        ];
+
+        assert_eq!(vec!["315 lux", "999 lux"], values(sources, Filter::None, 2));
+    }
+
+    #[test]
+    fn test_profiles_of_same_priority_are_voted_on_by_weight() {
+        let scale = || Scale::Profiles {
+            thresholds: thresholds(),
+            hysteresis: HashMap::new(),
+        };
+        let sources = vec![
+            source(vec![Some(50), Some(50), Some(50)], scale(), 1.0, 0),
+            source(vec![Some(100), Some(100), Some(50)], scale(), 1.0, 0),
+            source(vec![Some(300), Some(100), Some(300)], scale(), 1.0, 0),
+        ];
+
+        assert_eq!(
+            vec!["dark", "dim", "dark"],
+            values(sources, Filter::None, 3)
+        );
+    }
+
+    #[test]
+    fn test_sources_are_mapped_through_their_own_scale() {
+        let percent = Scale::Profiles {
+            thresholds: HashMap::from([(0, "dark".to_string()), (50, "dim".to_string())]),
+            hysteresis: HashMap::new(),
+        };
+        let lux = Scale::Profiles {
+            thresholds: thresholds(),
+            hysteresis: HashMap::new(),
+        };
+        let sources = vec![
+            source(vec![Some(60)], percent, 1.0, 0),
+            source(vec![Some(100)], lux, 1.0, 0),
+        ];
+
+        assert_eq!(vec!["dim"], values(sources, Filter::None, 1));
+    }
+
+    #[test]
+    fn test_failing_sources_are_left_out() {
+        let sources = vec![
+            source(vec![None, Some(99)], Scale::Continuous, 3.0, 0),
+            source(vec![Some(9999), Some(9999)], Scale::Continuous, 1.0, 0),
+        ];
+
+        assert_eq!(
+            vec!["9999 lux", "315 lux"],
+            values(sources, Filter::None, 2)
+        );
+    }
+
+    #[test]
+    fn test_falls_back_to_lower_priority_sources() {
+        let time = Scale::Profiles {
+            thresholds: HashMap::from([(0, "night".to_string()), (7, "day".to_string())]),
+            hysteresis: HashMap::new(),
+        };
+        let sources = vec![
+            source(vec![Some(500), None, Some(600)], Scale::Continuous, 1.0, 0),
+            source(vec![Some(12)], time, 1.0, 1),
+        ];
+
+        assert_eq!(
+            vec!["500 lux", "day", "600 lux"],
+            values(sources, Filter::None, 3)
+        );
+    }
+
+    #[test]
+    fn test_filter_is_reset_when_switching_sources() {
+        let sources = vec![
+            source(vec![Some(500), None, Some(600)], Scale::Continuous, 1.0, 0),
+            source(vec![Some(12)], Scale::Continuous, 1.0, 1),
+        ];
+
+        assert_eq!(
+            vec!["500 lux", "12 lux", "600 lux"],
+            values(sources, Filter::ema(0.5), 3)
+        );
+    }
+
+    #[test]
+    fn test_profiles_are_split_by_color_temperature() {
+        let scale = Scale::Profiles {
+            thresholds: thresholds(),
+            hysteresis: HashMap::from([(80, 10)]),
+        };
+        let mut temperatures = vec![Some(2700), Some(6500), None].into_iter();
+        let mut als = MockAls::new();
+        let mut readings = vec![85, 75, 75].into_iter();
+        als.expect_get()
+            .returning(move || Ok(readings.next().unwrap()));
+        als.expect_color_temperature()
+            .returning(move || temperatures.next().unwrap());
+        als.expect_wait().return_const(());
+        let source =
+            Source::new(Box::new(als), scale, 1.0, 0).with_color_thresholds(HashMap::from([
+                (0, "warm".to_string()),
+                (4500, "cold".to_string()),
+            ]));
+
+        assert_eq!(
+            vec!["dim warm", "dim cold", "dim"],
+            values(vec![source], Filter::None, 3)
+        );
+    }
+
+    #[test]
+    fn test_no_value_without_thresholds() {
+        let scale = Scale::Profiles {
+            thresholds: HashMap::new(),
+            hysteresis: HashMap::new(),
         };
+        let sources = vec![source(vec![Some(10)], scale, 1.0, 0)];
+
+        assert_eq!(Vec::<String>::new(), values(sources, Filter::None, 1));
+    }
+
+    #[test]
+    fn test_no_value_when_all_sources_fail() {
+        let sources = vec![
+            source(vec![None], Scale::Continuous, 1.0, 0),
+            source(vec![None], Scale::Continuous, 1.0, 1),
+        ];
 
-        thread::sleep(Duration::from_millis(WAITING_SLEEP_MS));
+        assert_eq!(Vec::<String>::new(), values(sources, Filter::None, 1));
     }
 }
@@ -0,0 +1,192 @@
+use std::error::Error;
+use zbus::blocking::{Connection, Proxy};
+
+const SENSOR_PROXY_DESTINATION: &str = "net.hadess.SensorProxy";
+const SENSOR_PROXY_PATH: &str = "/net/hadess/SensorProxy";
+const SENSOR_PROXY_INTERFACE: &str = "net.hadess.SensorProxy";
+
+// Sensor shared through iio-sensor-proxy, which keeps the device to itself while running
+pub struct Als {
+    proxy: Proxy<'static>,
+}
+
+impl Als {
+    pub fn new(connection: &Connection) -> Result<Self, Box<dyn Error>> {
+        let proxy = Proxy::new(
+            connection,
+            SENSOR_PROXY_DESTINATION,
+            SENSOR_PROXY_PATH,
+            SENSOR_PROXY_INTERFACE,
+        )?;
+
+        if !proxy.get_property::<bool>("HasAmbientLight")? {
+            return Err("iio-sensor-proxy has no ambient light sensor".into());
+        }
+
+        // Light level stays at 0 and its changes are not signaled until the sensor is claimed
+        proxy.call_method("ClaimLight", &())?;
+
+        let unit = proxy.get_property::<String>("LightLevelUnit")?;
+        if unit != "lux" {
+            log::warn!(
+                "iio-sensor-proxy reports light level in '{}' rather than lux, ALS thresholds must use the same unit",
+                unit
+            );
+        }
+
+        Ok(Self { proxy })
+    }
+
+    // Kept up to date from PropertiesChanged signals, only asked for until the cache is filled
+    fn light_level(&self) -> Result<f64, Box<dyn Error>> {
+        match self.proxy.cached_property::<f64>("LightLevel")? {
+            Some(level) => Ok(level),
+            None => Ok(self.proxy.get_property::<f64>("LightLevel")?),
+        }
+    }
+}
+
+impl super::Als for Als {
+    fn get(&self) -> Result<u64, Box<dyn Error>> {
+        let raw = self.light_level()? as u64;
+
+        log::trace!("ALS (dbus): {}", raw);
+        Ok(raw)
+    }
+}
+
+impl Drop for Als {
+    fn drop(&mut self) {
+        let _ = self.proxy.call_method("ReleaseLight", &());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::als::Als as _;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use zbus::blocking::connection::Builder;
+    use zbus::{Guid, SignalContext};
+
+    struct MockSensorProxy {
+        has_ambient_light: bool,
+        light_level: f64,
+        light_level_unit: String,
+        claimed: Arc<AtomicBool>,
+    }
+
+    #[zbus::interface(name = "net.hadess.SensorProxy")]
+    impl MockSensorProxy {
+        fn claim_light(&self) {
+            self.claimed.store(true, Ordering::SeqCst);
+        }
+
+        fn release_light(&self) {
+            self.claimed.store(false, Ordering::SeqCst);
+        }
+
+        #[zbus(property)]
+        fn has_ambient_light(&self) -> bool {
+            self.has_ambient_light
+        }
+
+        #[zbus(property)]
+        fn light_level(&self) -> f64 {
+            self.light_level
+        }
+
+        #[zbus(property)]
+        fn light_level_unit(&self) -> String {
+            self.light_level_unit.clone()
+        }
+    }
+
+    // Service and client talk directly over a socket pair, so no bus daemon is needed
+    fn setup(
+        has_ambient_light: bool,
+        light_level: f64,
+    ) -> (Connection, Connection, Arc<AtomicBool>) {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let claimed = Arc::new(AtomicBool::new(false));
+        let sensor = MockSensorProxy {
+            has_ambient_light,
+            light_level,
+            light_level_unit: "lux".to_string(),
+            claimed: claimed.clone(),
+        };
+
+        let server = thread::spawn(move || {
+            Builder::unix_stream(server_stream)
+                .server(Guid::generate())
+                .unwrap()
+                .p2p()
+                .serve_at(SENSOR_PROXY_PATH, sensor)
+                .unwrap()
+                .build()
+                .unwrap()
+        });
+        let client = Builder::unix_stream(client_stream).p2p().build().unwrap();
+
+        (server.join().unwrap(), client, claimed)
+    }
+
+    fn set_light_level(server: &Connection, light_level: f64) {
+        let sensor = server
+            .object_server()
+            .interface::<_, MockSensorProxy>(SENSOR_PROXY_PATH)
+            .unwrap();
+        sensor.get_mut().light_level = light_level;
+        let context: &SignalContext = sensor.signal_context();
+        zbus::block_on(sensor.get().light_level_changed(context)).unwrap();
+    }
+
+    #[test]
+    fn test_claims_and_releases_light() {
+        let (_server, client, claimed) = setup(true, 20.0);
+
+        let als = Als::new(&client).unwrap();
+        assert!(claimed.load(Ordering::SeqCst));
+
+        drop(als);
+        assert!(!claimed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_get_returns_light_level() {
+        let (_server, client, _) = setup(true, 20.0);
+
+        let als = Als::new(&client).unwrap();
+
+        assert_eq!(20, als.get().unwrap());
+    }
+
+    #[test]
+    fn test_get_follows_light_level_changes() {
+        let (server, client, _) = setup(true, 20.0);
+        let als = Als::new(&client).unwrap();
+        assert_eq!(20, als.get().unwrap());
+
+        set_light_level(&server, 300.0);
+
+        let light_level = (0..50)
+            .map(|_| {
+                thread::sleep(Duration::from_millis(20));
+                als.get().unwrap()
+            })
+            .find(|light_level| *light_level == 300);
+        assert_eq!(Some(300), light_level);
+    }
+
+    #[test]
+    fn test_new_fails_without_ambient_light_sensor() {
+        let (_server, client, claimed) = setup(false, 0.0);
+
+        assert!(Als::new(&client).is_err());
+        assert!(!claimed.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,305 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+const WAIT_TIMEOUT_MS: u64 = 1000;
+
+// Runs a program periodically, its output is expected to start with the reading
+pub struct Command {
+    command: String,
+    interval: Duration,
+}
+
+impl Command {
+    pub fn new(command: String, interval: Duration) -> Self {
+        Self { command, interval }
+    }
+
+    pub fn run(&self, value_tx: Sender<u64>) {
+        loop {
+            match self.read() {
+                Ok(value) => {
+                    if value_tx.send(value).is_err() {
+                        return;
+                    }
+                }
+                Err(err) => log::debug!("Unable to get ALS value from '{}': {}", self.command, err),
+            }
+
+            thread::sleep(self.interval);
+        }
+    }
+
+    fn read(&self) -> Result<u64, Box<dyn Error>> {
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(format!("command failed with {}", output.status).into());
+        }
+        parse(&String::from_utf8_lossy(&output.stdout))
+    }
+}
+
+// Named pipe that another process writes readings to, one per line
+pub struct Fifo {
+    path: PathBuf,
+}
+
+impl Fifo {
+    pub fn new(path: &str) -> Result<Self, Box<dyn Error>> {
+        let path = PathBuf::from(path);
+        if !fs::metadata(&path)?.file_type().is_fifo() {
+            return Err(format!("{} is not a named pipe", path.display()).into());
+        }
+
+        Ok(Self { path })
+    }
+
+    pub fn run(&self, value_tx: Sender<u64>) -> Result<(), Box<dyn Error>> {
+        loop {
+            // Blocks until there is a writer, and reaches the end once the writer is gone
+            let file = File::open(&self.path)?;
+            if read_lines(file, &value_tx).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Unix socket that other processes connect to and write readings to, one per line
+pub struct Socket {
+    path: PathBuf,
+    listener: UnixListener,
+}
+
+impl Socket {
+    pub fn new(path: &str) -> Result<Self, Box<dyn Error>> {
+        let path = PathBuf::from(path);
+        // Socket left behind by a previous run would make binding fail
+        if let Ok(metadata) = fs::metadata(&path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(&path)?;
+            }
+        }
+        let listener = UnixListener::bind(&path)?;
+
+        Ok(Self { path, listener })
+    }
+
+    pub fn run(&self, value_tx: Sender<u64>) -> Result<(), Box<dyn Error>> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let value_tx = value_tx.clone();
+            thread::Builder::new()
+                .name("als-socket-client".to_string())
+                .spawn(move || {
+                    // Nobody listens to the values anymore, which the listener finds out too
+                    let _ = read_lines(stream, &value_tx);
+                })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Fails only when nobody listens to the values anymore, bad lines are skipped
+fn read_lines(reader: impl Read, value_tx: &Sender<u64>) -> Result<(), Box<dyn Error>> {
+    for line in BufReader::new(reader).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                log::debug!("Unable to read ALS value: {}", err);
+                return Ok(());
+            }
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse(&line) {
+            Ok(value) => value_tx.send(value)?,
+            Err(err) => log::debug!("Ignoring ALS value '{}': {}", line, err),
+        }
+    }
+
+    Ok(())
+}
+
+// First word of the text, so that units or other details can follow the number
+fn parse(text: &str) -> Result<u64, Box<dyn Error>> {
+    let value = text
+        .split_whitespace()
+        .next()
+        .ok_or("no value")?
+        .parse::<f64>()?;
+
+    if !value.is_finite() || value < 0.0 {
+        return Err(format!("{} is not a valid reading", value).into());
+    }
+    Ok(value.round() as u64)
+}
+
+pub struct Als {
+    value_rx: Receiver<u64>,
+    lux: RefCell<Option<u64>>,
+}
+
+impl Als {
+    pub fn new(value_rx: Receiver<u64>) -> Self {
+        Self {
+            value_rx,
+            lux: RefCell::new(None),
+        }
+    }
+
+    fn get_raw(&self) -> Result<u64, Box<dyn Error>> {
+        if let Some(value) = self.value_rx.try_iter().last() {
+            *self.lux.borrow_mut() = Some(value);
+        }
+
+        match *self.lux.borrow() {
+            Some(value) => Ok(value),
+            None => Err("No value received from external ALS yet".into()),
+        }
+    }
+}
+
+impl super::Als for Als {
+    fn get(&self) -> Result<u64, Box<dyn Error>> {
+        let raw = self.get_raw()?;
+
+        log::trace!("ALS (external): {}", raw);
+        Ok(raw)
+    }
+
+    fn wait(&self) {
+        match self
+            .value_rx
+            .recv_timeout(Duration::from_millis(WAIT_TIMEOUT_MS))
+        {
+            Ok(value) => *self.lux.borrow_mut() = Some(value),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                // Reader is gone, fall back to checking in on subscribers periodically
+                thread::sleep(Duration::from_millis(WAIT_TIMEOUT_MS));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::als::Als as _;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+
+    fn socket_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("wluma-test-{}-{}.sock", name, std::process::id()))
+            .display()
+            .to_string()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(42, parse("42").unwrap());
+        assert_eq!(43, parse("  42.6 lux\n").unwrap());
+        assert!(parse("").is_err());
+        assert!(parse("dark").is_err());
+        assert!(parse("-1").is_err());
+        assert!(parse("NaN").is_err());
+    }
+
+    #[test]
+    fn test_read_lines_skips_bad_lines() {
+        let (value_tx, value_rx) = mpsc::channel();
+
+        read_lines("10\n\noops\n20 lux\n".as_bytes(), &value_tx).unwrap();
+
+        assert_eq!(vec![10, 20], value_rx.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_command_output_is_parsed() {
+        let command = Command::new("echo 123.4".to_string(), Duration::ZERO);
+
+        assert_eq!(123, command.read().unwrap());
+    }
+
+    #[test]
+    fn test_command_failure_is_an_error() {
+        let command = Command::new("echo 123; false".to_string(), Duration::ZERO);
+
+        assert!(command.read().is_err());
+    }
+
+    #[test]
+    fn test_socket_receives_values_from_clients() {
+        let path = socket_path("values");
+        let socket = Socket::new(&path).unwrap();
+        let (value_tx, value_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = socket.run(value_tx);
+        });
+
+        UnixStream::connect(&path)
+            .unwrap()
+            .write_all(b"300\n")
+            .unwrap();
+
+        let als = Als::new(value_rx);
+        als.wait();
+        assert_eq!(300, als.get().unwrap());
+    }
+
+    #[test]
+    fn test_socket_replaces_stale_socket() {
+        let path = socket_path("stale");
+        let stale = UnixListener::bind(&path).unwrap();
+        drop(stale);
+
+        let socket = Socket::new(&path).unwrap();
+        drop(socket);
+        assert!(fs::metadata(&path).is_err());
+    }
+
+    #[test]
+    fn test_fifo_must_be_a_named_pipe() {
+        let path = socket_path("fifo");
+        File::create(&path).unwrap();
+
+        assert!(Fifo::new(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_get_fails_until_first_value() {
+        let (value_tx, value_rx) = mpsc::channel();
+        let als = Als::new(value_rx);
+        assert!(als.get().is_err());
+
+        value_tx.send(5).unwrap();
+        value_tx.send(7).unwrap();
+
+        assert_eq!(7, als.get().unwrap());
+        assert_eq!(7, als.get().unwrap());
+    }
+}
@@ -1,96 +1,368 @@
 use crate::device_file::read;
-use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
-use SensorType::*;
 
-enum SensorType {
-    Illuminance {
-        value: Mutex<File>,
-        scale: f64,
-        offset: f64,
-    },
-    Intensity {
-        r: Mutex<File>,
-        g: Mutex<File>,
-        b: Mutex<File>,
-    },
+pub mod buffer;
+
+// Drivers name channels with or without an index
+const ILLUMINANCE_CHANNELS: [&str; 2] = ["in_illuminance", "in_illuminance0"];
+
+// Responses of the red, green and blue channels to the CIE XYZ tristimulus values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub x: [f64; 3],
+    pub y: [f64; 3],
+    pub z: [f64; 3],
+}
+
+impl Calibration {
+    // Y is the illuminance itself
+    fn illuminance(&self, rgb: Rgb) -> u64 {
+        let [_, y, _] = self.xyz(rgb);
+        y.max(0.0) as u64
+    }
+
+    fn color_temperature(&self, rgb: Rgb) -> Option<u64> {
+        color_temperature(self.xyz(rgb))
+    }
+
+    fn xyz(&self, rgb: Rgb) -> [f64; 3] {
+        let [r, g, b] = rgb.without_infrared();
+        [self.x, self.y, self.z].map(|[cr, cg, cb]| cr * r + cg * g + cb * b)
+    }
+}
+
+// Scaled values of the color channels
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rgb {
+    r: f64,
+    g: f64,
+    b: f64,
+    clear: Option<f64>,
+}
+
+impl Rgb {
+    // Color channels let infrared through too, which the clear channel sees only once
+    fn without_infrared(&self) -> [f64; 3] {
+        let ir = match self.clear {
+            Some(clear) => ((self.r + self.g + self.b - clear) / 2.0).max(0.0),
+            None => 0.0,
+        };
+        [self.r - ir, self.g - ir, self.b - ir]
+    }
+}
+
+// Latest values of a sensor, as sent by the buffered mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reading {
+    pub lux: u64,
+    pub color_temperature: Option<u64>,
+}
+
+struct Channel {
+    value: Mutex<File>,
+    scale: f64,
+    offset: f64,
+}
+
+impl Channel {
+    fn open(path: &Path, file: &str, scale: f64, offset: f64) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            value: Mutex::new(File::open(path.join(file))?),
+            scale,
+            offset,
+        })
+    }
+
+    fn read(&self) -> Result<f64, Box<dyn Error>> {
+        Ok((read(&mut self.value.lock().unwrap())? + self.offset) * self.scale)
+    }
+}
+
+struct Color {
+    r: Channel,
+    g: Channel,
+    b: Channel,
+    clear: Option<Channel>,
+}
+
+impl Color {
+    fn read(&self) -> Result<Rgb, Box<dyn Error>> {
+        Ok(Rgb {
+            r: self.r.read()?,
+            g: self.g.read()?,
+            b: self.b.read()?,
+            clear: self.clear.as_ref().map(Channel::read).transpose()?,
+        })
+    }
 }
 
 pub struct Als {
-    sensor: SensorType,
-    thresholds: HashMap<u64, String>,
+    illuminance: Option<Channel>,
+    color: Option<Color>,
+    calibration: Calibration,
 }
 
 impl Als {
-    pub fn new(base_path: &str, thresholds: HashMap<u64, String>) -> Result<Self, Box<dyn Error>> {
-        Path::new(base_path)
-            .read_dir()
-            .ok()
-            .and_then(|dir| {
-                dir.filter_map(|e| e.ok())
-                    .find(|e| {
-                        fs::read_to_string(e.path().join("name"))
-                            .unwrap_or_default()
-                            .trim()
-                            == "als"
-                    })
-                    .and_then(|e| {
-                        parse_illuminance(e.path())
-                            .or_else(|_| parse_intensity(e.path()))
-                            .ok()
-                    })
-            })
-            .map(|sensor| Self { sensor, thresholds })
-            .ok_or_else(|| "No iio device found".into())
+    pub fn new(base_path: &str, calibration: Calibration) -> Result<Self, Box<dyn Error>> {
+        let path = find_device(base_path).ok_or("No iio device found")?;
+        let illuminance = parse_illuminance(&path).ok();
+        let color = parse_color(&path).ok();
+
+        if illuminance.is_none() && color.is_none() {
+            return Err("No illuminance or intensity channels found on iio device".into());
+        }
+
+        Ok(Self {
+            illuminance,
+            color,
+            calibration,
+        })
     }
 
     fn get_raw(&self) -> Result<u64, Box<dyn Error>> {
-        Ok(match self.sensor {
-            Illuminance {
-                ref value,
-                scale,
-                offset,
-            } => (read(&mut value.lock().unwrap())? + offset) * scale,
-
-            Intensity {
-                ref r,
-                ref g,
-                ref b,
-            } => {
-                -0.32466 * read(&mut r.lock().unwrap())?
-                    + 1.57837 * read(&mut g.lock().unwrap())?
-                    + -0.73191 * read(&mut b.lock().unwrap())?
-            }
-        } as u64)
+        match (&self.illuminance, &self.color) {
+            (Some(illuminance), _) => Ok(illuminance.read()?.max(0.0) as u64),
+            (None, Some(color)) => Ok(self.calibration.illuminance(color.read()?)),
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+fn find_device(base_path: &str) -> Option<PathBuf> {
+    Path::new(base_path)
+        .read_dir()
+        .ok()?
+        .filter_map(|e| e.ok())
+        .find(|e| {
+            fs::read_to_string(e.path().join("name"))
+                .unwrap_or_default()
+                .trim()
+                == "als"
+        })
+        .map(|e| e.path())
+}
+
+fn illuminance(raw: f64, scale: f64, offset: f64) -> u64 {
+    ((raw + offset) * scale) as u64
+}
+
+// Based on the chromaticity of the light, with McCamy's approximation
+fn color_temperature([x, y, z]: [f64; 3]) -> Option<u64> {
+    let sum = x + y + z;
+    if sum <= 0.0 {
+        return None;
     }
+
+    let n = (x / sum - 0.3320) / (0.1858 - y / sum);
+    let cct = 449.0 * n.powi(3) + 3525.0 * n.powi(2) + 6823.3 * n + 5520.33;
+
+    (cct.is_finite() && cct > 0.0).then(|| cct.round() as u64)
+}
+
+// Scale of a channel, or the one shared by all channels of its type, if the driver has any
+fn scale(path: &Path, channel: &str, shared: &str) -> f64 {
+    [channel, shared]
+        .iter()
+        .find_map(|name| {
+            fs::read_to_string(path.join(format!("{}_scale", name)))
+                .ok()?
+                .trim()
+                .parse()
+                .ok()
+        })
+        .unwrap_or(1.0)
 }
 
 impl super::Als for Als {
-    fn get(&self) -> Result<String, Box<dyn Error>> {
+    fn get(&self) -> Result<u64, Box<dyn Error>> {
         let raw = self.get_raw()?;
-        let profile = super::find_profile(raw, &self.thresholds);
 
-        log::trace!("ALS (iio): {} ({})", profile, raw);
-        Ok(profile)
+        log::trace!("ALS (iio): {}", raw);
+        Ok(raw)
+    }
+
+    fn color_temperature(&self) -> Option<u64> {
+        let rgb = self
+            .color
+            .as_ref()?
+            .read()
+            .map_err(|err| log::debug!("Unable to read ALS color channels: {}", err))
+            .ok()?;
+
+        self.calibration.color_temperature(rgb)
     }
 }
 
-fn parse_illuminance(path: PathBuf) -> Result<SensorType, Box<dyn Error>> {
-    Ok(Illuminance {
-        value: Mutex::new(File::open(path.join("in_illuminance_raw"))?),
-        scale: read(&mut File::open(path.join("in_illuminance_scale"))?)?,
-        offset: read(&mut File::open(path.join("in_illuminance_offset"))?)?,
-    })
+// Processed value in lux is preferred, raw values need to be scaled and offset first
+fn parse_illuminance(path: &Path) -> Result<Channel, Box<dyn Error>> {
+    for name in ILLUMINANCE_CHANNELS {
+        if path.join(format!("{}_input", name)).exists() {
+            return Channel::open(path, &format!("{}_input", name), 1.0, 0.0);
+        }
+    }
+
+    for name in ILLUMINANCE_CHANNELS {
+        if path.join(format!("{}_raw", name)).exists() {
+            let offset = fs::read_to_string(path.join(format!("{}_offset", name)))
+                .ok()
+                .and_then(|offset| offset.trim().parse().ok())
+                .unwrap_or(0.0);
+            return Channel::open(
+                path,
+                &format!("{}_raw", name),
+                scale(path, name, "in_illuminance"),
+                offset,
+            );
+        }
+    }
+
+    Err("No illuminance channel found".into())
 }
 
-fn parse_intensity(path: PathBuf) -> Result<SensorType, Box<dyn Error>> {
-    Ok(Intensity {
-        r: Mutex::new(File::open(path.join("in_intensity_red_raw"))?),
-        g: Mutex::new(File::open(path.join("in_intensity_green_raw"))?),
-        b: Mutex::new(File::open(path.join("in_intensity_blue_raw"))?),
+fn parse_color(path: &Path) -> Result<Color, Box<dyn Error>> {
+    let channel = |name: &str| {
+        let name = format!("in_intensity_{}", name);
+        Channel::open(
+            path,
+            &format!("{}_raw", name),
+            scale(path, &name, "in_intensity"),
+            0.0,
+        )
+    };
+
+    Ok(Color {
+        r: channel("red")?,
+        g: channel("green")?,
+        b: channel("blue")?,
+        clear: channel("clear").ok(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::als::Als as _;
+
+    // Coefficients of the TCS3414 from the ams DN25 design note
+    pub(super) const CALIBRATION: Calibration = Calibration {
+        x: [-0.14282, 1.54924, -0.95641],
+        y: [-0.32466, 1.57837, -0.73191],
+        z: [-0.68202, 0.77073, 0.56332],
+    };
+
+    // Device directory laid out like sysfs, removed once the test is done
+    struct FakeDevice {
+        base_path: PathBuf,
+    }
+
+    impl FakeDevice {
+        fn new(test: &str, files: &[(&str, &str)]) -> Self {
+            let base_path = std::env::temp_dir().join(format!(
+                "wluma-iio-poll-{}-{}",
+                std::process::id(),
+                test
+            ));
+            let path = base_path.join("iio:device0");
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("name"), "als\n").unwrap();
+            for (file, content) in files {
+                fs::write(path.join(file), content).unwrap();
+            }
+            Self { base_path }
+        }
+
+        fn als(&self) -> Result<Als, Box<dyn Error>> {
+            Als::new(self.base_path.to_str().unwrap(), CALIBRATION)
+        }
+    }
+
+    impl Drop for FakeDevice {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base_path);
+        }
+    }
+
+    #[test]
+    fn test_color_temperature_of_standard_illuminants() {
+        // Daylight (D65) and incandescent light (A)
+        let d65 = color_temperature([0.95047, 1.0, 1.08883]).unwrap();
+        let a = color_temperature([1.09850, 1.0, 0.35585]).unwrap();
+
+        assert!((6400..6600).contains(&d65), "D65 is {}", d65);
+        assert!((2750..2950).contains(&a), "A is {}", a);
+        assert_eq!(None, color_temperature([0.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn test_clear_channel_removes_infrared() {
+        let rgb = Rgb {
+            r: 30.0,
+            g: 40.0,
+            b: 50.0,
+            clear: Some(100.0),
+        };
+        assert_eq!([20.0, 30.0, 40.0], rgb.without_infrared());
+
+        let rgb = Rgb { clear: None, ..rgb };
+        assert_eq!([30.0, 40.0, 50.0], rgb.without_infrared());
+    }
+
+    #[test]
+    fn test_input_is_preferred_over_raw() -> Result<(), Box<dyn Error>> {
+        let device = FakeDevice::new(
+            "input",
+            &[
+                ("in_illuminance0_input", "321\n"),
+                ("in_illuminance_raw", "5\n"),
+            ],
+        );
+
+        assert_eq!(321, device.als()?.get()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_illuminance_is_scaled_and_offset() -> Result<(), Box<dyn Error>> {
+        let device = FakeDevice::new(
+            "raw",
+            &[
+                ("in_illuminance_raw", "98\n"),
+                ("in_illuminance_scale", "0.5\n"),
+                ("in_illuminance_offset", "2\n"),
+            ],
+        );
+
+        assert_eq!(50, device.als()?.get()?);
+        assert_eq!(None, device.als()?.color_temperature());
+        Ok(())
+    }
+
+    #[test]
+    fn test_intensity_uses_calibration_and_scales() -> Result<(), Box<dyn Error>> {
+        let device = FakeDevice::new(
+            "intensity",
+            &[
+                ("in_intensity_red_raw", "0\n"),
+                ("in_intensity_green_raw", "50\n"),
+                ("in_intensity_blue_raw", "0\n"),
+                ("in_intensity_green_scale", "2\n"),
+            ],
+        );
+
+        let als = device.als()?;
+        assert_eq!(157, als.get()?);
+        assert!(als.color_temperature().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_fails_without_channels() {
+        let device = FakeDevice::new("empty", &[]);
+
+        assert!(device.als().is_err());
+    }
+}
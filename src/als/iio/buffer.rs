@@ -0,0 +1,675 @@
+use super::{Calibration, Reading, Rgb};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::time::Duration;
+
+// Scans the kernel keeps until they are read, only the latest one matters anyway
+const BUFFER_LENGTH: usize = 4;
+
+// New subscribers of the ALS controller are picked up at least this often
+const WAIT_TIMEOUT_MS: u64 = 1000;
+
+// Storage format of a channel in a scan, e.g. "le:u16/16>>0" or "be:s12/16X2>>4"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanType {
+    big_endian: bool,
+    signed: bool,
+    bits: u32,
+    storage_bits: u32,
+    repeat: u32,
+    shift: u32,
+}
+
+impl ScanType {
+    pub fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        let invalid = || format!("Invalid IIO scan element type '{}'", value.trim());
+
+        let (endianness, rest) = value.trim().split_once(':').ok_or_else(invalid)?;
+        let big_endian = match endianness {
+            "be" => true,
+            "le" => false,
+            _ => return Err(invalid().into()),
+        };
+
+        let signed = match rest.get(..1) {
+            Some("s") => true,
+            Some("u") => false,
+            _ => return Err(invalid().into()),
+        };
+
+        let (bits, rest) = rest[1..].split_once('/').ok_or_else(invalid)?;
+        let (storage, shift) = rest.split_once(">>").ok_or_else(invalid)?;
+        let (storage_bits, repeat) = match storage.split_once('X') {
+            Some((storage_bits, repeat)) => (storage_bits, repeat.parse()?),
+            None => (storage, 1),
+        };
+
+        let scan_type = Self {
+            big_endian,
+            signed,
+            bits: bits.parse()?,
+            storage_bits: storage_bits.parse()?,
+            repeat,
+            shift: shift.parse()?,
+        };
+
+        match scan_type.storage_bits {
+            8 | 16 | 32 | 64
+                if scan_type.bits > 0
+                    && scan_type.bits <= scan_type.storage_bits
+                    && scan_type.shift < scan_type.storage_bits
+                    && repeat > 0 =>
+            {
+                Ok(scan_type)
+            }
+            _ => Err(invalid().into()),
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        self.storage_bits as usize / 8
+    }
+
+    // Value of the first repetition of the channel
+    pub fn decode(&self, bytes: &[u8]) -> f64 {
+        let bytes = &bytes[..self.bytes()];
+        let fold = |raw: u64, byte: &u8| (raw << 8) | *byte as u64;
+        let raw = if self.big_endian {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        };
+
+        let raw = raw >> self.shift;
+        if self.bits == 64 {
+            return if self.signed {
+                raw as i64 as f64
+            } else {
+                raw as f64
+            };
+        }
+
+        let raw = raw & ((1 << self.bits) - 1);
+        if self.signed && raw & (1 << (self.bits - 1)) != 0 {
+            (raw as i64 - (1 << self.bits)) as f64
+        } else {
+            raw as f64
+        }
+    }
+}
+
+// Name, index and type of an enabled channel
+type Channel = (String, u32, ScanType);
+
+// Where each enabled channel is within a scan, which is padded so that every channel is aligned
+#[derive(Debug, PartialEq)]
+pub struct Layout {
+    channels: HashMap<String, (usize, ScanType)>,
+    size: usize,
+}
+
+impl Layout {
+    pub fn new(mut channels: Vec<Channel>) -> Self {
+        channels.sort_by_key(|(_, index, _)| *index);
+
+        let align = |offset: usize, bytes: usize| offset.div_ceil(bytes) * bytes;
+        let mut offset = 0;
+        let mut largest = 1;
+        let channels = channels
+            .into_iter()
+            .map(|(name, _, scan_type)| {
+                let bytes = scan_type.bytes();
+                let start = align(offset, bytes);
+                offset = start + bytes * scan_type.repeat as usize;
+                largest = largest.max(bytes);
+                (name, (start, scan_type))
+            })
+            .collect();
+
+        Self {
+            channels,
+            size: align(offset, largest),
+        }
+    }
+
+    pub fn read(&self, scan: &[u8], channel: &str) -> Result<f64, Box<dyn Error>> {
+        let (offset, scan_type) = self
+            .channels
+            .get(channel)
+            .ok_or_else(|| format!("IIO channel '{}' is not enabled", channel))?;
+        Ok(scan_type.decode(&scan[*offset..]))
+    }
+}
+
+// Red, green and blue are needed for the illuminance and color temperature, clear is optional
+const COLOR_CHANNELS: [&str; 4] = [
+    "in_intensity_red",
+    "in_intensity_green",
+    "in_intensity_blue",
+    "in_intensity_clear",
+];
+
+struct Sensor {
+    // Scale and offset of the illuminance channel
+    illuminance: Option<(f64, f64)>,
+    // Enabled color channels with their scales
+    color: Option<Vec<(&'static str, f64)>>,
+}
+
+// Device in buffered mode, which pushes new scans through its character device as they come
+pub struct Buffer {
+    path: PathBuf,
+    device: File,
+    sensor: Sensor,
+    layout: Layout,
+    calibration: Calibration,
+}
+
+impl Buffer {
+    pub fn new(base_path: &str, calibration: Calibration) -> Result<Self, Box<dyn Error>> {
+        let path = super::find_device(base_path).ok_or("No iio device found")?;
+        let name = path.file_name().ok_or("Invalid iio device path")?;
+        Self::open(
+            &path,
+            &Path::new("/dev").join(name),
+            Path::new(base_path),
+            calibration,
+        )
+    }
+
+    // Triggers are looked up next to the device, just like they are in sysfs
+    pub fn open(
+        path: &Path,
+        device: &Path,
+        base_path: &Path,
+        calibration: Calibration,
+    ) -> Result<Self, Box<dyn Error>> {
+        let scan_elements = path.join("scan_elements");
+        let available = |name: &str| scan_elements.join(format!("{}_en", name)).exists();
+
+        let illuminance = available("in_illuminance").then(|| {
+            (
+                // Not every driver exposes these, in which case raw values are already in lux
+                super::scale(path, "in_illuminance", "in_illuminance"),
+                read_value(&path.join("in_illuminance_offset")).unwrap_or(0.0),
+            )
+        });
+        let color = COLOR_CHANNELS[..3]
+            .iter()
+            .all(|name| available(name))
+            .then(|| {
+                COLOR_CHANNELS
+                    .into_iter()
+                    .filter(|name| available(name))
+                    .map(|name| (name, super::scale(path, name, "in_intensity")))
+                    .collect::<Vec<_>>()
+            });
+
+        let mut names = color
+            .iter()
+            .flatten()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        match illuminance {
+            Some(_) => names.push("in_illuminance"),
+            None if color.is_none() => {
+                return Err("No illuminance or intensity channels found in IIO buffer".into())
+            }
+            None => {}
+        }
+        let sensor = Sensor { illuminance, color };
+
+        // Buffer has to be disabled while it is being reconfigured
+        write(&path.join("buffer/enable"), "0")?;
+        for name in &names {
+            write(&scan_elements.join(format!("{}_en", name)), "1")?;
+        }
+        set_trigger(path, base_path)?;
+        write(&path.join("buffer/length"), &BUFFER_LENGTH.to_string())?;
+
+        let layout = Layout::new(enabled_channels(&scan_elements)?);
+        write(&path.join("buffer/enable"), "1")?;
+
+        log::debug!("Reading IIO buffer of {} with {:?}", path.display(), layout);
+        Ok(Self {
+            path: path.to_path_buf(),
+            device: File::open(device)?,
+            sensor,
+            layout,
+            calibration,
+        })
+    }
+
+    // Blocks until the device is gone
+    pub fn run(&mut self, value_tx: Sender<Reading>) -> Result<(), Box<dyn Error>> {
+        let mut scans = vec![0; self.layout.size * BUFFER_LENGTH];
+        loop {
+            let read = self.device.read(&mut scans)?;
+            if read < self.layout.size {
+                return Err("IIO buffer was closed".into());
+            }
+
+            let last = (read / self.layout.size - 1) * self.layout.size;
+            let value = self.value(&scans[last..read])?;
+            if value_tx.send(value).is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn value(&self, scan: &[u8]) -> Result<Reading, Box<dyn Error>> {
+        let rgb = match &self.sensor.color {
+            Some(channels) => {
+                let values = channels
+                    .iter()
+                    .map(|(name, scale)| Ok(self.layout.read(scan, name)? * scale))
+                    .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+                Some(Rgb {
+                    r: values[0],
+                    g: values[1],
+                    b: values[2],
+                    clear: values.get(3).copied(),
+                })
+            }
+            None => None,
+        };
+
+        let lux = match (self.sensor.illuminance, rgb) {
+            (Some((scale, offset)), _) => {
+                super::illuminance(self.layout.read(scan, "in_illuminance")?, scale, offset)
+            }
+            (None, Some(rgb)) => self.calibration.illuminance(rgb),
+            (None, None) => unreachable!(),
+        };
+
+        Ok(Reading {
+            lux,
+            color_temperature: rgb.and_then(|rgb| self.calibration.color_temperature(rgb)),
+        })
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Err(err) = write(&self.path.join("buffer/enable"), "0") {
+            log::warn!("Unable to disable IIO buffer: {}", err);
+        }
+    }
+}
+
+pub struct Als {
+    value_rx: Receiver<Reading>,
+    reading: RefCell<Option<Reading>>,
+}
+
+impl Als {
+    pub fn new(value_rx: Receiver<Reading>) -> Self {
+        Self {
+            value_rx,
+            reading: RefCell::new(None),
+        }
+    }
+
+    fn get_raw(&self) -> Result<u64, Box<dyn Error>> {
+        // Last value would be stale forever once the reader is gone, e.g. with the sensor
+        loop {
+            match self.value_rx.try_recv() {
+                Ok(reading) => *self.reading.borrow_mut() = Some(reading),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Err("IIO buffer reader is gone".into()),
+            }
+        }
+
+        match *self.reading.borrow() {
+            Some(reading) => Ok(reading.lux),
+            None => Err("No value received from IIO buffer yet".into()),
+        }
+    }
+}
+
+impl crate::als::Als for Als {
+    fn get(&self) -> Result<u64, Box<dyn Error>> {
+        let raw = self.get_raw()?;
+
+        log::trace!("ALS (iio buffer): {}", raw);
+        Ok(raw)
+    }
+
+    fn color_temperature(&self) -> Option<u64> {
+        (*self.reading.borrow())?.color_temperature
+    }
+
+    fn wait(&self) {
+        match self
+            .value_rx
+            .recv_timeout(Duration::from_millis(WAIT_TIMEOUT_MS))
+        {
+            Ok(reading) => *self.reading.borrow_mut() = Some(reading),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                // Reader is gone, fall back to checking in on subscribers periodically
+                std::thread::sleep(Duration::from_millis(WAIT_TIMEOUT_MS));
+            }
+        }
+    }
+}
+
+fn enabled_channels(scan_elements: &Path) -> Result<Vec<Channel>, Box<dyn Error>> {
+    scan_elements
+        .read_dir()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let name = file_name.strip_suffix("_en")?.to_string();
+            let enabled = fs::read_to_string(entry.path()).ok()?.trim() == "1";
+            enabled.then_some(name)
+        })
+        .map(|name| {
+            let index = read_value(&scan_elements.join(format!("{}_index", name)))? as u32;
+            let scan_type = ScanType::parse(&fs::read_to_string(
+                scan_elements.join(format!("{}_type", name)),
+            )?)?;
+            Ok((name, index, scan_type))
+        })
+        .collect()
+}
+
+// Drivers usually provide a data ready trigger named after the device, e.g. "als-dev0"
+fn set_trigger(path: &Path, base_path: &Path) -> Result<(), Box<dyn Error>> {
+    let current_trigger = path.join("trigger/current_trigger");
+    if !current_trigger.exists() || !fs::read_to_string(&current_trigger)?.trim().is_empty() {
+        return Ok(());
+    }
+
+    let device_name = fs::read_to_string(path.join("name"))?;
+    let device_number = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix("iio:device"))
+        .ok_or("Invalid iio device path")?;
+    let trigger_name = format!("{}-dev{}", device_name.trim(), device_number);
+
+    let trigger_exists = base_path
+        .read_dir()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("trigger"))
+        .any(|entry| {
+            fs::read_to_string(entry.path().join("name"))
+                .unwrap_or_default()
+                .trim()
+                == trigger_name
+        });
+
+    if trigger_exists {
+        write(&current_trigger, &trigger_name)
+    } else {
+        Err(format!("IIO trigger '{}' not found", trigger_name).into())
+    }
+}
+
+fn read_value(path: &Path) -> Result<f64, Box<dyn Error>> {
+    Ok(fs::read_to_string(path)?.trim().parse()?)
+}
+
+fn write(path: &Path, value: &str) -> Result<(), Box<dyn Error>> {
+    fs::write(path, value)
+        .map_err(|err| format!("Unable to write '{}': {}", path.display(), err).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::als::iio::tests::CALIBRATION;
+    use crate::als::Als as _;
+    use std::sync::mpsc;
+
+    // Device directory laid out like sysfs, removed once the test is done
+    struct FakeDevice {
+        base_path: PathBuf,
+    }
+
+    impl FakeDevice {
+        fn new(test: &str) -> Self {
+            let base_path =
+                std::env::temp_dir().join(format!("wluma-iio-{}-{}", std::process::id(), test));
+            let path = base_path.join("iio:device0");
+            fs::create_dir_all(path.join("scan_elements")).unwrap();
+            fs::create_dir_all(path.join("buffer")).unwrap();
+            fs::create_dir_all(path.join("trigger")).unwrap();
+            fs::create_dir_all(base_path.join("trigger0")).unwrap();
+            fs::write(path.join("name"), "als\n").unwrap();
+            fs::write(path.join("buffer/enable"), "0").unwrap();
+            fs::write(path.join("buffer/length"), "0").unwrap();
+            fs::write(path.join("trigger/current_trigger"), "\n").unwrap();
+            fs::write(base_path.join("trigger0/name"), "als-dev0\n").unwrap();
+            Self { base_path }
+        }
+
+        fn path(&self) -> PathBuf {
+            self.base_path.join("iio:device0")
+        }
+
+        fn channel(&self, name: &str, index: u32, scan_type: &str, enabled: bool) {
+            let scan_elements = self.path().join("scan_elements");
+            let enabled = if enabled { "1" } else { "0" };
+            fs::write(scan_elements.join(format!("{}_en", name)), enabled).unwrap();
+            fs::write(
+                scan_elements.join(format!("{}_index", name)),
+                index.to_string(),
+            )
+            .unwrap();
+            fs::write(scan_elements.join(format!("{}_type", name)), scan_type).unwrap();
+        }
+
+        fn read(&self, file: &str) -> String {
+            fs::read_to_string(self.path().join(file)).unwrap()
+        }
+
+        fn open(&self, scans: &[u8]) -> Buffer {
+            let device = self.base_path.join("dev");
+            fs::write(&device, scans).unwrap();
+            Buffer::open(&self.path(), &device, &self.base_path, CALIBRATION).unwrap()
+        }
+    }
+
+    impl Drop for FakeDevice {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base_path);
+        }
+    }
+
+    #[test]
+    fn test_scan_type_parse() -> Result<(), Box<dyn Error>> {
+        assert_eq!(
+            ScanType {
+                big_endian: false,
+                signed: false,
+                bits: 16,
+                storage_bits: 16,
+                repeat: 1,
+                shift: 0
+            },
+            ScanType::parse("le:u16/16>>0\n")?
+        );
+        assert_eq!(
+            ScanType {
+                big_endian: true,
+                signed: true,
+                bits: 12,
+                storage_bits: 16,
+                repeat: 2,
+                shift: 4
+            },
+            ScanType::parse("be:s12/16X2>>4")?
+        );
+        assert!(ScanType::parse("me:u16/16>>0").is_err());
+        assert!(ScanType::parse("le:u24/16>>0").is_err());
+        assert!(ScanType::parse("le:u16/12>>0").is_err());
+        assert!(ScanType::parse("le:s0/16>>0").is_err());
+        assert!(ScanType::parse("le:u16/16>>16").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_type_decode() -> Result<(), Box<dyn Error>> {
+        assert_eq!(
+            0x1234 as f64,
+            ScanType::parse("le:u16/16>>0")?.decode(&[0x34, 0x12])
+        );
+        assert_eq!(
+            0x1234 as f64,
+            ScanType::parse("be:u16/16>>0")?.decode(&[0x12, 0x34])
+        );
+        // 12 bits stored in the upper part of 16, sign extended
+        assert_eq!(-1.0, ScanType::parse("be:s12/16>>4")?.decode(&[0xff, 0xf0]));
+        assert_eq!(
+            0x123 as f64,
+            ScanType::parse("be:u12/16>>4")?.decode(&[0x12, 0x3f])
+        );
+        assert_eq!(
+            -2.0,
+            ScanType::parse("le:s32/32>>0")?.decode(&[0xfe, 0xff, 0xff, 0xff])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layout_aligns_channels() -> Result<(), Box<dyn Error>> {
+        let layout = Layout::new(vec![
+            (
+                "in_timestamp".to_string(),
+                3,
+                ScanType::parse("le:s64/64>>0")?,
+            ),
+            (
+                "in_intensity_red".to_string(),
+                0,
+                ScanType::parse("le:u16/16>>0")?,
+            ),
+            (
+                "in_intensity_green".to_string(),
+                1,
+                ScanType::parse("le:u32/32>>0")?,
+            ),
+        ]);
+
+        assert_eq!(16, layout.size);
+        assert_eq!(
+            Some(&0),
+            layout.channels.get("in_intensity_red").map(|c| &c.0)
+        );
+        assert_eq!(
+            Some(&4),
+            layout.channels.get("in_intensity_green").map(|c| &c.0)
+        );
+        assert_eq!(Some(&8), layout.channels.get("in_timestamp").map(|c| &c.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_enables_buffer() {
+        let device = FakeDevice::new("enables");
+        device.channel("in_illuminance", 0, "le:u16/16>>0", false);
+        device.channel("in_timestamp", 1, "le:s64/64>>0", false);
+
+        let buffer = device.open(&[]);
+
+        assert_eq!("1", device.read("scan_elements/in_illuminance_en"));
+        assert_eq!("0", device.read("scan_elements/in_timestamp_en"));
+        assert_eq!("als-dev0", device.read("trigger/current_trigger"));
+        assert_eq!(BUFFER_LENGTH.to_string(), device.read("buffer/length"));
+        assert_eq!("1", device.read("buffer/enable"));
+
+        drop(buffer);
+        assert_eq!("0", device.read("buffer/enable"));
+    }
+
+    #[test]
+    fn test_run_sends_illuminance_of_latest_scans() {
+        let device = FakeDevice::new("illuminance");
+        device.channel("in_illuminance", 0, "le:u16/16>>0", false);
+        device.channel("in_timestamp", 1, "le:s64/64>>0", true);
+        fs::write(device.path().join("in_illuminance_scale"), "0.5").unwrap();
+        fs::write(device.path().join("in_illuminance_offset"), "2").unwrap();
+
+        // Two scans of 16 bytes each, only the second one of which is used
+        let mut scans = vec![0; 32];
+        scans[..2].copy_from_slice(&100u16.to_le_bytes());
+        scans[16..18].copy_from_slice(&398u16.to_le_bytes());
+        let mut buffer = device.open(&scans);
+
+        let (value_tx, value_rx) = mpsc::channel();
+        assert!(buffer.run(value_tx).is_err());
+        assert_eq!(
+            vec![Reading {
+                lux: 200,
+                color_temperature: None
+            }],
+            value_rx.try_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_run_sends_intensity() {
+        let device = FakeDevice::new("intensity");
+        device.channel("in_intensity_red", 0, "le:u16/16>>0", false);
+        device.channel("in_intensity_green", 1, "le:u16/16>>0", false);
+        device.channel("in_intensity_blue", 2, "le:u16/16>>0", false);
+
+        let scans = [0, 0, 100, 0, 0, 0, 0, 0];
+        let mut buffer = device.open(&scans);
+
+        let (value_tx, value_rx) = mpsc::channel();
+        assert!(buffer.run(value_tx).is_err());
+        let readings = value_rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(
+            vec![157],
+            readings.iter().map(|r| r.lux).collect::<Vec<_>>()
+        );
+        assert!(readings[0].color_temperature.is_some());
+    }
+
+    #[test]
+    fn test_get_raw_keeps_latest_value() -> Result<(), Box<dyn Error>> {
+        let (value_tx, value_rx) = mpsc::channel();
+        let als = Als::new(value_rx);
+
+        let reading = |lux, color_temperature| Reading {
+            lux,
+            color_temperature,
+        };
+
+        assert!(als.get_raw().is_err());
+        value_tx.send(reading(10, None))?;
+        value_tx.send(reading(20, Some(4000)))?;
+        assert_eq!(20, als.get_raw()?);
+        assert_eq!(20, als.get_raw()?);
+        assert_eq!(Some(4000), als.color_temperature());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_raw_fails_once_reader_is_gone() -> Result<(), Box<dyn Error>> {
+        let (value_tx, value_rx) = mpsc::channel();
+        let als = Als::new(value_rx);
+
+        value_tx.send(Reading {
+            lux: 10,
+            color_temperature: None,
+        })?;
+        assert_eq!(10, als.get_raw()?);
+        drop(value_tx);
+        assert!(als.get_raw().is_err());
+
+        Ok(())
+    }
+}
@@ -1,25 +1,171 @@
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(test)]
+use mockall::*;
 
 pub mod controller;
+pub mod dbus;
+pub mod external;
 pub mod iio;
 pub mod none;
+pub mod solar;
 pub mod time;
 pub mod webcam;
 
+const WAITING_SLEEP_MS: u64 = 100;
+
+// Levels closer than this are considered the same ambient light, about 25% apart in lux
+const LEVEL_TOLERANCE: f64 = 2.0;
+
+#[cfg_attr(test, automock)]
 pub trait Als {
-    fn get(&self) -> Result<String, Box<dyn Error>>;
+    // Raw reading of the sensor, usually lux
+    fn get(&self) -> Result<u64, Box<dyn Error>>;
+
+    // Correlated color temperature in Kelvin, for sensors that can tell
+    fn color_temperature(&self) -> Option<u64> {
+        None
+    }
+
+    // Sensors that push their values return as soon as there is a new one, others are polled
+    fn wait(&self) {
+        thread::sleep(Duration::from_millis(WAITING_SLEEP_MS));
+    }
+}
+
+// How raw readings are passed on to the predictor
+#[derive(Debug, Clone)]
+pub enum Scale {
+    Profiles {
+        thresholds: HashMap<u64, String>,
+        // Raw values within this distance of a threshold keep the last profile
+        hysteresis: HashMap<u64, u64>,
+    },
+    Continuous,
+}
+
+impl Scale {
+    pub fn lux(&self, raw: u64, last: Option<&Lux>) -> Result<Lux, NoProfileError> {
+        match (self, last) {
+            (
+                Scale::Profiles {
+                    thresholds,
+                    hysteresis,
+                },
+                Some(Lux::Profile(last)),
+            ) if within_profile(raw, last, thresholds, hysteresis) => {
+                Ok(Lux::Profile(last.clone()))
+            }
+            (Scale::Profiles { thresholds, .. }, _) => {
+                find_profile(raw, thresholds).map(Lux::Profile)
+            }
+            (Scale::Continuous, _) => Ok(Lux::Value(raw)),
+        }
+    }
+}
+
+// Raw value could not be mapped to a profile, as there are no thresholds to begin with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoProfileError {
+    raw: u64,
 }
 
-fn find_profile(raw: u64, thresholds: &HashMap<u64, String>) -> String {
+impl std::fmt::Display for NoProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unable to find ALS profile for value '{}'", self.raw)
+    }
+}
+
+impl Error for NoProfileError {}
+
+// Ambient light as known to the predictor, either a profile or a numeric lux value
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Lux {
+    Value(u64),
+    Profile(String),
+}
+
+impl Lux {
+    // Perception of light is roughly logarithmic, every tenfold increase in lux adds 20 to the
+    // level, so that it spans about the same range as luma does
+    fn level(lux: u64) -> f64 {
+        (lux as f64 + 1.0).log10() * 20.0
+    }
+
+    fn from_level(level: f64) -> u64 {
+        (10f64.powf(level / 20.0) - 1.0).round().max(0.0) as u64
+    }
+
+    // Profiles are either the same or incomparable, as are values and profiles
+    pub fn distance(&self, other: &Lux) -> Option<f64> {
+        match (self, other) {
+            (Lux::Value(a), Lux::Value(b)) => Some((Self::level(*a) - Self::level(*b)).abs()),
+            (Lux::Profile(a), Lux::Profile(b)) if a == b => Some(0.0),
+            _ => None,
+        }
+    }
+
+    pub fn is_close(&self, other: &Lux) -> bool {
+        self.distance(other)
+            .is_some_and(|distance| distance < LEVEL_TOLERANCE)
+    }
+}
+
+impl From<&str> for Lux {
+    fn from(profile: &str) -> Self {
+        Lux::Profile(profile.to_string())
+    }
+}
+
+impl std::fmt::Display for Lux {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Lux::Value(lux) => write!(f, "{} lux", lux),
+            Lux::Profile(profile) => write!(f, "{}", profile),
+        }
+    }
+}
+
+fn find_profile(raw: u64, thresholds: &HashMap<u64, String>) -> Result<String, NoProfileError> {
     thresholds
         .iter()
         .sorted_by_key(|(lux, _)| *lux)
         .rev()
         .find_or_last(|(lux, _)| raw >= **lux)
         .map(|(_, profile)| profile.to_string())
-        .unwrap_or_else(|| panic!("Unable to find ALS profile for value '{}'", raw))
+        .ok_or(NoProfileError { raw })
+}
+
+// Whether the raw value is in one of the ranges of the profile, widened by their hysteresis bands
+fn within_profile(
+    raw: u64,
+    profile: &str,
+    thresholds: &HashMap<u64, String>,
+    hysteresis: &HashMap<u64, u64>,
+) -> bool {
+    let band = |threshold: &u64| hysteresis.get(threshold).copied().unwrap_or_default();
+    let thresholds = thresholds
+        .iter()
+        .sorted_by_key(|(lux, _)| *lux)
+        .collect_vec();
+
+    thresholds.iter().enumerate().any(|(i, (lux, name))| {
+        // Values below the lowest threshold fall back to its profile
+        let low = match i {
+            0 => 0,
+            _ => lux.saturating_sub(band(lux)),
+        };
+        let high = thresholds
+            .get(i + 1)
+            .map_or(u64::MAX, |(next, _)| next.saturating_add(band(next)));
+        name.as_str() == profile && (low..high).contains(&raw)
+    })
 }
 
 #[cfg(test)]
@@ -33,12 +179,12 @@ mod tests {
             .map(|(lux, profile)| (lux, profile.to_string()))
             .collect();
 
-        assert_eq!("dark", find_profile(0, &thresholds));
-        assert_eq!("dark", find_profile(2, &thresholds));
-        assert_eq!("dim", find_profile(10, &thresholds));
-        assert_eq!("dim", find_profile(19, &thresholds));
-        assert_eq!("bright", find_profile(20, &thresholds));
-        assert_eq!("bright", find_profile(200, &thresholds));
+        assert_eq!("dark", find_profile(0, &thresholds).unwrap());
+        assert_eq!("dark", find_profile(2, &thresholds).unwrap());
+        assert_eq!("dim", find_profile(10, &thresholds).unwrap());
+        assert_eq!("dim", find_profile(19, &thresholds).unwrap());
+        assert_eq!("bright", find_profile(20, &thresholds).unwrap());
+        assert_eq!("bright", find_profile(200, &thresholds).unwrap());
     }
 
     #[test]
@@ -48,8 +194,8 @@ mod tests {
             .map(|(lux, profile)| (lux, profile.to_string()))
             .collect();
 
-        assert_eq!("dark", find_profile(0, &thresholds));
-        assert_eq!("dark", find_profile(4, &thresholds));
+        assert_eq!("dark", find_profile(0, &thresholds).unwrap());
+        assert_eq!("dark", find_profile(4, &thresholds).unwrap());
     }
 
     #[test]
@@ -59,15 +205,70 @@ mod tests {
             .map(|(lux, profile)| (lux, profile.to_string()))
             .collect();
 
-        assert_eq!("dark", find_profile(0, &thresholds));
-        assert_eq!("dark", find_profile(4, &thresholds));
-        assert_eq!("dark", find_profile(5, &thresholds));
-        assert_eq!("dark", find_profile(9, &thresholds));
+        assert_eq!("dark", find_profile(0, &thresholds).unwrap());
+        assert_eq!("dark", find_profile(4, &thresholds).unwrap());
+        assert_eq!("dark", find_profile(5, &thresholds).unwrap());
+        assert_eq!("dark", find_profile(9, &thresholds).unwrap());
+    }
+
+    #[test]
+    fn test_find_profile_fails_on_empty_thresholds() {
+        assert_eq!(
+            Err(NoProfileError { raw: 10 }),
+            find_profile(10, &HashMap::default())
+        );
+    }
+
+    #[test]
+    fn test_scale_lux() {
+        let thresholds = HashMap::from([(0, "dark".to_string()), (10, "bright".to_string())]);
+
+        let scale = Scale::Profiles {
+            thresholds,
+            hysteresis: HashMap::from([(10, 5)]),
+        };
+
+        assert_eq!(Lux::from("bright"), scale.lux(42, None).unwrap());
+        assert_eq!(
+            Lux::from("dark"),
+            scale.lux(12, Some(&Lux::from("dark"))).unwrap()
+        );
+        assert_eq!(
+            Lux::from("bright"),
+            scale.lux(12, Some(&Lux::from("bright"))).unwrap()
+        );
+        assert_eq!(
+            Lux::from("bright"),
+            scale.lux(12, Some(&Lux::from("gone"))).unwrap()
+        );
+        assert_eq!(Lux::Value(42), Scale::Continuous.lux(42, None).unwrap());
+    }
+
+    #[test]
+    fn test_lux_distance_is_logarithmic() {
+        let distance = |a, b| Lux::Value(a).distance(&Lux::Value(b)).unwrap();
+
+        assert_eq!(0.0, distance(100, 100));
+        assert!((distance(9, 99) - 20.0).abs() < 1e-9);
+        assert!((distance(99, 999) - distance(999, 9999)).abs() < 1e-9);
+        assert!(Lux::Value(500).is_close(&Lux::Value(520)));
+        assert!(!Lux::Value(5).is_close(&Lux::Value(7)));
     }
 
     #[test]
-    #[should_panic]
-    fn test_find_profile_panics_on_empty_thresholds() {
-        find_profile(10, &HashMap::default());
+    fn test_lux_distance_of_profiles() {
+        assert_eq!(Some(0.0), Lux::from("dim").distance(&Lux::from("dim")));
+        assert_eq!(None, Lux::from("dim").distance(&Lux::from("bright")));
+        assert_eq!(None, Lux::from("dim").distance(&Lux::Value(0)));
+    }
+
+    #[test]
+    fn test_lux_deserializes_profiles_and_values() {
+        let lux: Vec<Lux> = serde_yaml::from_str("[dim, 250, \"100\"]").unwrap();
+
+        assert_eq!(
+            vec![Lux::from("dim"), Lux::Value(250), Lux::from("100")],
+            lux
+        );
     }
 }
@@ -0,0 +1,175 @@
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::error::Error;
+
+// Raw values are sun elevations shifted up by this many degrees, as they cannot be negative
+const ELEVATION_OFFSET: f64 = 90.0;
+
+// Lower bounds of elevation bands in degrees: civil twilight, golden hour and full daylight
+const TWILIGHT_ELEVATION: f64 = -6.0;
+const GOLDEN_HOUR_ELEVATION: f64 = 0.0;
+const DAY_ELEVATION: f64 = 6.0;
+
+// Days between the Unix epoch and J2000.0, the reference of the formulas below
+const UNIX_EPOCH_J2000_DAYS: f64 = 10957.5;
+
+// Simulates ALS from the position of the sun at the configured place, computed offline
+pub struct Als {
+    latitude: f64,
+    longitude: f64,
+}
+
+impl Als {
+    pub fn new(latitude: f64, longitude: f64) -> Self {
+        Self {
+            latitude,
+            longitude,
+        }
+    }
+
+    // Maps raw values of the elevation bands to the given profiles
+    pub fn thresholds(
+        night: String,
+        twilight: String,
+        golden_hour: String,
+        day: String,
+    ) -> HashMap<u64, String> {
+        HashMap::from([
+            (0, night),
+            (raw(TWILIGHT_ELEVATION), twilight),
+            (raw(GOLDEN_HOUR_ELEVATION), golden_hour),
+            (raw(DAY_ELEVATION), day),
+        ])
+    }
+}
+
+impl super::Als for Als {
+    fn get(&self) -> Result<u64, Box<dyn Error>> {
+        let elevation = elevation(self.latitude, self.longitude, Utc::now());
+        let raw = raw(elevation);
+
+        log::trace!("ALS (solar): {} ({:.1}°)", raw, elevation);
+        Ok(raw)
+    }
+}
+
+fn raw(elevation: f64) -> u64 {
+    (elevation + ELEVATION_OFFSET)
+        .round()
+        .clamp(0.0, 2.0 * ELEVATION_OFFSET) as u64
+}
+
+// Sun elevation in degrees, based on the low precision formulas of the Astronomical Almanac which
+// are accurate to about a hundredth of a degree, ignoring refraction near the horizon
+fn elevation(latitude: f64, longitude: f64, time: DateTime<Utc>) -> f64 {
+    let days = time.timestamp_millis() as f64 / 86_400_000.0 - UNIX_EPOCH_J2000_DAYS;
+
+    let mean_longitude = 280.460 + 0.9856474 * days;
+    let mean_anomaly = (357.528 + 0.9856003 * days).to_radians();
+    let ecliptic_longitude =
+        (mean_longitude + 1.915 * mean_anomaly.sin() + 0.020 * (2.0 * mean_anomaly).sin())
+            .to_radians();
+    let obliquity = (23.439 - 0.0000004 * days).to_radians();
+
+    let right_ascension = (obliquity.cos() * ecliptic_longitude.sin())
+        .atan2(ecliptic_longitude.cos())
+        .to_degrees();
+    let declination = (obliquity.sin() * ecliptic_longitude.sin()).asin();
+
+    let sidereal_time = 280.46061837 + 360.98564736629 * days;
+    let hour_angle = (sidereal_time + longitude - right_ascension).to_radians();
+
+    let latitude = latitude.to_radians();
+    (latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos())
+        .asin()
+        .to_degrees()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const BERLIN: (f64, f64) = (52.52, 13.405);
+
+    fn assert_elevation(expected: f64, (latitude, longitude): (f64, f64), time: DateTime<Utc>) {
+        let actual = elevation(latitude, longitude, time);
+        assert!(
+            (expected - actual).abs() < 0.5,
+            "expected {} at {}, got {}",
+            expected,
+            time,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_elevation_at_solstices() {
+        // Around solar noon and midnight, 90° - latitude ± axial tilt
+        assert_elevation(
+            60.9,
+            BERLIN,
+            Utc.with_ymd_and_hms(2024, 6, 20, 11, 8, 0).unwrap(),
+        );
+        assert_elevation(
+            -14.0,
+            BERLIN,
+            Utc.with_ymd_and_hms(2024, 6, 20, 23, 8, 0).unwrap(),
+        );
+        assert_elevation(
+            14.0,
+            BERLIN,
+            Utc.with_ymd_and_hms(2024, 12, 21, 11, 11, 0).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_elevation_at_equinox() {
+        assert_elevation(
+            90.0,
+            (0.0, 0.0),
+            Utc.with_ymd_and_hms(2024, 3, 20, 12, 7, 0).unwrap(),
+        );
+        // Sunrise at the equator is at about 6 in the morning local solar time
+        assert_elevation(
+            0.0,
+            (0.0, 0.0),
+            Utc.with_ymd_and_hms(2024, 3, 20, 6, 7, 0).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_elevation_follows_longitude() {
+        // Solar noon in New York is about 5 hours after the one in London
+        let london = elevation(
+            51.5,
+            0.0,
+            Utc.with_ymd_and_hms(2024, 9, 1, 12, 0, 0).unwrap(),
+        );
+        let new_york = elevation(
+            51.5,
+            -74.0,
+            Utc.with_ymd_and_hms(2024, 9, 1, 16, 56, 0).unwrap(),
+        );
+
+        assert!((london - new_york).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_thresholds_map_elevation_bands() {
+        let thresholds = Als::thresholds(
+            "night".to_string(),
+            "twilight".to_string(),
+            "golden hour".to_string(),
+            "day".to_string(),
+        );
+        let profile = |elevation| crate::als::find_profile(raw(elevation), &thresholds).unwrap();
+
+        assert_eq!("night", profile(-100.0));
+        assert_eq!("night", profile(-20.0));
+        assert_eq!("twilight", profile(-5.0));
+        assert_eq!("golden hour", profile(3.0));
+        assert_eq!("day", profile(6.0));
+        assert_eq!("day", profile(60.0));
+    }
+}
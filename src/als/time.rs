@@ -1,23 +1,14 @@
 use chrono::{Local, Timelike};
-use std::collections::HashMap;
 use std::error::Error;
 
-pub struct Als {
-    thresholds: HashMap<u64, String>,
-}
-
-impl Als {
-    pub fn new(thresholds: HashMap<u64, String>) -> Self {
-        Self { thresholds }
-    }
-}
+#[derive(Default)]
+pub struct Als {}
 
 impl super::Als for Als {
-    fn get(&self) -> Result<String, Box<dyn Error>> {
+    fn get(&self) -> Result<u64, Box<dyn Error>> {
         let raw = Local::now().hour() as u64;
-        let profile = super::find_profile(raw, &self.thresholds);
 
-        log::trace!("ALS (time): {} ({})", profile, raw);
-        Ok(profile)
+        log::trace!("ALS (time): {}", raw);
+        Ok(raw)
     }
 }
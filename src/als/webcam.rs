@@ -1,5 +1,5 @@
-use crate::frame::compute_perceived_lightness_percent;
 use itertools::Itertools;
+use jpeg_decoder::PixelFormat;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::error::Error;
@@ -7,54 +7,128 @@ use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
 use std::time::Duration;
 use v4l::buffer::Type;
+use v4l::control::Value;
 use v4l::io::mmap::Stream;
 use v4l::io::traits::CaptureStream;
+use v4l::video::capture::Parameters;
 use v4l::video::Capture;
 use v4l::{Device, FourCC};
 
 const DEFAULT_LUX: u64 = 100;
 const WAITING_SLEEP_MS: u64 = 2000;
 
+// Formats with a luma plane come first, as they need no conversion at all
+const FORMATS: [&[u8; 4]; 4] = [b"GREY", b"YUYV", b"MJPG", b"RGB3"];
+
+// Drivers round it up to the slowest frame rate they support
+const PERSISTENT_FPS: u32 = 1;
+// Frames queued while sleeping are stale, so these many are skipped before every reading
+const PERSISTENT_BUFFERS: u32 = 2;
+
+// Exposure time (in 100µs units) and gain (in device units) set by auto exposure
+const V4L2_CID_EXPOSURE_ABSOLUTE: u32 = 0x009a0902;
+const V4L2_CID_GAIN: u32 = 0x00980913;
+
+// Default values of the above controls which the webcam supports
+type ControlDefaults = HashMap<u32, i64>;
+
 pub struct Webcam {
     webcam_tx: Sender<u64>,
     video: usize,
+    persistent: bool,
+    compensate: bool,
 }
 
 impl Webcam {
-    pub fn new(webcam_tx: Sender<u64>, video: usize) -> Self {
-        Self { webcam_tx, video }
+    pub fn new(webcam_tx: Sender<u64>, video: usize, persistent: bool, compensate: bool) -> Self {
+        Self {
+            webcam_tx,
+            video,
+            persistent,
+            compensate,
+        }
     }
 
     pub fn run(&mut self) {
         loop {
-            self.step();
+            let result = if self.persistent {
+                self.stream()
+            } else {
+                self.capture()
+            };
+
+            if let Err(err) = result {
+                log::debug!("Unable to capture webcam frame: {:?}", err);
+            }
+
+            thread::sleep(Duration::from_millis(WAITING_SLEEP_MS));
         }
     }
 
-    fn step(&mut self) {
-        if let Ok((rgbs, pixels)) = self.frame() {
-            let lux = compute_perceived_lightness_percent(&rgbs, false, pixels) as u64;
+    // Opens the device for a single frame, so that it is not kept busy in between
+    fn capture(&self) -> Result<(), Box<dyn Error>> {
+        let (device, frame, defaults) = Self::setup(self.video)?;
+        let mut stream = Stream::new(&device, Type::VideoCapture)?;
+        let (data, _) = stream.next()?;
 
-            self.webcam_tx
-                .send(lux)
-                .expect("Unable to send new webcam lux value, channel is dead");
-        };
+        self.send(&device, &frame, &defaults, data)
+    }
 
-        thread::sleep(Duration::from_millis(WAITING_SLEEP_MS));
+    // Keeps the device streaming at a low frame rate, so that the privacy LED does not blink
+    fn stream(&self) -> Result<(), Box<dyn Error>> {
+        let (device, frame, defaults) = Self::setup(self.video)?;
+        device.set_params(&Parameters::with_fps(PERSISTENT_FPS))?;
+        let mut stream = Stream::with_buffers(&device, Type::VideoCapture, PERSISTENT_BUFFERS)?;
+
+        loop {
+            for _ in 0..PERSISTENT_BUFFERS {
+                stream.next()?;
+            }
+            let (data, _) = stream.next()?;
+            self.send(&device, &frame, &defaults, data)?;
+
+            thread::sleep(Duration::from_millis(WAITING_SLEEP_MS));
+        }
     }
 
-    fn frame(&mut self) -> Result<(Vec<u8>, usize), Box<dyn Error>> {
-        let (device, pixels) = Self::setup(self.video)?;
-        let mut stream = Stream::new(&device, Type::VideoCapture)?;
-        let (rgbs, _) = stream.next()?;
+    fn send(
+        &self,
+        device: &Device,
+        frame: &Frame,
+        defaults: &ControlDefaults,
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let luma = frame.luma(data)?;
+        let raw = if self.compensate {
+            let exposure = Exposure::read(device, defaults);
+            log::trace!("Webcam luma: {}, {:?}", luma, exposure);
+            exposure.compensate(luma)
+        } else {
+            luma as u64
+        };
 
-        Ok((rgbs.to_vec(), pixels))
+        self.webcam_tx
+            .send(raw)
+            .expect("Unable to send new webcam lux value, channel is dead");
+        Ok(())
     }
 
-    fn setup(video: usize) -> Result<(Device, usize), Box<dyn Error>> {
+    fn setup(video: usize) -> Result<(Device, Frame, ControlDefaults), Box<dyn Error>> {
         let device = Device::new(video)?;
+        let available = device
+            .enum_formats()?
+            .into_iter()
+            .map(|description| description.fourcc)
+            .collect_vec();
+        let fourcc = negotiate(&available).ok_or_else(|| {
+            format!(
+                "Webcam supports none of the formats: {}",
+                FORMATS.map(|f| FourCC::new(f).to_string()).join(", ")
+            )
+        })?;
+
         let mut format = device.format()?;
-        format.fourcc = FourCC::new(b"RGB3");
+        format.fourcc = fourcc;
         let (width, height) = device
             .enum_framesizes(format.fourcc)?
             .into_iter()
@@ -70,23 +144,161 @@ impl Webcam {
 
         format.height = height;
         format.width = width;
-        device.set_format(&format)?;
+        // Driver adjusts the format to what it actually supports
+        let format = device.set_format(&format)?;
+        if format.fourcc != fourcc {
+            return Err(format!("Webcam refused to use {} format", fourcc).into());
+        }
+        log::debug!(
+            "Using webcam format {} at {}x{}",
+            format.fourcc,
+            format.width,
+            format.height
+        );
+
+        // Webcams without these controls are simply not compensated for auto exposure
+        let defaults = device
+            .query_controls()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|control| [V4L2_CID_EXPOSURE_ABSOLUTE, V4L2_CID_GAIN].contains(&control.id))
+            .map(|control| (control.id, control.default))
+            .collect();
+
+        let frame = Frame {
+            fourcc: format.fourcc,
+            width: format.width as usize,
+            height: format.height as usize,
+            stride: format.stride as usize,
+        };
+
+        Ok((device, frame, defaults))
+    }
+}
+
+fn negotiate(available: &[FourCC]) -> Option<FourCC> {
+    FORMATS
+        .iter()
+        .map(|fourcc| FourCC::new(fourcc))
+        .find(|fourcc| available.contains(fourcc))
+}
+
+struct Frame {
+    fourcc: FourCC,
+    width: usize,
+    height: usize,
+    stride: usize,
+}
+
+impl Frame {
+    // Average luma in percent, the same for every format as far as the webcam encodes it
+    fn luma(&self, data: &[u8]) -> Result<u8, Box<dyn Error>> {
+        match &self.fourcc.repr {
+            b"GREY" => Ok(self.y_plane_luma(data, 1)),
+            b"YUYV" => Ok(self.y_plane_luma(data, 2)),
+            b"MJPG" => mjpeg_luma(data),
+            b"RGB3" => Ok(rgb_luma(data, self.width * self.height)),
+            _ => Err(format!("Unsupported webcam format: {}", self.fourcc).into()),
+        }
+    }
+
+    // Every n-th byte of a row is a Y sample, rows can be padded beyond the width of the frame
+    fn y_plane_luma(&self, data: &[u8], step: usize) -> u8 {
+        let stride = self.stride.max(self.width * step);
+        mean_percent(
+            data.chunks(stride)
+                .take(self.height)
+                .flat_map(|row| row.iter().take(self.width * step).step_by(step)),
+        )
+    }
+}
+
+fn mjpeg_luma(data: &[u8]) -> Result<u8, Box<dyn Error>> {
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    // Smallest scale the decoder supports is an eighth of the frame, plenty for its average
+    decoder.scale(1, 1)?;
+    let pixels = decoder.decode()?;
+    let info = decoder.info().ok_or("Unable to decode MJPEG frame")?;
+
+    match info.pixel_format {
+        PixelFormat::L8 => Ok(mean_percent(pixels.iter())),
+        PixelFormat::RGB24 => Ok(rgb_luma(
+            &pixels,
+            info.width as usize * info.height as usize,
+        )),
+        format => Err(format!("Unsupported MJPEG pixel format: {:?}", format).into()),
+    }
+}
+
+// Y of BT.601, which webcams use to encode their frames as YUV in the first place
+fn rgb_luma(data: &[u8], pixels: usize) -> u8 {
+    let ys = data
+        .chunks_exact(3)
+        .take(pixels)
+        .map(|rgb| {
+            (0.299 * rgb[0] as f64 + 0.587 * rgb[1] as f64 + 0.114 * rgb[2] as f64).round() as u8
+        })
+        .collect_vec();
+    mean_percent(ys.iter())
+}
+
+fn mean_percent<'a>(samples: impl Iterator<Item = &'a u8>) -> u8 {
+    let (sum, count) = samples.fold((0, 0), |(sum, count), sample| {
+        (sum + *sample as u64, count + 1)
+    });
+
+    if count == 0 {
+        return 0;
+    }
+    (sum as f64 / count as f64 / 255.0 * 100.0).round() as u8
+}
+
+// Current and default values of the controls that auto exposure plays with
+#[derive(Debug, Default)]
+struct Exposure {
+    time: Option<(i64, i64)>,
+    gain: Option<(i64, i64)>,
+}
+
+impl Exposure {
+    fn read(device: &Device, defaults: &ControlDefaults) -> Self {
+        let control = |id| {
+            let default = *defaults.get(&id)?;
+            match device.control(id).ok()?.value {
+                Value::Integer(value) => Some((value, default)),
+                _ => None,
+            }
+        };
+
+        Self {
+            time: control(V4L2_CID_EXPOSURE_ABSOLUTE),
+            gain: control(V4L2_CID_GAIN),
+        }
+    }
+
+    // Camera exposes longer and amplifies more in the dark to keep the frame equally bright,
+    // so luma is scaled back to what it would be with the default settings
+    fn compensate(&self, luma: u8) -> u64 {
+        let factor = [self.time, self.gain]
+            .into_iter()
+            .flatten()
+            .filter(|&(value, default)| value > 0 && default > 0)
+            .map(|(value, default)| default as f64 / value as f64)
+            .product::<f64>();
 
-        Ok((device, width as usize * height as usize))
+        (luma as f64 * factor).round() as u64
     }
 }
 
 pub struct Als {
     webcam_rx: Receiver<u64>,
-    thresholds: HashMap<u64, String>,
     lux: RefCell<u64>,
 }
 
 impl Als {
-    pub fn new(webcam_rx: Receiver<u64>, thresholds: HashMap<u64, String>) -> Self {
+    pub fn new(webcam_rx: Receiver<u64>) -> Self {
         Self {
             webcam_rx,
-            thresholds,
             lux: RefCell::new(DEFAULT_LUX),
         }
     }
@@ -103,12 +315,11 @@ impl Als {
 }
 
 impl super::Als for Als {
-    fn get(&self) -> Result<String, Box<dyn Error>> {
+    fn get(&self) -> Result<u64, Box<dyn Error>> {
         let raw = self.get_raw()?;
-        let profile = super::find_profile(raw, &self.thresholds);
 
-        log::trace!("ALS (webcam): {} ({})", profile, raw);
-        Ok(profile)
+        log::trace!("ALS (webcam): {}", raw);
+        Ok(raw)
     }
 }
 
@@ -117,9 +328,18 @@ mod tests {
     use super::*;
     use std::sync::mpsc;
 
+    fn frame(fourcc: &[u8; 4], width: usize, height: usize, stride: usize) -> Frame {
+        Frame {
+            fourcc: FourCC::new(fourcc),
+            width,
+            height,
+            stride,
+        }
+    }
+
     fn setup() -> (Als, Sender<u64>) {
         let (webcam_tx, webcam_rx) = mpsc::channel();
-        let als = Als::new(webcam_rx, HashMap::default());
+        let als = Als::new(webcam_rx);
         (als, webcam_tx)
     }
 
@@ -166,4 +386,68 @@ mod tests {
         assert_eq!(43, als.get_raw()?);
         Ok(())
     }
+
+    #[test]
+    fn test_negotiate_prefers_formats_with_luma_plane() {
+        let formats = |fourccs: &[&[u8; 4]]| fourccs.iter().map(|f| FourCC::new(f)).collect_vec();
+
+        assert_eq!(
+            Some(FourCC::new(b"YUYV")),
+            negotiate(&formats(&[b"MJPG", b"YUYV", b"RGB3"]))
+        );
+        assert_eq!(
+            Some(FourCC::new(b"MJPG")),
+            negotiate(&formats(&[b"RGB3", b"MJPG"]))
+        );
+        assert_eq!(None, negotiate(&formats(&[b"H264"])));
+    }
+
+    #[test]
+    fn test_luma_of_grey_frame_ignores_row_padding() -> Result<(), Box<dyn Error>> {
+        let data = [255, 255, 0, 0, 255, 255, 0, 0];
+
+        assert_eq!(100, frame(b"GREY", 2, 2, 4).luma(&data)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_luma_of_yuyv_frame_uses_only_y_samples() -> Result<(), Box<dyn Error>> {
+        // Y0 U Y1 V, with chroma at its maximum
+        let data = [0, 255, 255, 255, 0, 255, 255, 255];
+
+        assert_eq!(50, frame(b"YUYV", 4, 1, 8).luma(&data)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_luma_of_rgb_frame_matches_luma_plane() -> Result<(), Box<dyn Error>> {
+        let grey = [128, 128, 128, 128, 128, 128];
+        let green = [0, 255, 0, 0, 255, 0];
+
+        assert_eq!(
+            frame(b"GREY", 2, 1, 2).luma(&[128, 128])?,
+            frame(b"RGB3", 2, 1, 6).luma(&grey)?
+        );
+        assert_eq!(59, frame(b"RGB3", 2, 1, 6).luma(&green)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_luma_of_unsupported_format_fails() {
+        assert!(frame(b"H264", 1, 1, 1).luma(&[0]).is_err());
+    }
+
+    #[test]
+    fn test_compensate_for_auto_exposure() {
+        let exposure = |time, gain| Exposure { time, gain };
+
+        assert_eq!(50, exposure(None, None).compensate(50));
+        assert_eq!(50, exposure(Some((156, 156)), Some((0, 0))).compensate(50));
+        assert_eq!(6, exposure(Some((1250, 156)), None).compensate(50));
+        assert_eq!(
+            10,
+            exposure(Some((312, 156)), Some((80, 16))).compensate(100)
+        );
+        assert_eq!(200, exposure(Some((78, 156)), None).compensate(100));
+    }
 }
@@ -42,6 +42,10 @@ impl Backlight {
             current: None,
         })
     }
+
+    pub fn max_brightness(&self) -> u64 {
+        self.max_brightness
+    }
 }
 
 impl super::Brightness for Backlight {
@@ -1,29 +1,104 @@
 use super::Brightness;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-const TRANSITION_MAX_MS: u64 = 200;
 const TRANSITION_STEP_MS: u64 = 1;
 const WAITING_SLEEP_MS: u64 = 100;
 
+// How brightness moves from the current value to the desired one over time
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Easing {
+    Linear,
+    EaseInOut,
+    // Same ratio of brightness change in the same time, as perceived brightness is logarithmic
+    Exponential,
+}
+
+impl Easing {
+    // Value between start and end at the given progress of the transition (0 to 1)
+    fn interpolate(&self, start: u64, end: u64, progress: f64) -> f64 {
+        let (start, end) = (start as f64, end as f64);
+        match self {
+            Easing::Linear => start + (end - start) * progress,
+            Easing::EaseInOut => {
+                let eased = if progress < 0.5 {
+                    4.0 * progress.powi(3)
+                } else {
+                    1.0 - (2.0 - 2.0 * progress).powi(3) / 2.0
+                };
+                start + (end - start) * eased
+            }
+            // Shifted by one, as zero brightness would never change otherwise
+            Easing::Exponential => {
+                (start + 1.0) * ((end + 1.0) / (start + 1.0)).powf(progress) - 1.0
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Transition {
+    pub brighten: Duration,
+    pub dim: Duration,
+    pub easing: Easing,
+}
+
+// Time source of transitions, so that tests don't have to wait for them
+trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
 pub struct Controller {
     brightness: Box<dyn Brightness>,
     user_tx: Sender<u64>,
     prediction_rx: Receiver<u64>,
+    transition: Transition,
+    clock: Box<dyn Clock>,
     current: Option<u64>,
     target: Option<Target>,
+    // Exact value last set during the transition
+    exact: Option<f64>,
+    disconnected: bool,
 }
 
+// Schedule of a transition, where brightness is expected to be at any point of time
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 struct Target {
+    start: u64,
     desired: u64,
-    step: i64,
+    started: Instant,
+    duration: Duration,
+    easing: Easing,
 }
 
 impl Target {
-    fn reached(&self, current: u64) -> bool {
-        (self.step > 0 && current >= self.desired) || (self.step < 0 && current <= self.desired)
+    fn finished(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.started) >= self.duration
+    }
+
+    // Not rounded to whole values, which devices with finer steps can make use of
+    fn value_at(&self, now: Instant) -> f64 {
+        if self.finished(now) {
+            return self.desired as f64;
+        }
+
+        let progress =
+            now.saturating_duration_since(self.started).as_secs_f64() / self.duration.as_secs_f64();
+        self.easing.interpolate(self.start, self.desired, progress)
     }
 }
 
@@ -32,18 +107,24 @@ impl Controller {
         brightness: Box<dyn Brightness>,
         user_tx: Sender<u64>,
         prediction_rx: Receiver<u64>,
+        transition: Transition,
     ) -> Self {
         Self {
             brightness,
             user_tx,
             prediction_rx,
+            transition,
+            clock: Box::new(SystemClock),
             current: None,
             target: None,
+            exact: None,
+            disconnected: false,
         }
     }
 
-    pub fn run(&mut self) {
-        loop {
+    // Runs until the output is stopped by the supervisor or the predictor goes away
+    pub fn run(&mut self, stop_rx: Receiver<()>) {
+        while !self.disconnected && stop_rx.try_recv() == Err(TryRecvError::Empty) {
             self.step();
         }
     }
@@ -57,7 +138,7 @@ impl Controller {
                 }
 
                 // 2. check if predictor wants to set a new value
-                if let Some(desired) = self.prediction_rx.try_iter().last() {
+                if let Some(desired) = self.next_prediction() {
                     self.update_target(desired);
                 }
 
@@ -70,15 +151,30 @@ impl Controller {
         };
 
         // 4. nothing to do, sleep and check again
-        thread::sleep(Duration::from_millis(WAITING_SLEEP_MS));
+        self.clock.sleep(Duration::from_millis(WAITING_SLEEP_MS));
     }
 
     fn update_current(&mut self, new_brightness: u64) {
         self.current = Some(new_brightness);
-        self.user_tx
-            .send(new_brightness)
-            .expect("Unable to send new brightness value set by user, channel is dead");
+        if self.user_tx.send(new_brightness).is_err() {
+            self.disconnected = true;
+        }
         self.target = None;
+        self.exact = None;
+    }
+
+    fn next_prediction(&mut self) -> Option<u64> {
+        let mut prediction = None;
+        loop {
+            match self.prediction_rx.try_recv() {
+                Ok(value) => prediction = Some(value),
+                Err(TryRecvError::Empty) => return prediction,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnected = true;
+                    return prediction;
+                }
+            }
+        }
     }
 
     fn update_target(&mut self, desired: u64) {
@@ -86,12 +182,20 @@ impl Controller {
             (Some(old_target), _) if old_target.desired == desired => (),
             (_, Some(current)) if desired == current => (),
             (_, Some(current)) => {
-                let step = if desired > current {
-                    div_ceil(desired - current, TRANSITION_MAX_MS)
+                let duration = if desired > current {
+                    self.transition.brighten
                 } else {
-                    -div_ceil(current - desired, TRANSITION_MAX_MS)
+                    self.transition.dim
                 };
-                self.target = Some(Target { desired, step });
+                // New transition starts from wherever the previous one got to
+                self.exact = None;
+                self.target = Some(Target {
+                    start: current,
+                    desired,
+                    started: self.clock.now(),
+                    duration,
+                    easing: self.transition.easing,
+                });
             }
             _ => unreachable!("Current value cannot be None at this point"),
         };
@@ -100,19 +204,28 @@ impl Controller {
     fn transition(&mut self) {
         match (&self.target, self.current) {
             (Some(target), Some(current)) => {
-                if target.reached(current) {
+                let now = self.clock.now();
+                let exact = target.value_at(now);
+                let finished = target.finished(now);
+
+                // Device is already at the value last set, or at the start of the transition
+                if exact != self.exact.unwrap_or(current as f64) {
+                    match self.brightness.set_exact(exact) {
+                        Ok(new_value) => {
+                            self.current = Some(new_value);
+                            self.exact = Some(exact);
+                        }
+                        Err(err) => {
+                            log::error!("Unable to set brightness to value '{}': {:?}", exact, err)
+                        }
+                    };
+                }
+
+                if finished {
                     self.target = None;
+                    self.exact = None;
                 } else {
-                    let new_value = (current as i64 + target.step).max(0) as u64;
-                    match self.brightness.set(new_value) {
-                        Ok(new_value) => self.current = Some(new_value),
-                        Err(err) => log::error!(
-                            "Unable to set brightness to value '{}': {:?}",
-                            new_value,
-                            err
-                        ),
-                    };
-                    thread::sleep(Duration::from_millis(TRANSITION_STEP_MS));
+                    self.clock.sleep(Duration::from_millis(TRANSITION_STEP_MS));
                 }
             }
             _ => unreachable!("Current and target values cannot be None at this point"),
@@ -120,10 +233,6 @@ impl Controller {
     }
 }
 
-fn div_ceil(x: u64, y: u64) -> i64 {
-    ((x + y - 1) / y) as i64
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -132,16 +241,76 @@ mod tests {
     use std::error::Error;
     use std::sync::mpsc;
 
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+
+    const TRANSITION: Transition = Transition {
+        brighten: Duration::from_millis(100),
+        dim: Duration::from_millis(200),
+        easing: Easing::Linear,
+    };
+
+    // Time only moves when the controller sleeps
+    #[derive(Clone)]
+    struct FakeClock {
+        now: Rc<Cell<Instant>>,
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.now.get()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.now.set(self.now.get() + duration);
+        }
+    }
+
     // Intentionally not in main code to prevent confusing fields by accident
-    fn target(desired: u64, step: i64) -> Target {
-        Target { desired, step }
+    fn target(start: u64, desired: u64, started: Instant, duration_ms: u64) -> Target {
+        Target {
+            start,
+            desired,
+            started,
+            duration: Duration::from_millis(duration_ms),
+            easing: Easing::Linear,
+        }
     }
 
     fn setup(brightness_mock: MockBrightness) -> (Controller, Sender<u64>, Receiver<u64>) {
+        let (controller, prediction_tx, user_rx, _) = setup_with_clock(brightness_mock);
+        (controller, prediction_tx, user_rx)
+    }
+
+    fn setup_with_clock(
+        brightness_mock: MockBrightness,
+    ) -> (Controller, Sender<u64>, Receiver<u64>, FakeClock) {
         let (user_tx, user_rx) = mpsc::channel();
         let (prediction_tx, prediction_rx) = mpsc::channel();
-        let controller = Controller::new(Box::new(brightness_mock), user_tx, prediction_rx);
-        (controller, prediction_tx, user_rx)
+        let clock = FakeClock {
+            now: Rc::new(Cell::new(Instant::now())),
+        };
+        let mut controller = Controller::new(
+            Box::new(brightness_mock),
+            user_tx,
+            prediction_rx,
+            TRANSITION,
+        );
+        controller.clock = Box::new(clock.clone());
+        (controller, prediction_tx, user_rx, clock)
+    }
+
+    // Brightness device that remembers every exact value set on it
+    fn recording_mock() -> (MockBrightness, Arc<Mutex<Vec<f64>>>) {
+        let values = Arc::new(Mutex::new(vec![]));
+        let mut brightness_mock = MockBrightness::new();
+        let recorded = values.clone();
+        brightness_mock.expect_set_exact().returning(move |value| {
+            recorded.lock().unwrap().push(value);
+            Ok(value.round() as u64)
+        });
+        (brightness_mock, values)
     }
 
     #[test]
@@ -200,7 +369,7 @@ mod tests {
         prediction_tx.send(37)?;
 
         // ... or we were already in a transition
-        controller.target = Some(target(77, 1));
+        controller.target = Some(target(66, 77, Instant::now(), 100));
 
         // when we execute the next step...
         controller.step();
@@ -213,10 +382,36 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_run_stops_when_predictor_is_gone() {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock.expect_get().returning(|| Ok(42));
+        let (mut controller, prediction_tx, _user_rx) = setup(brightness_mock);
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        drop(prediction_tx);
+
+        // returns instead of looping forever
+        controller.run(stop_rx);
+
+        assert!(controller.disconnected);
+    }
+
+    #[test]
+    fn test_run_stops_when_supervisor_stops_output() {
+        let (mut controller, _prediction_tx, _user_rx) = setup(MockBrightness::new());
+        let (stop_tx, stop_rx) = mpsc::channel();
+
+        drop(stop_tx);
+
+        // returns without even touching the brightness device
+        controller.run(stop_rx);
+    }
+
     #[test]
     fn test_update_target_ignore_when_desired_didnt_change() {
-        let old_target = Some(target(10, -20));
         let (mut controller, _, _) = setup(MockBrightness::new());
+        let old_target = Some(target(20, 10, Instant::now(), 100));
         controller.target = old_target;
         controller.current = Some(7);
 
@@ -227,8 +422,8 @@ mod tests {
 
     #[test]
     fn test_update_target_ignore_when_desired_equals_current() {
-        let old_target = Some(target(10, -20));
         let (mut controller, _, _) = setup(MockBrightness::new());
+        let old_target = Some(target(20, 10, Instant::now(), 100));
         controller.target = old_target;
         controller.current = Some(7);
 
@@ -238,102 +433,151 @@ mod tests {
     }
 
     #[test]
-    fn test_update_target_finds_minimal_step_that_reaches_target_within_transition_duration() {
-        let (mut controller, _, _) = setup(MockBrightness::new());
+    fn test_update_target_uses_duration_of_direction() {
+        let (mut controller, _, _, clock) = setup_with_clock(MockBrightness::new());
+        controller.current = Some(50);
 
-        let test_cases = vec![
-            (0, 1, 1),
-            (10000, 10001, 1),
-            (10000, 10013, 1),
-            (10000, 10199, 1),
-            (10000, 10200, 1),
-            (10000, 10413, 3),
-            (10000, 11732, 9),
-            (10000, 9999, -1),
-            (10000, 9983, -1),
-            (10000, 9801, -1),
-            (10000, 9800, -1),
-            (10000, 9473, -3),
-            (10000, 8433, -8),
-        ];
-
-        for (current, desired, expected_step) in test_cases {
-            controller.current = Some(current);
-            controller.update_target(desired);
-            assert_eq!(Some(target(desired, expected_step)), controller.target);
-        }
+        controller.update_target(80);
+        assert_eq!(Some(target(50, 80, clock.now(), 100)), controller.target);
+
+        controller.update_target(20);
+        assert_eq!(Some(target(50, 20, clock.now(), 200)), controller.target);
     }
 
     #[test]
-    fn test_transition_reset_target_when_reached() {
-        let (mut controller, _, _) = setup(MockBrightness::new());
-        controller.current = Some(10);
-        controller.target = Some(target(10, 20));
+    fn test_update_target_restarts_from_current_value() {
+        let (mut controller, _, _, clock) = setup_with_clock(MockBrightness::new());
+        controller.current = Some(50);
+        controller.update_target(80);
 
-        controller.transition();
+        clock.sleep(Duration::from_millis(40));
+        controller.current = Some(62);
+        controller.update_target(100);
 
-        assert_eq!(None, controller.target);
+        assert_eq!(Some(target(62, 100, clock.now(), 100)), controller.target);
     }
 
     #[test]
-    fn test_transition_increases_brightness_with_next_step() {
+    fn test_transition_follows_schedule() {
+        let (brightness_mock, values) = recording_mock();
+        let (mut controller, _, _, clock) = setup_with_clock(brightness_mock);
+        let started = clock.now();
+        controller.current = Some(0);
+        controller.update_target(100);
+
+        // every transition step sleeps for one millisecond of the fake clock
+        while controller.target.is_some() {
+            controller.transition();
+        }
+
+        let values = values.lock().unwrap();
+        assert_eq!(
+            (1..=100).collect::<Vec<_>>(),
+            values.iter().map(|v| v.round() as u64).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(100), controller.current);
+        assert_eq!(Duration::from_millis(100), clock.now() - started);
+    }
+
+    #[test]
+    fn test_transition_dims_over_dimming_duration() {
+        let (brightness_mock, values) = recording_mock();
+        let (mut controller, _, _, clock) = setup_with_clock(brightness_mock);
+        let started = clock.now();
+        controller.current = Some(100);
+        controller.update_target(0);
+
+        while controller.target.is_some() {
+            controller.transition();
+        }
+
+        assert_eq!(Duration::from_millis(200), clock.now() - started);
+        assert_eq!(200, values.lock().unwrap().len());
+        assert_eq!(Some(&50.0), values.lock().unwrap().get(99));
+        assert_eq!(Some(0), controller.current);
+    }
+
+    #[test]
+    fn test_transition_with_zero_duration_is_immediate() {
         let mut brightness_mock = MockBrightness::new();
         brightness_mock
-            .expect_set()
-            .with(predicate::eq(12))
+            .expect_set_exact()
+            .with(predicate::eq(90.0))
             .times(1)
-            .returning(Ok);
+            .returning(|value| Ok(value as u64));
         let (mut controller, _, _) = setup(brightness_mock);
+        controller.transition.brighten = Duration::ZERO;
         controller.current = Some(10);
-        controller.target = Some(target(20, 2));
+        controller.update_target(90);
 
         controller.transition();
 
-        assert_eq!(Some(12), controller.current);
+        assert_eq!(Some(90), controller.current);
+        assert_eq!(None, controller.target);
     }
 
     #[test]
-    fn test_transition_decreases_brightness_with_next_step() {
-        let mut brightness_mock = MockBrightness::new();
-        brightness_mock
-            .expect_set()
-            .with(predicate::eq(9))
-            .times(1)
-            .returning(Ok);
-        let (mut controller, _, _) = setup(brightness_mock);
+    fn test_transition_reset_target_when_reached() {
+        let (mut controller, _, _, clock) = setup_with_clock(MockBrightness::new());
         controller.current = Some(10);
-        controller.target = Some(target(9, -1));
+        controller.target = Some(target(0, 10, clock.now(), 0));
 
         controller.transition();
 
-        assert_eq!(Some(9), controller.current);
+        assert_eq!(None, controller.target);
     }
 
     #[test]
-    fn test_transition_doesnt_decrease_below_0() {
-        let mut brightness_mock = MockBrightness::new();
-        brightness_mock
-            .expect_set()
-            .with(predicate::eq(0))
-            .times(1)
-            .returning(Ok);
-        let (mut controller, _, _) = setup(brightness_mock);
-        controller.current = Some(1);
-        controller.target = Some(target(0, -2)); // step of -2 should not overshoot
+    fn test_transition_sets_fractions_of_values() {
+        let (brightness_mock, values) = recording_mock();
+        let (mut controller, _, _, clock) = setup_with_clock(brightness_mock);
+        let started = clock.now();
+        controller.current = Some(10);
+        // one whole step every 100ms, devices with finer steps get there gradually
+        controller.target = Some(target(10, 12, started, 200));
 
+        controller.transition();
         controller.transition();
 
-        assert_eq!(Some(0), controller.current);
+        assert_eq!(vec![10.01], *values.lock().unwrap());
+        assert_eq!(Some(10), controller.current);
+        assert_eq!(started + Duration::from_millis(2), clock.now());
+    }
+
+    #[test]
+    fn test_easing_curves() {
+        let linear = Easing::Linear;
+        assert_eq!(10.0, linear.interpolate(10, 110, 0.0));
+        assert_eq!(35.0, linear.interpolate(10, 110, 0.25));
+        assert_eq!(110.0, linear.interpolate(10, 110, 1.0));
+
+        let ease_in_out = Easing::EaseInOut;
+        assert_eq!(0.0, ease_in_out.interpolate(0, 100, 0.0));
+        assert_eq!(6.25, ease_in_out.interpolate(0, 100, 0.25));
+        assert_eq!(50.0, ease_in_out.interpolate(0, 100, 0.5));
+        assert_eq!(93.75, ease_in_out.interpolate(0, 100, 0.75));
+        assert_eq!(100.0, ease_in_out.interpolate(0, 100, 1.0));
+
+        // every third of the time multiplies brightness (shifted by one) by the same ratio
+        let exponential = Easing::Exponential;
+        assert_eq!(0.0, exponential.interpolate(0, 7, 0.0));
+        assert!((exponential.interpolate(0, 7, 1.0 / 3.0) - 1.0).abs() < 1e-9);
+        assert!((exponential.interpolate(0, 7, 2.0 / 3.0) - 3.0).abs() < 1e-9);
+        assert!((exponential.interpolate(7, 0, 1.0 / 3.0) - 3.0).abs() < 1e-9);
+        assert!((exponential.interpolate(0, 7, 1.0) - 7.0).abs() < 1e-9);
     }
 
     #[test]
-    fn test_target_reached() {
-        assert_eq!(false, target(10, 1).reached(9));
-        assert_eq!(true, target(10, 1).reached(10));
-        assert_eq!(true, target(10, 1).reached(11));
-
-        assert_eq!(true, target(10, -1).reached(9));
-        assert_eq!(true, target(10, -1).reached(10));
-        assert_eq!(false, target(10, -1).reached(11));
+    fn test_target_value_at() {
+        let started = Instant::now();
+        let target = target(100, 0, started, 200);
+
+        assert_eq!(100.0, target.value_at(started));
+        assert_eq!(75.0, target.value_at(started + Duration::from_millis(50)));
+        assert_eq!(0.0, target.value_at(started + Duration::from_millis(200)));
+        assert_eq!(0.0, target.value_at(started + Duration::from_millis(500)));
+
+        assert!(!target.finished(started + Duration::from_millis(199)));
+        assert!(target.finished(started + Duration::from_millis(200)));
     }
 }
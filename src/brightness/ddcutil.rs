@@ -1,18 +1,118 @@
 use ddc_hi::{Ddc, Display, FeatureCode};
 use itertools::Itertools;
 use lazy_static::lazy_static;
-use std::cell::RefCell;
 use std::error::Error;
-use std::sync::Mutex;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 lazy_static! {
     static ref DDC_MUTEX: Mutex<()> = Mutex::new(());
 }
 
 const DDC_BRIGHTNESS_FEATURE: FeatureCode = 0x10;
+// Tells whether any control was changed on the monitor itself, e.g. from its on-screen menu
+const DDC_NEW_CONTROL_VALUE_FEATURE: FeatureCode = 0x02;
+const NO_NEW_CONTROL_VALUE: u8 = 0x01;
+const NEW_CONTROL_VALUE: u8 = 0x02;
+// Monitors need time to process a write, DDC/CI asks for at least 50ms between commands
+const MIN_WRITE_INTERVAL_MS: u64 = 50;
+// Checks for changes on the monitor slow down while nothing changes there
+const MIN_POLL_INTERVAL_MS: u64 = 250;
+const MAX_POLL_INTERVAL_MS: u64 = 4000;
+// Brightness is read anyway every so many polls, in case the monitor misses reporting a change
+const FORCED_READ_POLLS: u32 = 10;
+// Monitors that keep failing to report new control values are polled by brightness instead
+const MAX_NEW_CONTROL_VALUE_ERRORS: u32 = 3;
+const RETRY_ATTEMPTS: u32 = 5;
+const RETRY_BACKOFF_MS: u64 = 50;
+
+// Brightness of a monitor, kept apart from the display so that the worker can be tested
+trait Vcp {
+    // Current and maximal value
+    fn get(&mut self) -> Result<(u64, u64), Box<dyn Error>>;
+    fn set(&mut self, value: u64) -> Result<(), Box<dyn Error>>;
+    // Whether a control changed since the last reset, None if the monitor is unable to tell
+    fn new_control_value(&mut self) -> Result<Option<bool>, Box<dyn Error>>;
+    fn reset_new_control_value(&mut self) -> Result<(), Box<dyn Error>>;
+}
+
+impl Vcp for Display {
+    fn get(&mut self) -> Result<(u64, u64), Box<dyn Error>> {
+        let _lock = DDC_MUTEX
+            .lock()
+            .expect("Unable to acquire exclusive access to DDC API");
+        let value = self.handle.get_vcp_feature(DDC_BRIGHTNESS_FEATURE)?;
+        Ok((value.value() as u64, value.maximum() as u64))
+    }
+
+    fn set(&mut self, value: u64) -> Result<(), Box<dyn Error>> {
+        let _lock = DDC_MUTEX
+            .lock()
+            .expect("Unable to acquire exclusive access to DDC API");
+        Ok(self
+            .handle
+            .set_vcp_feature(DDC_BRIGHTNESS_FEATURE, value as u16)?)
+    }
+
+    fn new_control_value(&mut self) -> Result<Option<bool>, Box<dyn Error>> {
+        let _lock = DDC_MUTEX
+            .lock()
+            .expect("Unable to acquire exclusive access to DDC API");
+        let value = self.handle.get_vcp_feature(DDC_NEW_CONTROL_VALUE_FEATURE)?;
+        Ok(match value.sl {
+            NO_NEW_CONTROL_VALUE => Some(false),
+            NEW_CONTROL_VALUE => Some(true),
+            _ => None,
+        })
+    }
+
+    fn reset_new_control_value(&mut self) -> Result<(), Box<dyn Error>> {
+        let _lock = DDC_MUTEX
+            .lock()
+            .expect("Unable to acquire exclusive access to DDC API");
+        Ok(self
+            .handle
+            .set_vcp_feature(DDC_NEW_CONTROL_VALUE_FEATURE, NO_NEW_CONTROL_VALUE as u16)?)
+    }
+}
+
+// Shared by the output and its worker, holds only the latest target so that transition steps
+// the monitor did not get to in time are skipped
+struct Mailbox {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    // Brightness as far as the output is concerned, the target once one is set
+    value: u64,
+    pending: Option<u64>,
+    // Tells the worker whether a value it read is already outdated by a new target
+    generation: u64,
+    stopped: bool,
+}
+
+impl Mailbox {
+    fn new(value: u64) -> Self {
+        Self {
+            state: Mutex::new(State {
+                value,
+                pending: None,
+                generation: 0,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("DDC worker panicked")
+    }
+}
 
 pub struct DdcUtil {
-    display: RefCell<Display>,
+    mailbox: Arc<Mailbox>,
     min_brightness: u64,
     max_brightness: u64,
 }
@@ -20,47 +120,233 @@ pub struct DdcUtil {
 impl DdcUtil {
     pub fn new(name: &str, min_brightness: u64) -> Result<Self, Box<dyn Error>> {
         let mut display = find_display_by_name(name).ok_or("Unable to find display")?;
-        let max_brightness = get_max_brightness(&mut display)?;
+        let (value, max_brightness) = display.get()?;
+        let mailbox = Arc::new(Mailbox::new(value));
+
+        let mut worker = Worker::new(
+            display,
+            mailbox.clone(),
+            Duration::from_millis(MIN_WRITE_INTERVAL_MS),
+        );
+        thread::Builder::new()
+            .name(format!("ddc-{}", name))
+            .spawn(move || worker.run())?;
 
         Ok(Self {
-            display: RefCell::new(display),
+            mailbox,
             min_brightness,
             max_brightness,
         })
     }
-}
 
-impl super::Brightness for DdcUtil {
-    fn get(&mut self) -> Result<u64, Box<dyn Error>> {
+    pub fn max_brightness(&self) -> u64 {
+        self.max_brightness
+    }
+
+    pub fn is_connected(name: &str) -> bool {
         let _lock = DDC_MUTEX
             .lock()
             .expect("Unable to acquire exclusive access to DDC API");
-        Ok(self
-            .display
-            .borrow_mut()
-            .handle
-            .get_vcp_feature(DDC_BRIGHTNESS_FEATURE)?
-            .value() as u64)
+        find_display_by_name(name).is_some()
+    }
+}
+
+impl Drop for DdcUtil {
+    fn drop(&mut self) {
+        self.mailbox.state().stopped = true;
+        self.mailbox.changed.notify_one();
+    }
+}
+
+// Never blocks on the bus, the worker catches up in the background
+impl super::Brightness for DdcUtil {
+    fn get(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(self.mailbox.state().value)
     }
 
     fn set(&mut self, value: u64) -> Result<u64, Box<dyn Error>> {
-        let _lock = DDC_MUTEX
-            .lock()
-            .expect("Unable to acquire exclusive access to DDC API");
         let value = value.clamp(self.min_brightness, self.max_brightness);
-        self.display
-            .borrow_mut()
-            .handle
-            .set_vcp_feature(DDC_BRIGHTNESS_FEATURE, value as u16)?;
+
+        let mut state = self.mailbox.state();
+        state.value = value;
+        state.pending = Some(value);
+        state.generation += 1;
+        self.mailbox.changed.notify_one();
+
         Ok(value)
     }
 }
 
-fn get_max_brightness(display: &mut Display) -> Result<u64, Box<dyn Error>> {
-    Ok(display
-        .handle
-        .get_vcp_feature(DDC_BRIGHTNESS_FEATURE)?
-        .maximum() as u64)
+struct Worker<V: Vcp> {
+    vcp: V,
+    mailbox: Arc<Mailbox>,
+    min_interval: Duration,
+    last_write: Option<Instant>,
+    poll_interval: Duration,
+    // Until the monitor turns out to not support the new control value feature
+    notifies_changes: bool,
+    new_control_value_errors: u32,
+    // Polls since brightness was last read, or None once it needs to be read right away
+    unread_polls: Option<u32>,
+}
+
+impl<V: Vcp> Worker<V> {
+    fn new(vcp: V, mailbox: Arc<Mailbox>, min_interval: Duration) -> Self {
+        Self {
+            vcp,
+            mailbox,
+            min_interval,
+            last_write: None,
+            poll_interval: Duration::from_millis(MIN_POLL_INTERVAL_MS),
+            notifies_changes: true,
+            new_control_value_errors: 0,
+            unread_polls: Some(0),
+        }
+    }
+
+    fn run(&mut self) {
+        while self.step() {}
+    }
+
+    // Writes the latest target or checks for changes made on the monitor itself,
+    // returns false once the output is gone
+    fn step(&mut self) -> bool {
+        let poll_interval = self.poll_interval;
+        let state = self.mailbox.state();
+        let (state, _) = self
+            .mailbox
+            .changed
+            .wait_timeout_while(state, poll_interval, |state| {
+                state.pending.is_none() && !state.stopped
+            })
+            .expect("DDC worker panicked");
+
+        match (state.stopped, state.pending) {
+            (true, _) => false,
+            (false, Some(_)) => {
+                drop(state);
+                self.write();
+                true
+            }
+            (false, None) => {
+                let generation = state.generation;
+                drop(state);
+                self.poll(generation);
+                true
+            }
+        }
+    }
+
+    fn write(&mut self) {
+        for attempt in 0..RETRY_ATTEMPTS {
+            if let Some(last_write) = self.last_write {
+                thread::sleep(self.min_interval.saturating_sub(last_write.elapsed()));
+            }
+
+            // Targets set while waiting replace the one that woke the worker up
+            let value = match self.mailbox.state().pending.take() {
+                Some(value) => value,
+                None => return,
+            };
+
+            let result = self.vcp.set(value);
+            self.last_write = Some(Instant::now());
+            match result {
+                Ok(()) => return,
+                Err(err) => {
+                    log::debug!("Unable to set DDC brightness to '{}': {}", value, err);
+                    // Failed writes may still have reached the monitor, or not be reported by it
+                    self.unread_polls = None;
+                    // Retry unless a newer target is already waiting
+                    self.mailbox.state().pending.get_or_insert(value);
+                    if attempt + 1 < RETRY_ATTEMPTS {
+                        thread::sleep(Duration::from_millis(RETRY_BACKOFF_MS << attempt));
+                    }
+                }
+            }
+        }
+
+        let pending = self.mailbox.state().pending.take();
+        if let Some(value) = pending {
+            log::warn!(
+                "Giving up setting DDC brightness to '{}' after {} attempts",
+                value,
+                RETRY_ATTEMPTS
+            );
+            self.reread();
+        }
+    }
+
+    // Monitor most likely kept its previous brightness, which the output should not assume as
+    // the target it failed to get to
+    fn reread(&mut self) {
+        match self.vcp.get() {
+            Ok((value, _)) => {
+                let mut state = self.mailbox.state();
+                if state.pending.is_none() {
+                    state.value = value;
+                    state.generation += 1;
+                }
+            }
+            Err(err) => log::debug!("Unable to get DDC brightness: {}", err),
+        }
+    }
+
+    fn poll(&mut self, generation: u64) {
+        let forced = self
+            .unread_polls
+            .is_none_or(|polls| polls + 1 >= FORCED_READ_POLLS);
+        if self.notifies_changes && !forced {
+            match self.vcp.new_control_value() {
+                Ok(Some(false)) => {
+                    self.new_control_value_errors = 0;
+                    self.unread_polls = self.unread_polls.map(|polls| polls + 1);
+                    return self.slow_down();
+                }
+                Ok(Some(true)) => {
+                    self.new_control_value_errors = 0;
+                    if let Err(err) = self.vcp.reset_new_control_value() {
+                        log::debug!("Unable to reset DDC new control value: {}", err);
+                    }
+                }
+                Ok(None) => {
+                    log::debug!("Monitor does not report new control values, reading brightness");
+                    self.notifies_changes = false;
+                }
+                Err(err) => {
+                    log::debug!("Unable to get DDC new control value: {}", err);
+                    self.new_control_value_errors += 1;
+                    if self.new_control_value_errors >= MAX_NEW_CONTROL_VALUE_ERRORS {
+                        log::debug!("Monitor keeps failing to report new control values, reading brightness");
+                        self.notifies_changes = false;
+                    }
+                }
+            }
+        }
+
+        self.unread_polls = Some(0);
+        match self.vcp.get() {
+            Ok((value, _)) => {
+                let mut state = self.mailbox.state();
+                // A target set in the meantime was not written yet, the value is outdated
+                let outdated = state.generation != generation || state.pending.is_some();
+                if !outdated && state.value != value {
+                    state.value = value;
+                    // User is likely still adjusting it
+                    self.poll_interval = Duration::from_millis(MIN_POLL_INTERVAL_MS);
+                    return;
+                }
+            }
+            Err(err) => log::debug!("Unable to get DDC brightness: {}", err),
+        }
+
+        self.slow_down();
+    }
+
+    fn slow_down(&mut self) {
+        self.poll_interval =
+            (self.poll_interval * 2).min(Duration::from_millis(MAX_POLL_INTERVAL_MS));
+    }
 }
 
 fn find_display_by_name(name: &str) -> Option<Display> {
@@ -93,3 +379,260 @@ fn find_display_by_name(name: &str) -> Option<Display> {
             .map(|_| display)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brightness::Brightness;
+
+    // Monitor that remembers writes and fails as many of them as asked to
+    #[derive(Clone, Default)]
+    struct FakeVcp {
+        value: Arc<Mutex<u64>>,
+        writes: Arc<Mutex<Vec<(u64, Instant)>>>,
+        failures: Arc<Mutex<u32>>,
+        // None for monitors without the new control value feature
+        new_control_value: Arc<Mutex<Option<bool>>>,
+        new_control_value_fails: Arc<Mutex<bool>>,
+        reads: Arc<Mutex<u32>>,
+    }
+
+    impl Vcp for FakeVcp {
+        fn get(&mut self) -> Result<(u64, u64), Box<dyn Error>> {
+            *self.reads.lock().unwrap() += 1;
+            Ok((*self.value.lock().unwrap(), 100))
+        }
+
+        fn new_control_value(&mut self) -> Result<Option<bool>, Box<dyn Error>> {
+            if *self.new_control_value_fails.lock().unwrap() {
+                return Err("I2C error".into());
+            }
+            Ok(*self.new_control_value.lock().unwrap())
+        }
+
+        fn reset_new_control_value(&mut self) -> Result<(), Box<dyn Error>> {
+            *self.new_control_value.lock().unwrap() = Some(false);
+            Ok(())
+        }
+
+        fn set(&mut self, value: u64) -> Result<(), Box<dyn Error>> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err("I2C error".into());
+            }
+            *self.value.lock().unwrap() = value;
+            self.writes.lock().unwrap().push((value, Instant::now()));
+            Ok(())
+        }
+    }
+
+    impl FakeVcp {
+        fn written(&self) -> Vec<u64> {
+            self.writes
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(v, _)| *v)
+                .collect()
+        }
+    }
+
+    fn setup(min_interval_ms: u64) -> (DdcUtil, Worker<FakeVcp>, FakeVcp) {
+        let vcp = FakeVcp::default();
+        let mailbox = Arc::new(Mailbox::new(0));
+        let ddc = DdcUtil {
+            mailbox: mailbox.clone(),
+            min_brightness: 1,
+            max_brightness: 100,
+        };
+        let worker = Worker::new(vcp.clone(), mailbox, Duration::from_millis(min_interval_ms));
+        (ddc, worker, vcp)
+    }
+
+    #[test]
+    fn test_set_does_not_wait_for_the_monitor() {
+        let (mut ddc, _worker, vcp) = setup(0);
+
+        assert_eq!(1, ddc.set(0).unwrap());
+        assert_eq!(100, ddc.set(150).unwrap());
+        assert_eq!(100, ddc.get().unwrap());
+        assert_eq!(Vec::<u64>::new(), vcp.written());
+    }
+
+    #[test]
+    fn test_worker_writes_only_latest_target() {
+        let (mut ddc, mut worker, vcp) = setup(0);
+
+        ddc.set(10).unwrap();
+        ddc.set(20).unwrap();
+        ddc.set(30).unwrap();
+        assert!(worker.step());
+
+        assert_eq!(vec![30], vcp.written());
+        assert_eq!(30, ddc.get().unwrap());
+    }
+
+    #[test]
+    fn test_worker_respects_min_interval_between_writes() {
+        let (mut ddc, mut worker, vcp) = setup(30);
+
+        ddc.set(10).unwrap();
+        worker.step();
+        ddc.set(20).unwrap();
+        worker.step();
+
+        let writes = vcp.writes.lock().unwrap();
+        assert_eq!(2, writes.len());
+        assert!(writes[1].1 - writes[0].1 >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn test_worker_retries_failed_writes() {
+        let (mut ddc, mut worker, vcp) = setup(0);
+        *vcp.failures.lock().unwrap() = 2;
+
+        ddc.set(42).unwrap();
+        worker.step();
+
+        assert_eq!(vec![42], vcp.written());
+        assert_eq!(0, *vcp.failures.lock().unwrap());
+    }
+
+    #[test]
+    fn test_worker_gives_up_after_all_attempts() {
+        let (mut ddc, mut worker, vcp) = setup(0);
+        *vcp.failures.lock().unwrap() = RETRY_ATTEMPTS;
+
+        ddc.set(42).unwrap();
+        worker.step();
+
+        assert_eq!(Vec::<u64>::new(), vcp.written());
+        assert_eq!(None, ddc.mailbox.state().pending);
+        assert_eq!(0, ddc.get().unwrap());
+        assert_eq!(2, ddc.mailbox.state().generation);
+    }
+
+    #[test]
+    fn test_worker_notices_changes_made_on_monitor() {
+        let (mut ddc, mut worker, vcp) = setup(0);
+        *vcp.value.lock().unwrap() = 66;
+
+        assert!(worker.step());
+
+        assert_eq!(66, ddc.get().unwrap());
+    }
+
+    #[test]
+    fn test_worker_stops_with_output() {
+        let (ddc, mut worker, _) = setup(0);
+
+        drop(ddc);
+
+        assert!(!worker.step());
+    }
+
+    #[test]
+    fn test_poll_reads_brightness_only_when_monitor_reports_change() {
+        let (ddc, mut worker, vcp) = setup(0);
+        *vcp.new_control_value.lock().unwrap() = Some(false);
+        *vcp.value.lock().unwrap() = 66;
+
+        worker.poll(0);
+        assert_eq!(0, *vcp.reads.lock().unwrap());
+        assert_eq!(0, ddc.mailbox.state().value);
+
+        *vcp.new_control_value.lock().unwrap() = Some(true);
+        worker.poll(0);
+        assert_eq!(1, *vcp.reads.lock().unwrap());
+        assert_eq!(66, ddc.mailbox.state().value);
+        assert_eq!(Some(false), *vcp.new_control_value.lock().unwrap());
+    }
+
+    #[test]
+    fn test_poll_falls_back_to_reading_brightness() {
+        let (_ddc, mut worker, vcp) = setup(0);
+
+        worker.poll(0);
+        worker.poll(0);
+
+        assert!(!worker.notifies_changes);
+        assert_eq!(2, *vcp.reads.lock().unwrap());
+    }
+
+    #[test]
+    fn test_poll_reads_brightness_every_so_often() {
+        let (_ddc, mut worker, vcp) = setup(0);
+        *vcp.new_control_value.lock().unwrap() = Some(false);
+
+        for _ in 0..FORCED_READ_POLLS * 2 {
+            worker.poll(0);
+        }
+
+        assert_eq!(2, *vcp.reads.lock().unwrap());
+    }
+
+    #[test]
+    fn test_poll_reads_brightness_after_failed_write() {
+        let (mut ddc, mut worker, vcp) = setup(0);
+        *vcp.new_control_value.lock().unwrap() = Some(false);
+        *vcp.failures.lock().unwrap() = 1;
+
+        ddc.set(42).unwrap();
+        worker.step();
+        worker.poll(1);
+        worker.poll(1);
+
+        assert_eq!(vec![42], vcp.written());
+        assert_eq!(1, *vcp.reads.lock().unwrap());
+    }
+
+    #[test]
+    fn test_poll_falls_back_to_reading_brightness_after_repeated_errors() {
+        let (_ddc, mut worker, vcp) = setup(0);
+        *vcp.new_control_value_fails.lock().unwrap() = true;
+
+        for _ in 0..MAX_NEW_CONTROL_VALUE_ERRORS {
+            assert!(worker.notifies_changes);
+            worker.poll(0);
+        }
+
+        assert!(!worker.notifies_changes);
+    }
+
+    #[test]
+    fn test_poll_interval_adapts_to_changes() {
+        let (_ddc, mut worker, vcp) = setup(0);
+        *vcp.new_control_value.lock().unwrap() = Some(false);
+
+        worker.poll(0);
+        assert_eq!(Duration::from_millis(500), worker.poll_interval);
+        for _ in 0..10 {
+            worker.poll(0);
+        }
+        assert_eq!(
+            Duration::from_millis(MAX_POLL_INTERVAL_MS),
+            worker.poll_interval
+        );
+
+        // change on the monitor makes checks frequent again
+        *vcp.new_control_value.lock().unwrap() = Some(true);
+        *vcp.value.lock().unwrap() = 66;
+        worker.poll(0);
+        assert_eq!(
+            Duration::from_millis(MIN_POLL_INTERVAL_MS),
+            worker.poll_interval
+        );
+    }
+
+    #[test]
+    fn test_poll_ignores_values_outdated_by_new_target() {
+        let (mut ddc, mut worker, vcp) = setup(0);
+        *vcp.value.lock().unwrap() = 66;
+
+        ddc.set(30).unwrap();
+        worker.poll(0);
+
+        assert_eq!(30, ddc.get().unwrap());
+    }
+}
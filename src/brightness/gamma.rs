@@ -0,0 +1,264 @@
+use crate::frame::capturer::shm::shm_file;
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use wayland_client::{
+    protocol::{wl_output::WlOutput, wl_registry::WlRegistry},
+    Display, EventQueue, GlobalManager, Main,
+};
+use wayland_protocols::unstable::xdg_output::v1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1;
+use wayland_protocols::unstable::xdg_output::v1::client::zxdg_output_v1::Event::Description;
+use wayland_protocols::wlr::unstable::gamma_control::v1::client::{
+    zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1,
+    zwlr_gamma_control_v1::{self, ZwlrGammaControlV1},
+};
+
+// Brightness is how far the gamma ramps are scaled down, the display decodes the scaled signal
+// with its own gamma, so the configured curve is the only one applied on top
+const MAX_BRIGHTNESS: u64 = 10000;
+
+// Dims displays without a backlight or DDC/CI by scaling their gamma ramps
+pub struct Gamma {
+    value_tx: Sender<u64>,
+    min_brightness: u64,
+    current: u64,
+}
+
+impl Gamma {
+    pub fn new(name: &str, min_brightness: u64) -> Result<Self, Box<dyn Error>> {
+        let (value_tx, value_rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        // Wayland objects stay on the thread that created them
+        let output_name = name.to_string();
+        thread::Builder::new()
+            .name(format!("gamma-{}", name))
+            .spawn(move || {
+                let mut worker = match Worker::new(&output_name) {
+                    Ok(worker) => worker,
+                    Err(err) => {
+                        let _ = ready_tx.send(Err(err.to_string()));
+                        return;
+                    }
+                };
+                let _ = ready_tx.send(Ok(()));
+
+                if let Err(err) = worker.run(value_rx) {
+                    log::warn!("Gamma control of '{}' stopped: {}", output_name, err);
+                }
+            })?;
+        ready_rx.recv()??;
+
+        // Original gamma ramps are unknown, they are expected to be at full brightness
+        Ok(Self {
+            value_tx,
+            min_brightness,
+            current: MAX_BRIGHTNESS,
+        })
+    }
+
+    pub fn max_brightness(&self) -> u64 {
+        MAX_BRIGHTNESS
+    }
+
+    pub fn is_connected(name: &str) -> bool {
+        let connected = || -> Result<bool, Box<dyn Error>> {
+            let (mut event_queue, globals, registry) = connect()?;
+            Ok(find_output(&mut event_queue, &globals, &registry, name)?.is_some())
+        };
+        connected().unwrap_or(false)
+    }
+}
+
+impl super::Brightness for Gamma {
+    fn get(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(self.current)
+    }
+
+    fn set(&mut self, value: u64) -> Result<u64, Box<dyn Error>> {
+        let value = value.clamp(self.min_brightness, MAX_BRIGHTNESS);
+        self.value_tx
+            .send(value)
+            .map_err(|_| "Gamma control is gone")?;
+        self.current = value;
+        Ok(value)
+    }
+}
+
+struct Worker {
+    event_queue: EventQueue,
+    gamma_control: Main<ZwlrGammaControlV1>,
+    size: usize,
+    failed: Rc<Cell<bool>>,
+}
+
+impl Worker {
+    fn new(name: &str) -> Result<Self, Box<dyn Error>> {
+        let (mut event_queue, globals, registry) = connect()?;
+        let gamma_manager = globals
+            .instantiate_exact::<ZwlrGammaControlManagerV1>(1)
+            .map_err(|_| "Gamma control is not supported by the compositor")?;
+        let output =
+            find_output(&mut event_queue, &globals, &registry, name)?.ok_or("Output not found")?;
+
+        let size = Rc::new(Cell::new(None));
+        let failed = Rc::new(Cell::new(false));
+        let gamma_control = gamma_manager.get_gamma_control(&output);
+        {
+            let size = size.clone();
+            let failed = failed.clone();
+            gamma_control.quick_assign(move |_, event, _| match event {
+                zwlr_gamma_control_v1::Event::GammaSize { size: value } => {
+                    size.set(Some(value as usize))
+                }
+                zwlr_gamma_control_v1::Event::Failed => failed.set(true),
+                _ => {}
+            });
+        }
+        event_queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+
+        match (failed.get(), size.get()) {
+            (false, Some(size)) if size > 0 => Ok(Self {
+                event_queue,
+                gamma_control,
+                size,
+                failed,
+            }),
+            _ => {
+                gamma_control.destroy();
+                Err("Gamma control failed, another client might be using it".into())
+            }
+        }
+    }
+
+    fn run(&mut self, value_rx: Receiver<u64>) -> Result<(), Box<dyn Error>> {
+        // Nothing changes in between values, so there is no need to wake up for anything else
+        let result = loop {
+            let value = match value_rx.recv() {
+                // Only the latest value matters, steps that were not applied in time are skipped
+                Ok(value) => value_rx.try_iter().last().unwrap_or(value),
+                Err(_) => break Ok(()),
+            };
+
+            if let Err(err) = self.apply(value) {
+                break Err(err);
+            }
+            if self.failed.get() {
+                break Err("Gamma control failed, the output might be gone".into());
+            }
+        };
+
+        // Compositor restores the original gamma ramps once the control is gone, which it
+        // also does on its own if wluma exits without getting here
+        self.gamma_control.destroy();
+        let _ = self.event_queue.sync_roundtrip(&mut (), |_, _, _| {});
+        result
+    }
+
+    fn apply(&mut self, value: u64) -> Result<(), Box<dyn Error>> {
+        let factor = value as f64 / MAX_BRIGHTNESS as f64;
+        let bytes = ramps(self.size, factor)
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect::<Vec<_>>();
+
+        // Compositor reads the table when handling the request, the file can go after a roundtrip
+        let file = shm_file()?;
+        file.write_all_at(&bytes, 0)?;
+        self.gamma_control.set_gamma(file.as_raw_fd());
+        self.event_queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+        Ok(())
+    }
+}
+
+// Red, green and blue ramps one after another, scaled down from identity
+fn ramps(size: usize, factor: f64) -> Vec<u16> {
+    let ramp = (0..size).map(|i| {
+        let identity = if size > 1 {
+            i as f64 / (size - 1) as f64
+        } else {
+            1.0
+        };
+        (identity * factor.clamp(0.0, 1.0) * u16::MAX as f64).round() as u16
+    });
+    ramp.clone().chain(ramp.clone()).chain(ramp).collect()
+}
+
+fn connect() -> Result<(EventQueue, GlobalManager, Main<WlRegistry>), Box<dyn Error>> {
+    let display = Display::connect_to_env()?;
+    let mut event_queue = display.create_event_queue();
+    let attached_display = display.attach(event_queue.token());
+    let registry = attached_display.get_registry();
+    let globals = GlobalManager::new(&attached_display);
+    event_queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+    Ok((event_queue, globals, registry))
+}
+
+// Output whose description contains the name, same as for the capturers
+fn find_output(
+    event_queue: &mut EventQueue,
+    globals: &GlobalManager,
+    registry: &Main<WlRegistry>,
+    name: &str,
+) -> Result<Option<Main<WlOutput>>, Box<dyn Error>> {
+    let xdg_output_manager = globals.instantiate_exact::<ZxdgOutputManagerV1>(3)?;
+    let found = Rc::new(RefCell::new(None));
+
+    for (id, _, _) in globals
+        .list()
+        .iter()
+        .filter(|(_, interface, _)| interface == "wl_output")
+    {
+        let output = registry.bind::<WlOutput>(1, *id);
+        let found = found.clone();
+        let desired_output = name.to_string();
+        xdg_output_manager
+            .get_xdg_output(&output)
+            .quick_assign(move |_, event, _| match event {
+                Description { description } if description.contains(&desired_output) => {
+                    log::debug!(
+                        "Using output '{}' for config '{}'",
+                        description,
+                        desired_output
+                    );
+                    found.borrow_mut().get_or_insert_with(|| output.clone());
+                }
+                _ => {}
+            });
+    }
+    event_queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+
+    let output = found.borrow_mut().take();
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ramps_at_full_brightness_are_identity() {
+        let ramps = ramps(3, 1.0);
+
+        assert_eq!(
+            vec![0, 32768, 65535, 0, 32768, 65535, 0, 32768, 65535],
+            ramps
+        );
+    }
+
+    #[test]
+    fn test_ramps_are_scaled_down() {
+        let ramps = ramps(2, 0.5);
+
+        assert_eq!(vec![0, 32768, 0, 32768, 0, 32768], ramps);
+    }
+
+    #[test]
+    fn test_ramps_do_not_overflow() {
+        assert_eq!(vec![65535; 3], ramps(1, 2.0));
+    }
+}
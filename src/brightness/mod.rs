@@ -6,13 +6,22 @@ use mockall::*;
 mod backlight;
 mod controller;
 mod ddcutil;
+mod gamma;
+mod perceptual;
 
 pub use backlight::Backlight;
-pub use controller::Controller;
+pub use controller::{Controller, Easing, Transition};
 pub use ddcutil::DdcUtil;
+pub use gamma::Gamma;
+pub use perceptual::{Curve, Perceptual, Scale};
 
 #[cfg_attr(test, automock)]
 pub trait Brightness {
     fn get(&mut self) -> Result<u64, Box<dyn Error>>;
     fn set(&mut self, value: u64) -> Result<u64, Box<dyn Error>>;
+
+    // Fractions of a value are worth keeping for devices with finer steps, e.g. in transitions
+    fn set_exact(&mut self, value: f64) -> Result<u64, Box<dyn Error>> {
+        self.set(value.round().max(0.0) as u64)
+    }
 }
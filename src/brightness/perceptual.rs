@@ -0,0 +1,230 @@
+use super::Brightness;
+use std::error::Error;
+
+const MAX_PERCENT: u64 = 100;
+
+// How the light output of a device relates to the brightness perceived by the eye
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+    // Light output is perceived brightness raised to the power of gamma, 1 being linear
+    Gamma(f64),
+    // Perceived brightness grows with the logarithm of light output, base being the contrast
+    // between full and barely visible brightness
+    Logarithmic(f64),
+}
+
+impl Curve {
+    // Both take and give fractions of the full brightness, between 0 and 1
+    fn perceived(&self, light: f64) -> f64 {
+        match *self {
+            Curve::Gamma(gamma) => light.powf(1.0 / gamma),
+            Curve::Logarithmic(base) => (1.0 + (base - 1.0) * light).ln() / base.ln(),
+        }
+    }
+
+    fn light(&self, perceived: f64) -> f64 {
+        match *self {
+            Curve::Gamma(gamma) => perceived.powf(gamma),
+            Curve::Logarithmic(base) => (base.powf(perceived) - 1.0) / (base - 1.0),
+        }
+    }
+}
+
+// Converts between raw units of a device and percent of perceived brightness
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scale {
+    curve: Curve,
+    max: u64,
+}
+
+impl Scale {
+    pub fn new(curve: Curve, max: u64) -> Self {
+        Self { curve, max }
+    }
+
+    pub fn percent(&self, raw: u64) -> u64 {
+        if self.max == 0 {
+            return 0;
+        }
+        let light = raw.min(self.max) as f64 / self.max as f64;
+        (self.curve.perceived(light) * MAX_PERCENT as f64).round() as u64
+    }
+
+    pub fn raw(&self, percent: f64) -> u64 {
+        let perceived = percent.clamp(0.0, MAX_PERCENT as f64) / MAX_PERCENT as f64;
+        (self.curve.light(perceived) * self.max as f64).round() as u64
+    }
+}
+
+// Brightness in percent, so that learned values mean the same on every device
+pub struct Perceptual {
+    brightness: Box<dyn Brightness + Send>,
+    scale: Scale,
+    // Several percents share a raw value on devices with few steps, the last one set is kept
+    last: Option<(u64, u64)>,
+}
+
+impl Perceptual {
+    pub fn new(brightness: Box<dyn Brightness + Send>, scale: Scale) -> Self {
+        Self {
+            brightness,
+            scale,
+            last: None,
+        }
+    }
+
+    fn percent(&mut self, raw: u64) -> u64 {
+        match self.last {
+            Some((last_raw, percent)) if last_raw == raw => percent,
+            _ => {
+                let percent = self.scale.percent(raw);
+                self.last = Some((raw, percent));
+                percent
+            }
+        }
+    }
+}
+
+impl Brightness for Perceptual {
+    fn get(&mut self) -> Result<u64, Box<dyn Error>> {
+        let raw = self.brightness.get()?;
+        Ok(self.percent(raw))
+    }
+
+    fn set(&mut self, value: u64) -> Result<u64, Box<dyn Error>> {
+        self.set_exact(value as f64)
+    }
+
+    // Raw value is mapped from the exact percent, so devices with many steps change smoothly
+    fn set_exact(&mut self, value: f64) -> Result<u64, Box<dyn Error>> {
+        let exact = value.clamp(0.0, MAX_PERCENT as f64);
+        let percent = exact.round() as u64;
+        let raw = self.scale.raw(exact);
+
+        // Device is already there, writing again would only slow transitions down
+        let raw_set = match self.last {
+            Some((last_raw, _)) if last_raw == raw => raw,
+            _ => self.brightness.set(raw)?,
+        };
+
+        if raw_set == raw {
+            self.last = Some((raw, percent));
+            return Ok(percent);
+        }
+        Ok(self.percent(raw_set))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brightness::MockBrightness;
+    use mockall::predicate;
+
+    #[test]
+    fn test_scale_gamma() {
+        let scale = Scale::new(Curve::Gamma(2.0), 10000);
+
+        assert_eq!(0, scale.percent(0));
+        assert_eq!(50, scale.percent(2500));
+        assert_eq!(100, scale.percent(10000));
+        assert_eq!(100, scale.percent(20000));
+
+        assert_eq!(0, scale.raw(0.0));
+        assert_eq!(2500, scale.raw(50.0));
+        assert_eq!(10000, scale.raw(100.0));
+        assert_eq!(10000, scale.raw(150.0));
+    }
+
+    #[test]
+    fn test_scale_logarithmic() {
+        let scale = Scale::new(Curve::Logarithmic(101.0), 1000);
+
+        assert_eq!(0, scale.percent(0));
+        assert_eq!(50, scale.percent(90));
+        assert_eq!(100, scale.percent(1000));
+
+        assert_eq!(90, scale.raw(50.0));
+        assert_eq!(1000, scale.raw(100.0));
+    }
+
+    #[test]
+    fn test_scale_is_same_across_devices() {
+        let intel = Scale::new(Curve::Gamma(2.2), 120000);
+        let monitor = Scale::new(Curve::Gamma(2.2), 100);
+
+        for percent in [0, 10, 50, 90, 100] {
+            assert_eq!(percent, intel.percent(intel.raw(percent as f64)));
+        }
+        assert_eq!(intel.percent(60000), monitor.percent(50));
+    }
+
+    #[test]
+    fn test_get_converts_to_percent() {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock.expect_get().return_once(|| Ok(2500));
+        let mut perceptual = Perceptual::new(
+            Box::new(brightness_mock),
+            Scale::new(Curve::Gamma(2.0), 10000),
+        );
+
+        assert_eq!(50, perceptual.get().unwrap());
+    }
+
+    #[test]
+    fn test_set_converts_to_raw_and_back() {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock
+            .expect_set()
+            .with(predicate::eq(2500))
+            .times(1)
+            .returning(Ok);
+        // minimal brightness of the device is respected
+        brightness_mock
+            .expect_set()
+            .with(predicate::eq(0))
+            .times(1)
+            .returning(|_| Ok(1));
+        let mut perceptual = Perceptual::new(
+            Box::new(brightness_mock),
+            Scale::new(Curve::Gamma(2.0), 10000),
+        );
+
+        assert_eq!(50, perceptual.set(50).unwrap());
+        assert_eq!(1, perceptual.set(0).unwrap());
+    }
+
+    #[test]
+    fn test_set_exact_keeps_fractions_of_percent() {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock
+            .expect_set()
+            .with(predicate::eq(2525))
+            .times(1)
+            .returning(Ok);
+        let mut perceptual = Perceptual::new(
+            Box::new(brightness_mock),
+            Scale::new(Curve::Gamma(2.0), 10000),
+        );
+
+        assert_eq!(50, perceptual.set_exact(50.25).unwrap());
+    }
+
+    #[test]
+    fn test_set_keeps_percent_when_raw_value_is_shared() {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock
+            .expect_set()
+            .with(predicate::eq(1))
+            .times(1)
+            .returning(Ok);
+        brightness_mock.expect_get().returning(|| Ok(1));
+        let mut perceptual =
+            Perceptual::new(Box::new(brightness_mock), Scale::new(Curve::Gamma(2.0), 3));
+
+        // 50% and 55% are both the lowest step of a keyboard, which is written only once
+        assert_eq!(50, perceptual.set(50).unwrap());
+        assert_eq!(55, perceptual.set(55).unwrap());
+        assert_eq!(55, perceptual.get().unwrap());
+    }
+}
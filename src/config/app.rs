@@ -1,27 +1,122 @@
+use crate::frame::{Metric, Region};
 use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
 pub enum Capturer {
     Pipewire,
-
+    Screencopy,
+    ExtImageCopy,
     Wlroots,
     None,
 }
 
+#[derive(Debug)]
+pub enum IioMode {
+    Poll,
+    Buffered,
+}
+
+#[derive(Debug)]
+pub struct SolarProfiles {
+    pub night: String,
+    pub twilight: String,
+    pub golden_hour: String,
+    pub day: String,
+}
+
+#[derive(Debug)]
+pub struct IioCalibration {
+    pub x: [f64; 3],
+    pub y: [f64; 3],
+    pub z: [f64; 3],
+}
+
 #[derive(Debug)]
 pub enum Als {
     Iio {
         path: String,
         thresholds: HashMap<u64, String>,
+        mode: IioMode,
+        continuous: bool,
+        calibration: IioCalibration,
+        color_thresholds: HashMap<u64, String>,
     },
     Time {
         thresholds: HashMap<u64, String>,
     },
+    Solar {
+        latitude: f64,
+        longitude: f64,
+        profiles: SolarProfiles,
+    },
+    Dbus {
+        thresholds: HashMap<u64, String>,
+        continuous: bool,
+    },
     Webcam {
         video: usize,
         thresholds: HashMap<u64, String>,
+        persistent: bool,
+        compensate: bool,
+    },
+    Command {
+        command: String,
+        interval: u64,
+        thresholds: HashMap<u64, String>,
+        continuous: bool,
+    },
+    Fifo {
+        path: String,
+        thresholds: HashMap<u64, String>,
+        continuous: bool,
     },
+    Socket {
+        path: String,
+        thresholds: HashMap<u64, String>,
+        continuous: bool,
+    },
+    None,
+}
+
+#[derive(Debug)]
+pub struct AlsSource {
+    pub als: Als,
+    pub weight: f64,
+    pub priority: u32,
+}
+
+#[derive(Debug)]
+pub enum AlsFilter {
     None,
+    Ema { alpha: f64 },
+    Median { window: usize },
+}
+
+#[derive(Debug)]
+pub struct AlsSmoothing {
+    pub filter: AlsFilter,
+    pub hysteresis: HashMap<u64, u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Easing {
+    Linear,
+    EaseInOut,
+    Exponential,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+    Gamma { gamma: f64 },
+    Logarithmic { base: f64 },
+}
+
+// Durations in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub brighten: u64,
+    pub dim: u64,
+    pub easing: Easing,
 }
 
 #[derive(Debug, Clone)]
@@ -30,6 +125,10 @@ pub struct BacklightOutput {
     pub path: String,
     pub capturer: Capturer,
     pub min_brightness: u64,
+    pub exclude: Vec<Region>,
+    pub luma: Metric,
+    pub transition: Transition,
+    pub curve: Curve,
 }
 
 #[derive(Debug, Clone)]
@@ -37,16 +136,41 @@ pub struct DdcUtilOutput {
     pub name: String,
     pub capturer: Capturer,
     pub min_brightness: u64,
+    pub exclude: Vec<Region>,
+    pub luma: Metric,
+    pub transition: Transition,
+    pub curve: Curve,
+}
+
+#[derive(Debug, Clone)]
+pub struct GammaOutput {
+    pub name: String,
+    pub capturer: Capturer,
+    pub min_brightness: u64,
+    pub exclude: Vec<Region>,
+    pub luma: Metric,
+    pub transition: Transition,
+    pub curve: Curve,
+    // Output whose learned data is used, as there is no other way to adjust the gamma
+    pub follow: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub enum Output {
     Backlight(BacklightOutput),
     DdcUtil(DdcUtilOutput),
+    Gamma(GammaOutput),
+}
+
+#[derive(Debug)]
+pub struct Idle {
+    pub timeout: u64,
 }
 
 #[derive(Debug)]
 pub struct Config {
-    pub als: Als,
+    pub als: Vec<AlsSource>,
+    pub als_smoothing: AlsSmoothing,
+    pub idle: Idle,
     pub output: Vec<Output>,
 }
@@ -5,32 +5,269 @@ use std::collections::HashMap;
 #[serde(rename_all = "lowercase")]
 pub enum Capturer {
     Pipewire,
+    Screencopy,
+    #[serde(rename = "ext-image-copy")]
+    ExtImageCopy,
     Wlroots,
     None,
 }
 
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IioMode {
+    #[default]
+    Poll,
+    Buffered,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct SolarProfiles {
+    pub night: String,
+    pub twilight: String,
+    pub golden_hour: String,
+    pub day: String,
+}
+
+impl Default for SolarProfiles {
+    fn default() -> Self {
+        Self {
+            night: "night".to_string(),
+            twilight: "dark".to_string(),
+            golden_hour: "dim".to_string(),
+            day: "normal".to_string(),
+        }
+    }
+}
+
+// Responses of the red, green and blue channels to the CIE XYZ values, where Y is in lux
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct IioCalibration {
+    pub x: [f64; 3],
+    pub y: [f64; 3],
+    pub z: [f64; 3],
+}
+
+impl Default for IioCalibration {
+    // Coefficients of the TCS3414 from the ams DN25 design note
+    fn default() -> Self {
+        Self {
+            x: [-0.14282, 1.54924, -0.95641],
+            y: [-0.32466, 1.57837, -0.73191],
+            z: [-0.68202, 0.77073, 0.56332],
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum Als {
     Iio {
         path: String,
+        #[serde(default)]
         thresholds: HashMap<String, String>,
+        #[serde(default)]
+        mode: IioMode,
+        #[serde(default)]
+        continuous: bool,
+        #[serde(default)]
+        calibration: IioCalibration,
+        #[serde(default)]
+        color_thresholds: HashMap<String, String>,
     },
     Time {
         thresholds: HashMap<String, String>,
     },
+    Solar {
+        latitude: f64,
+        longitude: f64,
+        #[serde(default)]
+        profiles: SolarProfiles,
+    },
+    Dbus {
+        #[serde(default)]
+        thresholds: HashMap<String, String>,
+        #[serde(default)]
+        continuous: bool,
+    },
     Webcam {
         video: usize,
         thresholds: HashMap<String, String>,
+        #[serde(default)]
+        persistent: bool,
+        #[serde(default)]
+        compensate: bool,
+    },
+    Command {
+        command: String,
+        #[serde(default = "default_command_interval")]
+        interval: u64,
+        #[serde(default)]
+        thresholds: HashMap<String, String>,
+        #[serde(default)]
+        continuous: bool,
+    },
+    Fifo {
+        path: String,
+        #[serde(default)]
+        thresholds: HashMap<String, String>,
+        #[serde(default)]
+        continuous: bool,
+    },
+    Socket {
+        path: String,
+        #[serde(default)]
+        thresholds: HashMap<String, String>,
+        #[serde(default)]
+        continuous: bool,
     },
     None,
 }
 
+fn default_command_interval() -> u64 {
+    5
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AlsSource {
+    #[serde(flatten)]
+    pub als: Als,
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    #[serde(default)]
+    pub priority: u32,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+// Either a single `[als.iio]` or several `[[als]]` sources
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum AlsSources {
+    Single(Als),
+    Multiple(Vec<AlsSource>),
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AlsFilter {
+    #[default]
+    None,
+    Ema,
+    Median,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct AlsSmoothing {
+    pub filter: AlsFilter,
+    pub alpha: f64,
+    pub window: usize,
+    pub hysteresis: HashMap<String, u64>,
+}
+
+impl Default for AlsSmoothing {
+    fn default() -> Self {
+        Self {
+            filter: AlsFilter::None,
+            alpha: 0.3,
+            window: 5,
+            hysteresis: HashMap::new(),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Default)]
 #[serde(default)]
 pub struct OutputByType {
     pub backlight: Vec<BacklightOutput>,
     pub ddcutil: Vec<DdcUtilOutput>,
+    pub gamma: Vec<GammaOutput>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LumaMetric {
+    #[default]
+    Mean,
+    P10,
+    P50,
+    P90,
+    #[serde(rename = "bright-fraction")]
+    BrightFraction,
+    #[serde(rename = "center-weighted")]
+    CenterWeighted,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Easing {
+    #[default]
+    Linear,
+    EaseInOut,
+    Exponential,
+}
+
+// Durations in milliseconds, brightening and dimming take the common duration unless set
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Transition {
+    pub duration: u64,
+    pub brighten: Option<u64>,
+    pub dim: Option<u64>,
+    pub easing: Easing,
+}
+
+impl Default for Transition {
+    fn default() -> Self {
+        Self {
+            duration: 200,
+            brighten: None,
+            dim: None,
+            easing: Easing::Linear,
+        }
+    }
+}
+
+// Perceptual scale of brightness, which is learned in percent of it
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Curve {
+    Gamma {
+        #[serde(default = "default_gamma")]
+        gamma: f64,
+    },
+    Logarithmic {
+        #[serde(default = "default_log_base")]
+        base: f64,
+    },
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Curve::Gamma {
+            gamma: default_gamma(),
+        }
+    }
+}
+
+fn default_gamma() -> f64 {
+    2.2
+}
+
+fn default_log_base() -> f64 {
+    100.0
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
 }
 
 #[derive(Deserialize, Debug)]
@@ -38,12 +275,43 @@ pub struct BacklightOutput {
     pub name: String,
     pub path: String,
     pub capturer: Capturer,
+    #[serde(default)]
+    pub exclude: Vec<Region>,
+    #[serde(default)]
+    pub luma: LumaMetric,
+    #[serde(default)]
+    pub transition: Transition,
+    #[serde(default)]
+    pub curve: Curve,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct DdcUtilOutput {
     pub name: String,
     pub capturer: Capturer,
+    #[serde(default)]
+    pub exclude: Vec<Region>,
+    #[serde(default)]
+    pub luma: LumaMetric,
+    #[serde(default)]
+    pub transition: Transition,
+    #[serde(default)]
+    pub curve: Curve,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GammaOutput {
+    pub name: String,
+    pub capturer: Capturer,
+    #[serde(default)]
+    pub exclude: Vec<Region>,
+    #[serde(default)]
+    pub luma: LumaMetric,
+    #[serde(default)]
+    pub transition: Transition,
+    // Linear by default, as the display already decodes the scaled signal perceptually
+    pub curve: Option<Curve>,
+    pub follow: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -52,9 +320,20 @@ pub struct Keyboard {
     pub path: String,
 }
 
+// Off unless a timeout is configured, as it has to be tuned to the idle manager in use
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Idle {
+    pub timeout: u64,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Config {
-    pub als: Als,
+    pub als: AlsSources,
+    #[serde(default)]
+    pub als_smoothing: AlsSmoothing,
+    #[serde(default)]
+    pub idle: Idle,
     #[serde(default)]
     pub output: OutputByType,
     #[serde(default)]
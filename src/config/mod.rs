@@ -1,3 +1,4 @@
+use crate::frame::{Metric, Region};
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error::Error;
@@ -23,6 +24,134 @@ fn parse() -> Result<app::Config, toml::de::Error> {
             .collect()
     };
 
+    let parse_regions = |r: Vec<file::Region>| -> Vec<Region> {
+        r.into_iter()
+            .map(|r| Region::new(r.x, r.y, r.w, r.h))
+            .collect()
+    };
+
+    let parse_luma_metric = |m: file::LumaMetric| -> Metric {
+        match m {
+            file::LumaMetric::Mean => Metric::Mean,
+            file::LumaMetric::P10 => Metric::P10,
+            file::LumaMetric::P50 => Metric::P50,
+            file::LumaMetric::P90 => Metric::P90,
+            file::LumaMetric::BrightFraction => Metric::BrightFraction,
+            file::LumaMetric::CenterWeighted => Metric::CenterWeighted,
+        }
+    };
+
+    let parse_transition = |t: file::Transition| -> app::Transition {
+        app::Transition {
+            brighten: t.brighten.unwrap_or(t.duration),
+            dim: t.dim.unwrap_or(t.duration),
+            easing: match t.easing {
+                file::Easing::Linear => app::Easing::Linear,
+                file::Easing::EaseInOut => app::Easing::EaseInOut,
+                file::Easing::Exponential => app::Easing::Exponential,
+            },
+        }
+    };
+
+    let parse_curve = |c: file::Curve| -> app::Curve {
+        match c {
+            file::Curve::Gamma { gamma } => app::Curve::Gamma { gamma },
+            file::Curve::Logarithmic { base } => app::Curve::Logarithmic { base },
+        }
+    };
+
+    let parse_als = |als: file::Als| -> app::Als {
+        match als {
+            file::Als::Iio {
+                path,
+                thresholds,
+                mode,
+                continuous,
+                calibration,
+                color_thresholds,
+            } => app::Als::Iio {
+                path,
+                thresholds: parse_als_thresholds(thresholds),
+                mode: match mode {
+                    file::IioMode::Poll => app::IioMode::Poll,
+                    file::IioMode::Buffered => app::IioMode::Buffered,
+                },
+                continuous,
+                calibration: app::IioCalibration {
+                    x: calibration.x,
+                    y: calibration.y,
+                    z: calibration.z,
+                },
+                color_thresholds: parse_als_thresholds(color_thresholds),
+            },
+            file::Als::Dbus {
+                thresholds,
+                continuous,
+            } => app::Als::Dbus {
+                thresholds: parse_als_thresholds(thresholds),
+                continuous,
+            },
+            file::Als::Webcam {
+                video,
+                thresholds,
+                persistent,
+                compensate,
+            } => app::Als::Webcam {
+                video,
+                thresholds: parse_als_thresholds(thresholds),
+                persistent,
+                compensate,
+            },
+            file::Als::Command {
+                command,
+                interval,
+                thresholds,
+                continuous,
+            } => app::Als::Command {
+                command,
+                interval,
+                thresholds: parse_als_thresholds(thresholds),
+                continuous,
+            },
+            file::Als::Fifo {
+                path,
+                thresholds,
+                continuous,
+            } => app::Als::Fifo {
+                path,
+                thresholds: parse_als_thresholds(thresholds),
+                continuous,
+            },
+            file::Als::Socket {
+                path,
+                thresholds,
+                continuous,
+            } => app::Als::Socket {
+                path,
+                thresholds: parse_als_thresholds(thresholds),
+                continuous,
+            },
+            file::Als::Time { thresholds } => app::Als::Time {
+                thresholds: parse_als_thresholds(thresholds),
+            },
+            file::Als::Solar {
+                latitude,
+                longitude,
+                profiles,
+            } => app::Als::Solar {
+                latitude,
+                longitude,
+                profiles: app::SolarProfiles {
+                    night: profiles.night,
+                    twilight: profiles.twilight,
+                    golden_hour: profiles.golden_hour,
+                    day: profiles.day,
+                },
+            },
+            file::Als::None => app::Als::None,
+        }
+    };
+
     toml::from_str(&file_config).map(|file_config: file::Config| app::Config {
         output: file_config
             .output
@@ -37,7 +166,13 @@ fn parse() -> Result<app::Config, toml::de::Error> {
                         file::Capturer::None => app::Capturer::None,
                         file::Capturer::Wlroots => app::Capturer::Wlroots,
                         file::Capturer::Pipewire => app::Capturer::Pipewire,
+                        file::Capturer::Screencopy => app::Capturer::Screencopy,
+                        file::Capturer::ExtImageCopy => app::Capturer::ExtImageCopy,
                     },
+                    exclude: parse_regions(o.exclude),
+                    luma: parse_luma_metric(o.luma),
+                    transition: parse_transition(o.transition),
+                    curve: parse_curve(o.curve),
                 })
             })
             .chain(file_config.output.ddcutil.into_iter().map(|o| {
@@ -48,7 +183,34 @@ fn parse() -> Result<app::Config, toml::de::Error> {
                         file::Capturer::None => app::Capturer::None,
                         file::Capturer::Wlroots => app::Capturer::Wlroots,
                         file::Capturer::Pipewire => app::Capturer::Pipewire,
+                        file::Capturer::Screencopy => app::Capturer::Screencopy,
+                        file::Capturer::ExtImageCopy => app::Capturer::ExtImageCopy,
                     },
+                    exclude: parse_regions(o.exclude),
+                    luma: parse_luma_metric(o.luma),
+                    transition: parse_transition(o.transition),
+                    curve: parse_curve(o.curve),
+                })
+            }))
+            .chain(file_config.output.gamma.into_iter().map(|o| {
+                app::Output::Gamma(app::GammaOutput {
+                    name: o.name,
+                    min_brightness: 1,
+                    capturer: match o.capturer {
+                        file::Capturer::None => app::Capturer::None,
+                        file::Capturer::Wlroots => app::Capturer::Wlroots,
+                        file::Capturer::Pipewire => app::Capturer::Pipewire,
+                        file::Capturer::Screencopy => app::Capturer::Screencopy,
+                        file::Capturer::ExtImageCopy => app::Capturer::ExtImageCopy,
+                    },
+                    exclude: parse_regions(o.exclude),
+                    luma: parse_luma_metric(o.luma),
+                    transition: parse_transition(o.transition),
+                    curve: o
+                        .curve
+                        .map(parse_curve)
+                        .unwrap_or(app::Curve::Gamma { gamma: 1.0 }),
+                    follow: o.follow,
                 })
             }))
             .chain(file_config.keyboard.into_iter().map(|k| {
@@ -57,23 +219,50 @@ fn parse() -> Result<app::Config, toml::de::Error> {
                     path: k.path,
                     min_brightness: 0,
                     capturer: Capturer::None,
+                    exclude: vec![],
+                    luma: Metric::Mean,
+                    transition: parse_transition(file::Transition::default()),
+                    curve: parse_curve(file::Curve::default()),
                 })
             }))
             .collect(),
 
         als: match file_config.als {
-            file::Als::Iio { path, thresholds } => app::Als::Iio {
-                path,
-                thresholds: parse_als_thresholds(thresholds),
-            },
-            file::Als::Webcam { video, thresholds } => app::Als::Webcam {
-                video,
-                thresholds: parse_als_thresholds(thresholds),
-            },
-            file::Als::Time { thresholds } => app::Als::Time {
-                thresholds: parse_als_thresholds(thresholds),
+            file::AlsSources::Single(als) => vec![app::AlsSource {
+                als: parse_als(als),
+                weight: 1.0,
+                priority: 0,
+            }],
+            file::AlsSources::Multiple(sources) => sources
+                .into_iter()
+                .map(|source| app::AlsSource {
+                    als: parse_als(source.als),
+                    weight: source.weight,
+                    priority: source.priority,
+                })
+                .collect(),
+        },
+
+        als_smoothing: app::AlsSmoothing {
+            filter: match file_config.als_smoothing.filter {
+                file::AlsFilter::None => app::AlsFilter::None,
+                file::AlsFilter::Ema => app::AlsFilter::Ema {
+                    alpha: file_config.als_smoothing.alpha,
+                },
+                file::AlsFilter::Median => app::AlsFilter::Median {
+                    window: file_config.als_smoothing.window,
+                },
             },
-            file::Als::None => app::Als::None,
+            hysteresis: file_config
+                .als_smoothing
+                .hysteresis
+                .into_iter()
+                .map(|(k, v)| (k.parse().unwrap(), v))
+                .collect(),
+        },
+
+        idle: app::Idle {
+            timeout: file_config.idle.timeout,
         },
     })
 }
@@ -85,12 +274,100 @@ fn validate(config: app::Config) -> Result<app::Config, Box<dyn Error>> {
         .map(|output| match output {
             app::Output::Backlight(app::BacklightOutput { name, .. }) => name,
             app::Output::DdcUtil(DdcUtilOutput { name, .. }) => name,
+            app::Output::Gamma(app::GammaOutput { name, .. }) => name,
         })
         .collect::<HashSet<_>>();
 
+    // Gamma outputs cannot tell their brightness, so they have nothing to learn from themselves
+    let valid_follow = config.output.iter().all(|output| match output {
+        app::Output::Gamma(app::GammaOutput { name, follow, .. }) => follow
+            .as_ref()
+            .is_some_and(|follow| follow != name && names.contains(follow)),
+        _ => true,
+    });
+
+    let missing_thresholds = config.als.iter().any(|source| match &source.als {
+        app::Als::Iio {
+            thresholds,
+            continuous,
+            ..
+        }
+        | app::Als::Dbus {
+            thresholds,
+            continuous,
+        }
+        | app::Als::Command {
+            thresholds,
+            continuous,
+            ..
+        }
+        | app::Als::Fifo {
+            thresholds,
+            continuous,
+            ..
+        }
+        | app::Als::Socket {
+            thresholds,
+            continuous,
+            ..
+        } => thresholds.is_empty() && !continuous,
+        app::Als::Time { thresholds } | app::Als::Webcam { thresholds, .. } => {
+            thresholds.is_empty()
+        }
+        _ => false,
+    });
+    // Readings of sources with the same priority are fused, which needs them on the same scale
+    let mixed_scales = config.als.iter().any(|a| {
+        config
+            .als
+            .iter()
+            .any(|b| a.priority == b.priority && is_continuous(&a.als) != is_continuous(&b.als))
+    });
+    let valid_weights = config.als.iter().all(|source| source.weight > 0.0);
+    let valid_intervals = config.als.iter().all(|source| match source.als {
+        app::Als::Command { interval, .. } => interval > 0,
+        _ => true,
+    });
+
+    let valid_curves = config.output.iter().all(|output| match output {
+        app::Output::Backlight(app::BacklightOutput { curve, .. })
+        | app::Output::DdcUtil(DdcUtilOutput { curve, .. })
+        | app::Output::Gamma(app::GammaOutput { curve, .. }) => match *curve {
+            app::Curve::Gamma { gamma } => gamma > 0.0,
+            app::Curve::Logarithmic { base } => base > 1.0,
+        },
+    });
+
+    let valid_filter = match config.als_smoothing.filter {
+        app::AlsFilter::Ema { alpha } => alpha > 0.0 && alpha <= 1.0,
+        app::AlsFilter::Median { window } => window > 0,
+        app::AlsFilter::None => true,
+    };
+
     match (names.len(), names.len() == config.output.len()) {
         (0, _) => Err("No output or keyboard configured".into()),
+        _ if config.als.is_empty() => Err("No ALS configured".into()),
         (_, false) => Err("Names of all outputs and keyboards are not unique".into()),
+        _ if missing_thresholds => Err("ALS thresholds are required unless continuous".into()),
+        _ if mixed_scales => {
+            Err("ALS sources of the same priority must all be continuous or not".into())
+        }
+        _ if !valid_weights => Err("Weights of ALS sources must be positive".into()),
+        _ if !valid_intervals => Err("Interval of ALS commands must be positive".into()),
+        _ if !valid_follow => Err("Gamma outputs must follow another configured output".into()),
+        _ if !valid_curves => Err("Brightness curves need gamma > 0 and base > 1".into()),
+        _ if !valid_filter => Err("ALS smoothing needs 0 < alpha <= 1 and window > 0".into()),
         _ => Ok(config),
     }
 }
+
+fn is_continuous(als: &app::Als) -> bool {
+    match als {
+        app::Als::Iio { continuous, .. }
+        | app::Als::Dbus { continuous, .. }
+        | app::Als::Command { continuous, .. }
+        | app::Als::Fifo { continuous, .. }
+        | app::Als::Socket { continuous, .. } => *continuous,
+        _ => false,
+    }
+}
@@ -0,0 +1,92 @@
+use crate::frame::object::{Object, DRM_FORMAT_MOD_LINEAR};
+use crate::frame::vulkan::{ExportedImage, Vulkan};
+use crate::frame::Settings;
+use std::error::Error;
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::rc::Rc;
+use wayland_client::{protocol::wl_buffer::WlBuffer, Main};
+use wayland_protocols::unstable::linux_dmabuf::v1::client::{
+    zwp_linux_buffer_params_v1::Flags, zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferSpec {
+    // DRM fourcc
+    pub format: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// A wl_buffer backed by a linear DMA-BUF that Vulkan allocated, so frames stay on the GPU
+pub struct DmabufBuffer {
+    pub spec: BufferSpec,
+    vulkan: Rc<Vulkan>,
+    image: ExportedImage,
+    buffer: Main<WlBuffer>,
+}
+
+impl DmabufBuffer {
+    pub fn new(
+        linux_dmabuf: &Main<ZwpLinuxDmabufV1>,
+        vulkan: Rc<Vulkan>,
+        spec: BufferSpec,
+    ) -> Result<Self, Box<dyn Error>> {
+        let image = vulkan.export_image(spec.width, spec.height, spec.format)?;
+
+        let params = linux_dmabuf.create_params();
+        params.add(
+            image.fd.as_raw_fd(),
+            0,
+            image.offset,
+            image.stride,
+            (DRM_FORMAT_MOD_LINEAR >> 32) as u32,
+            DRM_FORMAT_MOD_LINEAR as u32,
+        );
+        let buffer = params.create_immed(
+            spec.width as i32,
+            spec.height as i32,
+            spec.format,
+            Flags::empty(),
+        );
+        params.destroy();
+
+        Ok(Self {
+            spec,
+            vulkan,
+            image,
+            buffer,
+        })
+    }
+
+    pub fn wl_buffer(&self) -> WlBuffer {
+        self.buffer.detach()
+    }
+
+    pub fn luma_percent(&mut self, settings: &Settings) -> Result<u8, Box<dyn Error>> {
+        // Vulkan takes ownership of imported fds, while the exported one is kept for the next frame
+        let mut frame = Object::default();
+        frame.set_metadata(
+            self.spec.width,
+            self.spec.height,
+            self.spec.format,
+            DRM_FORMAT_MOD_LINEAR,
+            1,
+        );
+        frame.set_object(
+            0,
+            self.image.fd.try_clone()?.into_raw_fd(),
+            self.image.size,
+            self.image.offset,
+            self.image.stride,
+            0,
+        );
+
+        self.vulkan.luma_percent(&frame, settings)
+    }
+}
+
+impl Drop for DmabufBuffer {
+    fn drop(&mut self) {
+        self.buffer.destroy();
+    }
+}
@@ -0,0 +1,530 @@
+use super::dmabuf::{self, DmabufBuffer};
+use super::pacer::Pacer;
+use super::shm::{self, pixel_layout, ShmBuffer};
+use crate::frame::object::DRM_FORMAT_MOD_LINEAR;
+use crate::frame::vulkan::{vk_format, Vulkan};
+use crate::frame::Settings;
+use crate::predictor::Controller;
+use crate::protocols::ext_image_capture_source::client::{
+    ext_image_capture_source_v1::ExtImageCaptureSourceV1,
+    ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1,
+};
+use crate::protocols::ext_image_copy_capture::client::{
+    ext_image_copy_capture_frame_v1::{self, FailureReason},
+    ext_image_copy_capture_manager_v1::{ExtImageCopyCaptureManagerV1, Options},
+    ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
+};
+use std::error::Error;
+use std::{cell::Cell, cell::RefCell, rc::Rc, thread, time::Duration};
+use wayland_client::{
+    protocol::{
+        wl_buffer::WlBuffer,
+        wl_output::WlOutput,
+        wl_registry::WlRegistry,
+        wl_shm::{Format, WlShm},
+    },
+    Display, EventQueue, GlobalManager, Main,
+};
+
+use wayland_protocols::unstable::linux_dmabuf::v1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1;
+use wayland_protocols::unstable::xdg_output::v1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1;
+use wayland_protocols::unstable::xdg_output::v1::client::zxdg_output_v1::Event::Description;
+
+const DELAY_FAILURE: Duration = Duration::from_millis(1000);
+
+// Buffer constraints as advertised by the session, complete once `done` is received
+#[derive(Debug, Default, Clone)]
+struct Constraints {
+    width: u32,
+    height: u32,
+    shm_formats: Vec<Format>,
+    // DRM fourccs with their modifiers
+    dmabuf_formats: Vec<(u32, Vec<u64>)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BufferSpec {
+    Shm(shm::BufferSpec),
+    Dmabuf(dmabuf::BufferSpec),
+}
+
+impl Constraints {
+    // SHM works with any GPU, DMA-BUF buffers are used when the compositor offers nothing else
+    fn buffer_spec(&self, dmabuf_supported: bool) -> Result<BufferSpec, Box<dyn Error>> {
+        let shm_format = self
+            .shm_formats
+            .iter()
+            .find(|format| pixel_layout(**format).is_some());
+        if let Some(format) = shm_format {
+            return Ok(BufferSpec::Shm(shm::BufferSpec {
+                format: *format,
+                width: self.width,
+                height: self.height,
+                stride: self.width * 4,
+            }));
+        }
+
+        // Vulkan allocates linear images only, so the compositor has to accept that modifier
+        let dmabuf_format = self.dmabuf_formats.iter().find(|(format, modifiers)| {
+            vk_format(*format).is_ok() && modifiers.contains(&DRM_FORMAT_MOD_LINEAR)
+        });
+        match dmabuf_format {
+            Some((format, _)) if dmabuf_supported => Ok(BufferSpec::Dmabuf(dmabuf::BufferSpec {
+                format: *format,
+                width: self.width,
+                height: self.height,
+            })),
+            Some(_) => Err("Compositor offers only DMA-BUF buffers, but no linux-dmabuf".into()),
+            None => Err("Compositor offers no supported buffer format".into()),
+        }
+    }
+}
+
+enum Buffer {
+    Shm(ShmBuffer),
+    Dmabuf(Box<DmabufBuffer>),
+}
+
+impl Buffer {
+    fn new(
+        shm: &Main<WlShm>,
+        linux_dmabuf: Option<&Main<ZwpLinuxDmabufV1>>,
+        vulkan: impl FnOnce() -> Result<Rc<Vulkan>, Box<dyn Error>>,
+        spec: BufferSpec,
+    ) -> Result<Self, Box<dyn Error>> {
+        match (spec, linux_dmabuf) {
+            (BufferSpec::Shm(spec), _) => Ok(Buffer::Shm(ShmBuffer::new(shm, spec)?)),
+            (BufferSpec::Dmabuf(spec), Some(linux_dmabuf)) => Ok(Buffer::Dmabuf(Box::new(
+                DmabufBuffer::new(linux_dmabuf, vulkan()?, spec)?,
+            ))),
+            (BufferSpec::Dmabuf(_), None) => Err("linux-dmabuf is not supported".into()),
+        }
+    }
+
+    fn spec(&self) -> BufferSpec {
+        match self {
+            Buffer::Shm(buffer) => BufferSpec::Shm(buffer.spec),
+            Buffer::Dmabuf(buffer) => BufferSpec::Dmabuf(buffer.spec),
+        }
+    }
+
+    fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Buffer::Shm(buffer) => (buffer.spec.width, buffer.spec.height),
+            Buffer::Dmabuf(buffer) => (buffer.spec.width, buffer.spec.height),
+        }
+    }
+
+    fn wl_buffer(&self) -> WlBuffer {
+        match self {
+            Buffer::Shm(buffer) => buffer.wl_buffer(),
+            Buffer::Dmabuf(buffer) => buffer.wl_buffer(),
+        }
+    }
+
+    fn luma_percent(&mut self, settings: &Settings) -> Result<u8, Box<dyn Error>> {
+        match self {
+            Buffer::Shm(buffer) => buffer.luma_percent(false, settings),
+            Buffer::Dmabuf(buffer) => buffer.luma_percent(settings),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Capturer {
+    event_queue: Rc<RefCell<EventQueue>>,
+    globals: GlobalManager,
+    source_manager: Main<ExtOutputImageCaptureSourceManagerV1>,
+    copy_manager: Main<ExtImageCopyCaptureManagerV1>,
+    shm: Main<WlShm>,
+    linux_dmabuf: Option<Main<ZwpLinuxDmabufV1>>,
+    // Shared by every DMA-BUF buffer, created along with the first one
+    vulkan: Rc<RefCell<Option<Rc<Vulkan>>>>,
+    registry: Main<WlRegistry>,
+    xdg_output_manager: Main<ZxdgOutputManagerV1>,
+    constraints: Rc<RefCell<Option<Constraints>>>,
+    buffer: Rc<RefCell<Option<Buffer>>>,
+    luma: Rc<Cell<Option<u8>>>,
+    capturing: Rc<Cell<bool>>,
+    output_found: Rc<Cell<bool>>,
+    pacer: Rc<RefCell<Pacer>>,
+    running: Rc<Cell<bool>>,
+}
+
+impl super::Capturer for Capturer {
+    fn run(
+        &self,
+        output_name: &str,
+        settings: &Settings,
+        controller: Controller,
+    ) -> Result<(), Box<dyn Error>> {
+        let controller = Rc::new(RefCell::new(controller));
+        let settings = Rc::new(settings.clone());
+
+        self.globals
+            .list()
+            .iter()
+            .filter(|(_, interface, _)| interface == "wl_output")
+            .for_each(|(id, _, _)| {
+                let output = Rc::new(self.registry.bind::<WlOutput>(1, *id));
+                let capturer = Rc::new(self.clone());
+                let controller = controller.clone();
+                let settings = settings.clone();
+                let desired_output = output_name.to_string();
+                self.xdg_output_manager
+                    .get_xdg_output(&output)
+                    .quick_assign(move |_, event, _| match event {
+                        Description { description } if description.contains(&desired_output) => {
+                            log::debug!(
+                                "Using output '{}' for config '{}'",
+                                description,
+                                desired_output,
+                            );
+                            capturer.output_found.set(true);
+                            capturer.clone().start_session(
+                                controller.clone(),
+                                output.clone(),
+                                settings.clone(),
+                            );
+                        }
+                        _ => {}
+                    });
+            });
+
+        self.event_queue
+            .borrow_mut()
+            .sync_roundtrip(&mut (), |_, _, _| {})?;
+
+        if !self.output_found.get() {
+            log::info!("Output '{}' is not connected", output_name);
+            return Ok(());
+        }
+
+        while self.running.get() {
+            self.event_queue
+                .borrow_mut()
+                .dispatch(&mut (), |_, _, _| {})?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Capturer {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let display = Display::connect_to_env()?;
+        let mut event_queue = display.create_event_queue();
+        let attached_display = display.attach(event_queue.token());
+        let registry = attached_display.get_registry();
+        let globals = GlobalManager::new(&attached_display);
+
+        event_queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+
+        let source_manager = globals
+            .instantiate_exact::<ExtOutputImageCaptureSourceManagerV1>(1)
+            .map_err(|err| {
+                format!(
+                    "Unable to init output_image_capture_source_manager: {}",
+                    err
+                )
+            })?;
+
+        let copy_manager = globals
+            .instantiate_exact::<ExtImageCopyCaptureManagerV1>(1)
+            .map_err(|err| format!("Unable to init image_copy_capture_manager: {}", err))?;
+
+        let shm = globals
+            .instantiate_exact::<WlShm>(1)
+            .map_err(|err| format!("Unable to init wl_shm: {}", err))?;
+
+        // Only needed by compositors that do not offer SHM buffers
+        let linux_dmabuf = globals.instantiate_range::<ZwpLinuxDmabufV1>(3, 4).ok();
+
+        let xdg_output_manager = globals
+            .instantiate_exact::<ZxdgOutputManagerV1>(3)
+            .map_err(|err| format!("Unable to init xdg_output_manager: {}", err))?;
+
+        Ok(Self {
+            event_queue: Rc::new(RefCell::new(event_queue)),
+            globals,
+            source_manager,
+            copy_manager,
+            shm,
+            linux_dmabuf,
+            vulkan: Rc::new(RefCell::new(None)),
+            registry,
+            xdg_output_manager,
+            constraints: Rc::new(RefCell::new(None)),
+            buffer: Rc::new(RefCell::new(None)),
+            luma: Rc::new(Cell::new(None)),
+            capturing: Rc::new(Cell::new(false)),
+            output_found: Rc::new(Cell::new(false)),
+            pacer: Rc::new(RefCell::new(Pacer::default())),
+            running: Rc::new(Cell::new(true)),
+        })
+    }
+
+    fn start_session(
+        self: Rc<Self>,
+        controller: Rc<RefCell<Controller>>,
+        output: Rc<Main<WlOutput>>,
+        settings: Rc<Settings>,
+    ) {
+        let source: Main<ExtImageCaptureSourceV1> = self.source_manager.create_source(&output);
+        let session = self.copy_manager.create_session(&source, Options::empty());
+        source.destroy();
+
+        let mut pending = Constraints::default();
+        session.quick_assign(move |session, event, _| match event {
+            ext_image_copy_capture_session_v1::Event::BufferSize { width, height } => {
+                pending.width = width;
+                pending.height = height;
+            }
+
+            ext_image_copy_capture_session_v1::Event::ShmFormat { format } => {
+                pending.shm_formats.push(format);
+            }
+
+            ext_image_copy_capture_session_v1::Event::DmabufFormat { format, modifiers } => {
+                let modifiers = modifiers
+                    .chunks_exact(8)
+                    .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap()))
+                    .collect();
+                pending.dmabuf_formats.push((format, modifiers));
+            }
+
+            ext_image_copy_capture_session_v1::Event::Done => {
+                log::debug!("Received buffer constraints: {:?}", pending);
+                *self.constraints.borrow_mut() = Some(std::mem::take(&mut pending));
+
+                // Constraints are also re-sent when they change, the frame in flight will fail then
+                if !self.capturing.get() {
+                    self.clone().capture_frame(
+                        controller.clone(),
+                        session.clone(),
+                        settings.clone(),
+                    );
+                }
+            }
+
+            ext_image_copy_capture_session_v1::Event::Stopped => {
+                log::info!("Capture session was stopped, output might be disconnected");
+                session.destroy();
+                self.running.set(false);
+            }
+
+            _ => {}
+        });
+    }
+
+    fn capture_frame(
+        self: Rc<Self>,
+        controller: Rc<RefCell<Controller>>,
+        session: Main<ExtImageCopyCaptureSessionV1>,
+        settings: Rc<Settings>,
+    ) {
+        let (wl_buffer, fresh) = match self.prepare_buffer() {
+            Ok(buffer) => buffer,
+            Err(err) => {
+                log::error!("Unable to prepare capture buffer: {}", err);
+                session.destroy();
+                self.running.set(false);
+                return;
+            }
+        };
+
+        let frame = session.create_frame();
+        frame.attach_buffer(&wl_buffer);
+        if fresh {
+            let dimensions = self.buffer.borrow().as_ref().map(Buffer::dimensions);
+            if let Some((width, height)) = dimensions {
+                frame.damage_buffer(0, 0, width as i32, height as i32);
+            }
+        }
+
+        // Without damage the buffer holds exactly what was already measured
+        let mut damaged = fresh;
+        self.capturing.set(true);
+        frame.quick_assign(move |frame, event, _| match event {
+            ext_image_copy_capture_frame_v1::Event::Damage { .. } => damaged = true,
+
+            ext_image_copy_capture_frame_v1::Event::Ready => {
+                frame.destroy();
+                self.capturing.set(false);
+
+                let luma = match self.luma_percent(damaged, &settings) {
+                    Ok(luma) => luma,
+                    Err(err) => {
+                        log::error!("Unable to compute luma percent: {}", err);
+                        thread::sleep(DELAY_FAILURE);
+                        self.clone().capture_frame(
+                            controller.clone(),
+                            session.clone(),
+                            settings.clone(),
+                        );
+                        return;
+                    }
+                };
+
+                if let Err(err) = controller.borrow_mut().adjust(luma) {
+                    log::debug!("Stopping capturer: {}", err);
+                    session.destroy();
+                    self.running.set(false);
+                    return;
+                }
+
+                let settled = controller.borrow().is_settled();
+                thread::sleep(self.pacer.borrow_mut().update(luma, settled));
+                self.clone()
+                    .capture_frame(controller.clone(), session.clone(), settings.clone());
+            }
+
+            ext_image_copy_capture_frame_v1::Event::Failed { reason } => {
+                frame.destroy();
+                self.capturing.set(false);
+
+                match reason {
+                    FailureReason::Stopped => {
+                        log::info!("Frame capture was stopped, output might be disconnected");
+                        session.destroy();
+                        self.running.set(false);
+                    }
+                    FailureReason::BufferConstraints => {
+                        log::debug!("Buffer constraints changed, will try again.");
+                        self.clone().capture_frame(
+                            controller.clone(),
+                            session.clone(),
+                            settings.clone(),
+                        );
+                    }
+                    _ => {
+                        log::error!("Frame capture failed, will try again.");
+                        thread::sleep(DELAY_FAILURE);
+                        self.clone().capture_frame(
+                            controller.clone(),
+                            session.clone(),
+                            settings.clone(),
+                        );
+                    }
+                }
+            }
+
+            _ => {}
+        });
+
+        frame.capture();
+    }
+
+    // Buffer is reused for as long as constraints allow, a fresh one needs to be copied in full
+    fn prepare_buffer(&self) -> Result<(WlBuffer, bool), Box<dyn Error>> {
+        let spec = self
+            .constraints
+            .borrow()
+            .as_ref()
+            .ok_or("No buffer constraints received")?
+            .buffer_spec(self.linux_dmabuf.is_some())?;
+
+        let mut buffer = self.buffer.borrow_mut();
+        match buffer.as_ref() {
+            Some(buffer) if buffer.spec() == spec => Ok((buffer.wl_buffer(), false)),
+            _ => {
+                self.luma.set(None);
+                // Previous buffer goes first, there is only one exported image at a time
+                buffer.take();
+                let new_buffer = Buffer::new(
+                    &self.shm,
+                    self.linux_dmabuf.as_ref(),
+                    || self.vulkan(),
+                    spec,
+                )?;
+                Ok((buffer.insert(new_buffer).wl_buffer(), true))
+            }
+        }
+    }
+
+    fn vulkan(&self) -> Result<Rc<Vulkan>, Box<dyn Error>> {
+        let mut vulkan = self.vulkan.borrow_mut();
+        match vulkan.as_ref() {
+            Some(vulkan) => Ok(vulkan.clone()),
+            None => Ok(vulkan.insert(Rc::new(Vulkan::new()?)).clone()),
+        }
+    }
+
+    fn luma_percent(&self, damaged: bool, settings: &Settings) -> Result<u8, Box<dyn Error>> {
+        match self.luma.get() {
+            Some(luma) if !damaged => Ok(luma),
+            _ => {
+                let luma = self
+                    .buffer
+                    .borrow_mut()
+                    .as_mut()
+                    .ok_or("No capture buffer")?
+                    .luma_percent(settings)?;
+                self.luma.set(Some(luma));
+                Ok(luma)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XR24: u32 = 0x3432_5258;
+
+    #[test]
+    fn test_buffer_spec_picks_first_supported_shm_format() {
+        let constraints = Constraints {
+            width: 1920,
+            height: 1080,
+            shm_formats: vec![Format::Rgb565, Format::Xbgr8888, Format::Xrgb8888],
+            dmabuf_formats: vec![(XR24, vec![DRM_FORMAT_MOD_LINEAR])],
+        };
+
+        assert_eq!(
+            BufferSpec::Shm(shm::BufferSpec {
+                format: Format::Xbgr8888,
+                width: 1920,
+                height: 1080,
+                stride: 7680,
+            }),
+            constraints.buffer_spec(true).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_buffer_spec_falls_back_to_linear_dmabuf() {
+        let constraints = Constraints {
+            width: 1920,
+            height: 1080,
+            shm_formats: vec![],
+            dmabuf_formats: vec![
+                (XR24, vec![0x0100_0000_0000_0001]),
+                (XR24, vec![0x0100_0000_0000_0001, DRM_FORMAT_MOD_LINEAR]),
+            ],
+        };
+
+        assert_eq!(
+            BufferSpec::Dmabuf(dmabuf::BufferSpec {
+                format: XR24,
+                width: 1920,
+                height: 1080,
+            }),
+            constraints.buffer_spec(true).unwrap()
+        );
+        assert!(constraints.buffer_spec(false).is_err());
+    }
+
+    #[test]
+    fn test_buffer_spec_requires_supported_format() {
+        let constraints = Constraints {
+            width: 1920,
+            height: 1080,
+            shm_formats: vec![Format::Rgb565],
+            dmabuf_formats: vec![(XR24, vec![0x0100_0000_0000_0001])],
+        };
+
+        assert!(constraints.buffer_spec(true).is_err());
+    }
+}
@@ -1,9 +1,22 @@
+use crate::frame::Settings;
 use crate::predictor::Controller;
+use std::error::Error;
 
+mod dmabuf;
+pub mod ext_image_copy;
 pub mod none;
+mod pacer;
 pub mod pipewire;
+mod portal;
+pub mod screencopy;
+pub(crate) mod shm;
 pub mod wlroots;
 
 pub trait Capturer {
-    fn run(&self, output_name: &str, controller: Controller);
+    fn run(
+        &self,
+        output_name: &str,
+        settings: &Settings,
+        controller: Controller,
+    ) -> Result<(), Box<dyn Error>>;
 }
@@ -1,13 +1,23 @@
+use crate::frame::Settings;
 use crate::predictor::Controller;
+use std::error::Error;
 use std::{thread, time::Duration};
 
 #[derive(Default)]
 pub struct Capturer {}
 
 impl super::Capturer for Capturer {
-    fn run(&self, _output_name: &str, mut controller: Controller) {
+    fn run(
+        &self,
+        _output_name: &str,
+        _settings: &Settings,
+        mut controller: Controller,
+    ) -> Result<(), Box<dyn Error>> {
         loop {
-            controller.adjust(0);
+            if let Err(err) = controller.adjust(0) {
+                log::debug!("Stopping capturer: {}", err);
+                return Ok(());
+            }
             thread::sleep(Duration::from_millis(200));
         }
     }
@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+// Interval between captures while the screen keeps changing, and the longest one for static content
+const INTERVAL_MIN: Duration = Duration::from_millis(100);
+const INTERVAL_MAX: Duration = Duration::from_millis(1600);
+
+// Captures less often while the screen stays the same and goes back to full speed on changes
+#[derive(Debug)]
+pub struct Pacer {
+    interval: Duration,
+    last_luma: Option<u8>,
+}
+
+impl Default for Pacer {
+    fn default() -> Self {
+        Self {
+            interval: INTERVAL_MIN,
+            last_luma: None,
+        }
+    }
+}
+
+impl Pacer {
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    // Backing off is only safe when the controller has nothing pending, as it counts its cooldowns
+    // in captures, and the same luma would not make a difference for it then
+    pub fn update(&mut self, luma: u8, settled: bool) -> Duration {
+        if settled && self.last_luma == Some(luma) {
+            self.interval = (self.interval * 2).min(INTERVAL_MAX);
+        } else {
+            self.interval = INTERVAL_MIN;
+        }
+
+        self.last_luma = Some(luma);
+        self.interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backs_off_on_static_content() {
+        let mut pacer = Pacer::default();
+
+        assert_eq!(INTERVAL_MIN, pacer.update(50, true));
+        assert_eq!(Duration::from_millis(200), pacer.update(50, true));
+        assert_eq!(Duration::from_millis(400), pacer.update(50, true));
+        for _ in 0..10 {
+            pacer.update(50, true);
+        }
+        assert_eq!(INTERVAL_MAX, pacer.interval());
+    }
+
+    #[test]
+    fn test_speeds_up_on_changes() {
+        let mut pacer = Pacer::default();
+        for _ in 0..10 {
+            pacer.update(50, true);
+        }
+
+        assert_eq!(INTERVAL_MIN, pacer.update(51, true));
+    }
+
+    #[test]
+    fn test_keeps_full_speed_while_controller_is_busy() {
+        let mut pacer = Pacer::default();
+        for _ in 0..10 {
+            pacer.update(50, true);
+        }
+
+        assert_eq!(INTERVAL_MIN, pacer.update(50, false));
+        assert_eq!(INTERVAL_MIN, pacer.update(50, false));
+    }
+}
@@ -1,186 +1,402 @@
-use crate::frame::{object::Object, vulkan::Vulkan};
+use super::pacer::Pacer;
+use super::portal::ScreenCast;
+use crate::frame::object::{Object, DRM_FORMAT_MOD_INVALID};
+use crate::frame::{compute_shm_lightness_percent, vulkan::Vulkan, PixelLayout, Settings};
 use crate::predictor::Controller;
-use pipewire::prelude::*;
-use pipewire::properties;
-use pipewire::registry::Registry;
-use std::{cell::RefCell, rc::Rc, thread, time::Duration};
-use wayland_client::{
-    protocol::{wl_output::WlOutput, wl_registry::WlRegistry},
-    Display, EventQueue, GlobalManager, Main,
+use pipewire::context::Context;
+use pipewire::main_loop::MainLoop;
+use pipewire::properties::properties;
+use pipewire::spa;
+use pipewire::spa::buffer::{Data, DataType};
+use pipewire::spa::param::format::{FormatProperties, MediaSubtype, MediaType};
+use pipewire::spa::param::video::{VideoFormat, VideoInfoRaw};
+use pipewire::spa::param::ParamType;
+use pipewire::spa::pod::serialize::PodSerializer;
+use pipewire::spa::pod::{self, Pod, Property, PropertyFlags};
+use pipewire::spa::utils::{
+    Choice, ChoiceEnum, ChoiceFlags, Direction, Fraction, Id, Rectangle, SpaTypes,
 };
+use pipewire::stream::{Stream, StreamFlags, StreamRef, StreamState};
+use std::error::Error;
+use std::io::Cursor;
+use std::os::fd::{BorrowedFd, IntoRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::Arc;
+use std::{rc::Rc, thread, time::Instant};
 
-use pipewire::stream::{Stream, StreamFlags};
-use pipewire::{Context, Core, MainLoop};
-use wayland_protocols::unstable::xdg_output::v1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1;
-use wayland_protocols::unstable::xdg_output::v1::client::zxdg_output_v1::Event::Description;
+// DRM fourcc codes, the counterparts of supported SPA video formats
+const DRM_FORMAT_XRGB8888: u32 = 0x3432_5258;
+const DRM_FORMAT_ARGB8888: u32 = 0x3432_5241;
+const DRM_FORMAT_XBGR8888: u32 = 0x3432_4258;
+const DRM_FORMAT_ABGR8888: u32 = 0x3432_4241;
 
-const DELAY_SUCCESS: Duration = Duration::from_millis(100);
-const DELAY_FAILURE: Duration = Duration::from_millis(1000);
+const VIDEO_FORMATS: [VideoFormat; 4] = [
+    VideoFormat::BGRx,
+    VideoFormat::BGRA,
+    VideoFormat::RGBx,
+    VideoFormat::RGBA,
+];
+
+#[derive(Default)]
+struct State {
+    format: Option<VideoInfoRaw>,
+    last_frame: Option<Instant>,
+    pacer: Pacer,
+}
 
-#[derive(Clone)]
 pub struct Capturer {
-    event_queue: Rc<RefCell<EventQueue>>,
-    globals: GlobalManager,
-    mainloop: MainLoop,
-    context: Rc<Context<MainLoop>>,
-    core: Core,
-    pw_registry: Rc<Registry>,
-    wl_registry: Main<WlRegistry>,
     vulkan: Rc<Vulkan>,
-    xdg_output_manager: Main<ZxdgOutputManagerV1>,
 }
+
 impl super::Capturer for Capturer {
-    fn run(&self, output_name: &str, controller: Controller) {
-        let controller = Rc::new(RefCell::new(controller));
-        let mut stream = Stream::<i32>::simple(
-            &self.mainloop,
+    fn run(
+        &self,
+        output_name: &str,
+        settings: &Settings,
+        controller: Controller,
+    ) -> Result<(), Box<dyn Error>> {
+        // The screencast session is closed when this is dropped, keep it until the loop stops
+        let screencast = ScreenCast::start(output_name)
+            .map_err(|err| format!("Unable to start screencast: {}", err))?;
+
+        self.stream(output_name, &screencast, settings.clone(), controller)
+            .map_err(|err| format!("Unable to capture screencast: {}", err).into())
+    }
+}
+
+impl Capturer {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let vulkan = Rc::new(Vulkan::new()?);
+
+        Ok(Self { vulkan })
+    }
+
+    fn stream(
+        &self,
+        output_name: &str,
+        screencast: &ScreenCast,
+        settings: Settings,
+        controller: Controller,
+    ) -> Result<(), Box<dyn Error>> {
+        let mainloop = MainLoop::new(None)?;
+        let context = Context::new(&mainloop)?;
+        let core = context.connect_fd(screencast.fd.try_clone()?, None)?;
+
+        let stream = Stream::new(
+            &core,
             "wluma",
             properties! {
                 *pipewire::keys::MEDIA_TYPE => "Video",
                 *pipewire::keys::MEDIA_CATEGORY => "Capture",
-            *pipewire::keys::MEDIA_ROLE => "Camera",
+                *pipewire::keys::MEDIA_ROLE => "Screen",
             },
-        )
-        .state_changed(|old, new| {
-            println!("State changed: {:?} -> {:?}", old, new);
-        })
-        .process(|_stream, _user_data| {
-            println!("On frame");
-        })
-        .create()
-        .expect("Error creating stream");
+        )?;
+
+        // Predictions can block, e.g. on brightness changes, which must not stall the stream,
+        // so they run on their own thread and frames are dropped while it is busy
+        let (luma_tx, luma_rx) = mpsc::sync_channel(1);
+        let (stop_tx, stop_rx) = pipewire::channel::channel();
+        let settled = Arc::new(AtomicBool::new(false));
+        let controller_settled = settled.clone();
+        let thread_name = format!("luma-{}", output_name);
+        let controller_thread = thread::Builder::new().name(thread_name).spawn(move || {
+            let mut controller = controller;
+            for luma in luma_rx {
+                if let Err(err) = controller.adjust(luma) {
+                    log::debug!("Stopping capturer: {}", err);
+                    let _ = stop_tx.send(());
+                    return;
+                }
+                controller_settled.store(controller.is_settled(), Ordering::Relaxed);
+            }
+        })?;
+
+        let vulkan = self.vulkan.clone();
+        let on_error = mainloop.downgrade();
+        let on_stop = mainloop.downgrade();
+        let _stop = stop_rx.attach(mainloop.loop_(), move |_| {
+            if let Some(mainloop) = on_stop.upgrade() {
+                mainloop.quit();
+            }
+        });
+
+        let listener = stream
+            .add_local_listener_with_user_data(State::default())
+            .state_changed(move |_, _, old, new| {
+                log::debug!("Screencast stream state changed: {:?} -> {:?}", old, new);
+                if matches!(new, StreamState::Error(_) | StreamState::Unconnected) {
+                    if let Some(mainloop) = on_error.upgrade() {
+                        mainloop.quit();
+                    }
+                }
+            })
+            .param_changed(|stream, state, id, param| {
+                let Some(param) = param else { return };
+                if id != ParamType::Format.as_raw() {
+                    return;
+                }
+
+                match negotiate(stream, param) {
+                    Ok(format) => state.format = Some(format),
+                    Err(err) => log::error!("Unable to negotiate screencast format: {}", err),
+                }
+            })
+            .process(move |stream, state| {
+                let Some(mut buffer) = stream.dequeue_buffer() else {
+                    return;
+                };
+                let Some(format) = state.format else { return };
+
+                if state
+                    .last_frame
+                    .is_some_and(|last| last.elapsed() < state.pacer.interval())
+                {
+                    return;
+                }
+                state.last_frame = Some(Instant::now());
+
+                let luma = match luma_percent(&vulkan, buffer.datas_mut(), format, &settings) {
+                    Ok(luma) => luma,
+                    Err(err) => {
+                        log::error!("Unable to compute luma percent: {}", err);
+                        return;
+                    }
+                };
+
+                match luma_tx.try_send(luma) {
+                    Ok(()) | Err(TrySendError::Full(_)) => {}
+                    // The controller is stopping, and so is the loop
+                    Err(TrySendError::Disconnected(_)) => return,
+                }
+                state.pacer.update(luma, settled.load(Ordering::Relaxed));
+            })
+            .register()?;
+
+        // Each DMA-BUF format comes with the modifiers Vulkan can import, an implicit modifier
+        // is the fallback, while SHM buffers work with any format
+        let mut values = VIDEO_FORMATS
+            .iter()
+            .map(|&format| {
+                let mut modifiers = drm_format(format)
+                    .map(|format| self.vulkan.import_modifiers(format))
+                    .transpose()?
+                    .unwrap_or_default();
+                modifiers.push(DRM_FORMAT_MOD_INVALID);
+                enum_format(&[format], Some(&modifiers))
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        values.push(enum_format(&VIDEO_FORMATS, None)?);
+        let mut params = values
+            .iter()
+            .map(|values| Pod::from_bytes(values).ok_or("Invalid format pod"))
+            .collect::<Result<Vec<_>, _>>()?;
+
         stream.connect(
-            pipewire::spa::Direction::Input,
-            None,
-            StreamFlags::INACTIVE,
-            &mut [],
-        );
-        println!("Created Stream");
-        // self.globals
-        //     .list()
-        //     .iter()
-        //     .filter(|(_, interface, _)| interface == "wl_output")
-        //     .for_each(|(id, _, _)| {
-        //         let output = Rc::new(self.wl_registry.bind::<WlOutput>(1, *id));
-        //         let capturer = Rc::new(self.clone());
-        //         let controller = controller.clone();
-        //         let desired_output = output_name.to_string();
-        //         self.xdg_output_manager
-        //             .get_xdg_output(&output)
-        //             .quick_assign(move |_, event, _| match event {
-        //                 Description { description } if description.contains(&desired_output) => {
-        //                     log::debug!(
-        //                         "Using output '{}' for config '{}'",
-        //                         description,
-        //                         desired_output,
-        //                     );
-        //                     capturer
-        //                         .clone()
-        //                         .capture_frame(controller.clone(), output.clone());
-        //                 }
-        //                 _ => {}
-        //             });
-        //     });
-
-        loop {
-            self.event_queue
-                .borrow_mut()
-                .dispatch(&mut (), |_, _, _| {})
-                .expect("Error running wlroots capturer main loop");
-        }
+            Direction::Input,
+            Some(screencast.node_id),
+            StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS,
+            &mut params,
+        )?;
+
+        mainloop.run();
+
+        // Dropping the listener closes the luma channel, which the controller thread waits on
+        drop(listener);
+        controller_thread
+            .join()
+            .map_err(|_| "Controller thread panicked".into())
+    }
+}
+
+// Formats with modifiers are DMA-BUF ones, which the compositor picks first
+fn enum_format(
+    formats: &[VideoFormat],
+    modifiers: Option<&[u64]>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut format = pod::object!(
+        SpaTypes::ObjectParamFormat,
+        ParamType::EnumFormat,
+        pod::property!(FormatProperties::MediaType, Id, MediaType::Video),
+        pod::property!(FormatProperties::MediaSubtype, Id, MediaSubtype::Raw),
+        pod::property!(
+            FormatProperties::VideoSize,
+            Choice,
+            Range,
+            Rectangle,
+            Rectangle {
+                width: 1920,
+                height: 1080
+            },
+            Rectangle {
+                width: 1,
+                height: 1
+            },
+            Rectangle {
+                width: 8192,
+                height: 8192
+            }
+        ),
+        pod::property!(
+            FormatProperties::VideoFramerate,
+            Choice,
+            Range,
+            Fraction,
+            Fraction { num: 10, denom: 1 },
+            Fraction { num: 0, denom: 1 },
+            Fraction {
+                num: 1000,
+                denom: 1
+            }
+        ),
+    );
+
+    format.properties.push(Property::new(
+        FormatProperties::VideoFormat.as_raw(),
+        pod::Value::Choice(pod::ChoiceValue::Id(Choice(
+            ChoiceFlags::empty(),
+            ChoiceEnum::Enum {
+                default: Id(formats[0].as_raw()),
+                alternatives: formats.iter().map(|format| Id(format.as_raw())).collect(),
+            },
+        ))),
+    ));
+
+    if let Some(modifiers) = modifiers {
+        format.properties.push(Property {
+            key: FormatProperties::VideoModifier.as_raw(),
+            flags: PropertyFlags::MANDATORY,
+            value: pod::Value::Choice(pod::ChoiceValue::Long(Choice(
+                ChoiceFlags::empty(),
+                ChoiceEnum::Enum {
+                    default: modifiers[0] as i64,
+                    alternatives: modifiers.iter().map(|&modifier| modifier as i64).collect(),
+                },
+            ))),
+        });
     }
+
+    serialize(pod::Value::Object(format))
 }
 
-impl Default for Capturer {
-    fn default() -> Self {
-        let display = Display::connect_to_env().unwrap();
-        let mut event_queue = display.create_event_queue();
-        let attached_display = display.attach(event_queue.token());
-        let wl_registry = attached_display.get_registry();
-        let globals = GlobalManager::new(&attached_display);
-        let mainloop = MainLoop::new().expect("Failed to init mainloop");
-        let context = Rc::new(Context::new(&mainloop).expect("Failed to init context."));
-        let core = context.connect(None).expect("Failed to connect");
-        let pw_registry = Rc::new(core.get_registry().expect("Failed to connect to registy"));
-
-        event_queue.sync_roundtrip(&mut (), |_, _, _| {}).unwrap();
-
-        let xdg_output_manager = globals
-            .instantiate_exact::<ZxdgOutputManagerV1>(3)
-            .expect("Unable to init xdg_output_manager");
-
-        let vulkan = Rc::new(Vulkan::new().expect("Unable to initialize Vulkan"));
-
-        Self {
-            event_queue: Rc::new(RefCell::new(event_queue)),
-            globals,
-            mainloop,
-            context,
-            core,
-            pw_registry,
-            wl_registry,
-            vulkan,
-            xdg_output_manager,
+fn negotiate(stream: &StreamRef, param: &Pod) -> Result<VideoInfoRaw, Box<dyn Error>> {
+    let (media_type, media_subtype) = spa::param::format_utils::parse_format(param)?;
+    if media_type != MediaType::Video || media_subtype != MediaSubtype::Raw {
+        return Err("Screencast is not a raw video".into());
+    }
+
+    let mut format = VideoInfoRaw::new();
+    format.parse(param)?;
+    log::debug!(
+        "Negotiated screencast format {:?} {}x{}",
+        format.format(),
+        format.size().width,
+        format.size().height
+    );
+
+    let data_types = (1 << DataType::MemPtr.as_raw())
+        | (1 << DataType::MemFd.as_raw())
+        | (1 << DataType::DmaBuf.as_raw());
+    let buffers = pod::Object {
+        type_: SpaTypes::ObjectParamBuffers.as_raw(),
+        id: ParamType::Buffers.as_raw(),
+        properties: vec![Property::new(
+            spa::sys::SPA_PARAM_BUFFERS_dataType,
+            pod::Value::Choice(pod::ChoiceValue::Int(Choice(
+                ChoiceFlags::empty(),
+                ChoiceEnum::Flags {
+                    default: data_types as i32,
+                    flags: vec![],
+                },
+            ))),
+        )],
+    };
+    let values = serialize(pod::Value::Object(buffers))?;
+    stream.update_params(&mut [Pod::from_bytes(&values).ok_or("Invalid buffers pod")?])?;
+
+    Ok(format)
+}
+
+fn serialize(value: pod::Value) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(PodSerializer::serialize(Cursor::new(Vec::new()), &value)?
+        .0
+        .into_inner())
+}
+
+fn luma_percent(
+    vulkan: &Vulkan,
+    datas: &mut [Data],
+    format: VideoInfoRaw,
+    settings: &Settings,
+) -> Result<u8, Box<dyn Error>> {
+    let size = format.size();
+    let first = datas.first_mut().ok_or("Empty screencast buffer")?;
+
+    match first.type_() {
+        DataType::DmaBuf => {
+            let mut frame = Object::default();
+            frame.set_metadata(
+                size.width,
+                size.height,
+                drm_format(format.format()).ok_or("Unsupported screencast format")?,
+                format.modifier(),
+                datas.len() as u32,
+            );
+
+            for (index, data) in datas.iter().enumerate() {
+                // Buffer stays owned by PipeWire, Vulkan gets its own copy of the fd
+                let fd = unsafe { BorrowedFd::borrow_raw(data.as_raw().fd as i32) }
+                    .try_clone_to_owned()?
+                    .into_raw_fd();
+                frame.set_object(
+                    index as u32,
+                    fd,
+                    data.as_raw().maxsize,
+                    data.chunk().offset(),
+                    data.chunk().stride() as u32,
+                    index as u32,
+                );
+            }
+
+            vulkan.luma_percent(&frame, settings)
         }
+
+        DataType::MemPtr | DataType::MemFd => {
+            let offset = first.chunk().offset() as usize;
+            let stride = first.chunk().stride() as usize;
+            let bytes = first.data().ok_or("Screencast buffer is not mapped")?;
+
+            compute_shm_lightness_percent(
+                bytes
+                    .get(offset..)
+                    .ok_or("Invalid screencast buffer offset")?,
+                size.width as usize,
+                size.height as usize,
+                stride,
+                pixel_layout(format.format()).ok_or("Unsupported screencast format")?,
+                false,
+                settings,
+            )
+        }
+
+        _ => Err("Unsupported screencast buffer type".into()),
     }
 }
 
-impl Capturer {
-    fn capture_frame(
-        self: Rc<Self>,
-        controller: Rc<RefCell<Controller>>,
-        output: Rc<Main<WlOutput>>,
-    ) {
-        // let mut frame = Object::default();
-        // self.dmabuf_manager
-        //     .capture_output(0, &output)
-        //     .quick_assign(move |data, event, _| match event {
-        //         Event::Frame {
-        //             width,
-        //             height,
-        //             num_objects,
-        //             ..
-        //         } => {
-        //             frame.set_metadata(width, height, num_objects);
-        //         }
-        //
-        //         Event::Object {
-        //             index, fd, size, ..
-        //         } => {
-        //             frame.set_object(index, fd, size);
-        //         }
-        //
-        //         Event::Ready { .. } => {
-        //             let luma = self
-        //                 .vulkan
-        //                 .luma_percent(&frame)
-        //                 .expect("Unable to compute luma percent");
-        //
-        //             controller.borrow_mut().adjust(luma);
-        //
-        //             data.destroy();
-        //
-        //             thread::sleep(DELAY_SUCCESS);
-        //             self.clone().capture_frame(controller.clone(), output.clone());
-        //         }
-        //
-        //         Event::Cancel { reason } => {
-        //             data.destroy();
-        //
-        //             if reason == CancelReason::Permanent {
-        //                 panic!("Frame was cancelled due to a permanent error. If you just disconnected screen, this is not implemented yet.");
-        //             } else {
-        //                 log::error!("Frame was cancelled due to a temporary error, will try again.");
-        //                 thread::sleep(DELAY_FAILURE);
-        //                 self.clone().capture_frame(controller.clone(), output.clone());
-        //             }
-        //         }
-        //
-        //         _ => unreachable!(),
-        //     });
+fn drm_format(format: VideoFormat) -> Option<u32> {
+    match format {
+        VideoFormat::BGRx => Some(DRM_FORMAT_XRGB8888),
+        VideoFormat::BGRA => Some(DRM_FORMAT_ARGB8888),
+        VideoFormat::RGBx => Some(DRM_FORMAT_XBGR8888),
+        VideoFormat::RGBA => Some(DRM_FORMAT_ABGR8888),
+        _ => None,
     }
+}
 
-    pub fn context(&self) -> &Context<MainLoop> {
-        &self.context
+fn pixel_layout(format: VideoFormat) -> Option<PixelLayout> {
+    match format {
+        VideoFormat::BGRx | VideoFormat::BGRA => Some(PixelLayout::Bgrx),
+        VideoFormat::RGBx | VideoFormat::RGBA => Some(PixelLayout::Rgbx),
+        _ => None,
     }
 }
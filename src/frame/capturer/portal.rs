@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::{self, ObjectPath, OwnedObjectPath, OwnedValue, Value};
+
+const PORTAL_DESTINATION: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const SCREENCAST_INTERFACE: &str = "org.freedesktop.portal.ScreenCast";
+const REQUEST_INTERFACE: &str = "org.freedesktop.portal.Request";
+
+const SOURCE_TYPE_MONITOR: u32 = 1;
+const PERSIST_MODE_UNTIL_REVOKED: u32 = 2;
+
+static REQUEST_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+type Results = HashMap<String, OwnedValue>;
+
+// Keeps the session alive for as long as the stream is being consumed
+pub struct ScreenCast {
+    _connection: Connection,
+    pub node_id: u32,
+    pub fd: OwnedFd,
+}
+
+impl ScreenCast {
+    pub fn start(output_name: &str) -> Result<Self, Box<dyn Error>> {
+        let connection = Connection::session()?;
+        let screencast = Proxy::new(
+            &connection,
+            PORTAL_DESTINATION,
+            PORTAL_PATH,
+            SCREENCAST_INTERFACE,
+        )?;
+
+        let session_token = next_token();
+        let results = request(&connection, |handle_token| {
+            let options = HashMap::from([
+                ("handle_token", Value::from(handle_token)),
+                ("session_handle_token", Value::from(session_token.as_str())),
+            ]);
+            screencast.call("CreateSession", &(options,))
+        })?;
+        let session = OwnedObjectPath::try_from(String::try_from(
+            results
+                .get("session_handle")
+                .ok_or("No session handle")?
+                .try_clone()?,
+        )?)?;
+
+        let restore_token = load_restore_token(output_name);
+        request(&connection, |handle_token| {
+            let mut options = HashMap::from([
+                ("handle_token", Value::from(handle_token)),
+                ("types", Value::from(SOURCE_TYPE_MONITOR)),
+                ("multiple", Value::from(false)),
+                ("persist_mode", Value::from(PERSIST_MODE_UNTIL_REVOKED)),
+            ]);
+            if let Some(restore_token) = &restore_token {
+                options.insert("restore_token", Value::from(restore_token.as_str()));
+            }
+            screencast.call("SelectSources", &(&session, options))
+        })?;
+
+        let results = request(&connection, |handle_token| {
+            let options = HashMap::from([("handle_token", Value::from(handle_token))]);
+            screencast.call("Start", &(&session, "", options))
+        })?;
+
+        if let Some(token) = results.get("restore_token") {
+            let token = String::try_from(token.try_clone()?)?;
+            if let Err(err) = save_restore_token(output_name, &token) {
+                log::warn!("Unable to save screencast restore token: {}", err);
+            }
+        }
+
+        let streams = Vec::<(u32, Results)>::try_from(
+            results
+                .get("streams")
+                .ok_or("No streams selected")?
+                .try_clone()?,
+        )?;
+        let (node_id, _) = streams.first().ok_or("No streams selected")?;
+
+        let options: HashMap<&str, Value> = HashMap::new();
+        let fd: zvariant::OwnedFd = screencast.call("OpenPipeWireRemote", &(&session, options))?;
+
+        log::debug!(
+            "Using screencast node '{}' for config '{}'",
+            node_id,
+            output_name
+        );
+
+        Ok(Self {
+            _connection: connection,
+            node_id: *node_id,
+            fd: fd.into(),
+        })
+    }
+}
+
+// Portal requests reply asynchronously with a Response signal on a predictable request object,
+// subscribe to it before making the call so that the reply cannot be missed
+fn request<F>(connection: &Connection, call: F) -> Result<Results, Box<dyn Error>>
+where
+    F: FnOnce(&str) -> zbus::Result<OwnedObjectPath>,
+{
+    let handle_token = next_token();
+    let sender = connection
+        .unique_name()
+        .ok_or("Not connected to session bus")?
+        .trim_start_matches(':')
+        .replace('.', "_");
+    let path = format!("{}/request/{}/{}", PORTAL_PATH, sender, handle_token);
+
+    let request = Proxy::new(
+        connection,
+        PORTAL_DESTINATION,
+        ObjectPath::try_from(path)?,
+        REQUEST_INTERFACE,
+    )?;
+    let mut responses = request.receive_signal("Response")?;
+
+    call(&handle_token)?;
+
+    let message = responses.next().ok_or("No response from portal")?;
+    let (response, results): (u32, Results) = message.body().deserialize()?;
+
+    match response {
+        0 => Ok(results),
+        1 => Err("Screencast request was cancelled".into()),
+        _ => Err("Screencast request failed".into()),
+    }
+}
+
+fn next_token() -> String {
+    format!(
+        "wluma{}_{}",
+        std::process::id(),
+        REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+fn load_restore_token(output_name: &str) -> Option<String> {
+    restore_token_path(output_name)
+        .ok()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
+
+fn save_restore_token(output_name: &str, token: &str) -> Result<(), Box<dyn Error>> {
+    Ok(fs::write(restore_token_path(output_name)?, token)?)
+}
+
+fn restore_token_path(output_name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    Ok(xdg::BaseDirectories::with_prefix("wluma")?
+        .create_data_directory("")?
+        .join(format!("{:}.restore_token", output_name)))
+}
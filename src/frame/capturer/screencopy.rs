@@ -0,0 +1,258 @@
+use super::pacer::Pacer;
+use super::shm::{pixel_layout, BufferSpec, ShmBuffer};
+use crate::frame::Settings;
+use crate::predictor::Controller;
+use std::error::Error;
+use std::{cell::Cell, cell::RefCell, rc::Rc, thread, time::Duration};
+use wayland_client::{
+    protocol::{wl_buffer::WlBuffer, wl_output::WlOutput, wl_registry::WlRegistry, wl_shm::WlShm},
+    Display, EventQueue, GlobalManager, Main,
+};
+use wayland_protocols::wlr::unstable::screencopy::v1::client::{
+    zwlr_screencopy_frame_v1::{Event, Flags, ZwlrScreencopyFrameV1},
+    zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
+};
+
+use wayland_protocols::unstable::xdg_output::v1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1;
+use wayland_protocols::unstable::xdg_output::v1::client::zxdg_output_v1::Event::Description;
+
+const DELAY_FAILURE: Duration = Duration::from_millis(1000);
+
+// Screencopy gives no reason for failures, assume the output is gone if they keep happening
+const MAX_FAILURES: u32 = 10;
+
+#[derive(Clone)]
+pub struct Capturer {
+    event_queue: Rc<RefCell<EventQueue>>,
+    globals: GlobalManager,
+    screencopy_manager: Main<ZwlrScreencopyManagerV1>,
+    shm: Main<WlShm>,
+    registry: Main<WlRegistry>,
+    xdg_output_manager: Main<ZxdgOutputManagerV1>,
+    buffer: Rc<RefCell<Option<ShmBuffer>>>,
+    failures: Rc<Cell<u32>>,
+    output_found: Rc<Cell<bool>>,
+    pacer: Rc<RefCell<Pacer>>,
+    running: Rc<Cell<bool>>,
+}
+
+impl super::Capturer for Capturer {
+    fn run(
+        &self,
+        output_name: &str,
+        settings: &Settings,
+        controller: Controller,
+    ) -> Result<(), Box<dyn Error>> {
+        let controller = Rc::new(RefCell::new(controller));
+        let settings = Rc::new(settings.clone());
+
+        self.globals
+            .list()
+            .iter()
+            .filter(|(_, interface, _)| interface == "wl_output")
+            .for_each(|(id, _, _)| {
+                let output = Rc::new(self.registry.bind::<WlOutput>(1, *id));
+                let capturer = Rc::new(self.clone());
+                let controller = controller.clone();
+                let settings = settings.clone();
+                let desired_output = output_name.to_string();
+                self.xdg_output_manager
+                    .get_xdg_output(&output)
+                    .quick_assign(move |_, event, _| match event {
+                        Description { description } if description.contains(&desired_output) => {
+                            log::debug!(
+                                "Using output '{}' for config '{}'",
+                                description,
+                                desired_output,
+                            );
+                            capturer.output_found.set(true);
+                            capturer.clone().capture_frame(
+                                controller.clone(),
+                                output.clone(),
+                                settings.clone(),
+                            );
+                        }
+                        _ => {}
+                    });
+            });
+
+        self.event_queue
+            .borrow_mut()
+            .sync_roundtrip(&mut (), |_, _, _| {})?;
+
+        if !self.output_found.get() {
+            log::info!("Output '{}' is not connected", output_name);
+            return Ok(());
+        }
+
+        while self.running.get() {
+            self.event_queue
+                .borrow_mut()
+                .dispatch(&mut (), |_, _, _| {})?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Capturer {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let display = Display::connect_to_env()?;
+        let mut event_queue = display.create_event_queue();
+        let attached_display = display.attach(event_queue.token());
+        let registry = attached_display.get_registry();
+        let globals = GlobalManager::new(&attached_display);
+
+        event_queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+
+        let screencopy_manager = globals
+            .instantiate_range::<ZwlrScreencopyManagerV1>(1, 3)
+            .map_err(|err| format!("Unable to init screencopy_manager: {}", err))?;
+
+        let shm = globals
+            .instantiate_exact::<WlShm>(1)
+            .map_err(|err| format!("Unable to init wl_shm: {}", err))?;
+
+        let xdg_output_manager = globals
+            .instantiate_exact::<ZxdgOutputManagerV1>(3)
+            .map_err(|err| format!("Unable to init xdg_output_manager: {}", err))?;
+
+        Ok(Self {
+            event_queue: Rc::new(RefCell::new(event_queue)),
+            globals,
+            screencopy_manager,
+            shm,
+            registry,
+            xdg_output_manager,
+            buffer: Rc::new(RefCell::new(None)),
+            failures: Rc::new(Cell::new(0)),
+            output_found: Rc::new(Cell::new(false)),
+            pacer: Rc::new(RefCell::new(Pacer::default())),
+            running: Rc::new(Cell::new(true)),
+        })
+    }
+
+    fn capture_frame(
+        self: Rc<Self>,
+        controller: Rc<RefCell<Controller>>,
+        output: Rc<Main<WlOutput>>,
+        settings: Rc<Settings>,
+    ) {
+        let mut spec = None;
+        let mut y_invert = false;
+        self.screencopy_manager
+            .capture_output(0, &output)
+            .quick_assign(move |data, event, _| match event {
+                Event::Buffer {
+                    format,
+                    width,
+                    height,
+                    stride,
+                } => {
+                    if spec.is_none() && pixel_layout(format).is_some() {
+                        spec = Some(BufferSpec {
+                            format,
+                            width,
+                            height,
+                            stride,
+                        });
+                    }
+
+                    // Since version 3 all buffer types are announced before buffer_done
+                    if data.as_ref().version() < 3 {
+                        self.copy(&data, spec);
+                    }
+                }
+
+                Event::BufferDone => self.copy(&data, spec),
+
+                Event::Flags { flags } => y_invert = flags.contains(Flags::YInvert),
+
+                Event::Ready { .. } => {
+                    data.destroy();
+                    self.failures.set(0);
+
+                    let luma = match self.luma_percent(y_invert, &settings) {
+                        Ok(luma) => luma,
+                        Err(err) => {
+                            log::error!("Unable to compute luma percent: {}", err);
+                            thread::sleep(DELAY_FAILURE);
+                            self.clone().capture_frame(
+                                controller.clone(),
+                                output.clone(),
+                                settings.clone(),
+                            );
+                            return;
+                        }
+                    };
+
+                    if let Err(err) = controller.borrow_mut().adjust(luma) {
+                        log::debug!("Stopping capturer: {}", err);
+                        self.running.set(false);
+                        return;
+                    }
+
+                    let settled = controller.borrow().is_settled();
+                    thread::sleep(self.pacer.borrow_mut().update(luma, settled));
+                    self.clone().capture_frame(
+                        controller.clone(),
+                        output.clone(),
+                        settings.clone(),
+                    );
+                }
+
+                Event::Failed => {
+                    data.destroy();
+                    self.failures.set(self.failures.get() + 1);
+
+                    if self.failures.get() >= MAX_FAILURES {
+                        log::info!("Frame capture keeps failing, output might be disconnected");
+                        self.running.set(false);
+                    } else {
+                        log::error!("Frame capture failed, will try again.");
+                        thread::sleep(DELAY_FAILURE);
+                        self.clone().capture_frame(
+                            controller.clone(),
+                            output.clone(),
+                            settings.clone(),
+                        );
+                    }
+                }
+
+                _ => {}
+            });
+    }
+
+    fn copy(&self, frame: &Main<ZwlrScreencopyFrameV1>, spec: Option<BufferSpec>) {
+        let buffer = spec
+            .ok_or_else(|| "Compositor offers no supported shm format".into())
+            .and_then(|spec| self.prepare_buffer(spec));
+
+        match buffer {
+            Ok(buffer) => frame.copy(&buffer),
+            Err(err) => {
+                log::error!("Unable to prepare screencopy buffer: {}", err);
+                frame.destroy();
+                self.running.set(false);
+            }
+        }
+    }
+
+    // Buffer is reused for as long as the compositor asks for the same one
+    fn prepare_buffer(&self, spec: BufferSpec) -> Result<WlBuffer, Box<dyn Error>> {
+        let mut buffer = self.buffer.borrow_mut();
+
+        match buffer.as_ref() {
+            Some(buffer) if buffer.spec == spec => Ok(buffer.wl_buffer()),
+            _ => Ok(buffer.insert(ShmBuffer::new(&self.shm, spec)?).wl_buffer()),
+        }
+    }
+
+    fn luma_percent(&self, y_invert: bool, settings: &Settings) -> Result<u8, Box<dyn Error>> {
+        self.buffer
+            .borrow_mut()
+            .as_mut()
+            .ok_or("No screencopy buffer")?
+            .luma_percent(y_invert, settings)
+    }
+}
@@ -0,0 +1,154 @@
+use crate::frame::{compute_shm_lightness_percent, PixelLayout, Settings};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use wayland_client::protocol::{
+    wl_buffer::WlBuffer,
+    wl_shm::{Format, WlShm},
+};
+use wayland_client::Main;
+
+static SHM_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferSpec {
+    pub format: Format,
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+}
+
+// A wl_buffer backed by a file that the compositor copies frames into
+pub struct ShmBuffer {
+    pub spec: BufferSpec,
+    buffer: Main<WlBuffer>,
+    mapping: Mapping,
+}
+
+impl ShmBuffer {
+    pub fn new(shm: &Main<WlShm>, spec: BufferSpec) -> Result<Self, Box<dyn Error>> {
+        let size = spec.stride * spec.height;
+        let file = shm_file()?;
+        file.set_len(size as u64)?;
+
+        let pool = shm.create_pool(file.as_raw_fd(), size as i32);
+        let buffer = pool.create_buffer(
+            0,
+            spec.width as i32,
+            spec.height as i32,
+            spec.stride as i32,
+            spec.format,
+        );
+        pool.destroy();
+
+        // Compositor writes frames straight into the mapping, so they never need to be copied
+        let mapping = match Mapping::new(&file, size as usize) {
+            Ok(mapping) => mapping,
+            Err(err) => {
+                buffer.destroy();
+                return Err(err);
+            }
+        };
+
+        Ok(Self {
+            spec,
+            buffer,
+            mapping,
+        })
+    }
+
+    pub fn wl_buffer(&self) -> WlBuffer {
+        self.buffer.detach()
+    }
+
+    pub fn luma_percent(
+        &mut self,
+        y_invert: bool,
+        settings: &Settings,
+    ) -> Result<u8, Box<dyn Error>> {
+        compute_shm_lightness_percent(
+            self.mapping.bytes(),
+            self.spec.width as usize,
+            self.spec.height as usize,
+            self.spec.stride as usize,
+            pixel_layout(self.spec.format).ok_or("Unsupported shm format")?,
+            y_invert,
+            settings,
+        )
+    }
+}
+
+impl Drop for ShmBuffer {
+    fn drop(&mut self) {
+        self.buffer.destroy();
+    }
+}
+
+// Read-only view of the whole file, which stays mapped even once the file is closed
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(file: &File, len: usize) -> Result<Self, Box<dyn Error>> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+// Formats are named after little-endian words, so the bytes in memory go in reverse order
+pub fn pixel_layout(format: Format) -> Option<PixelLayout> {
+    match format {
+        Format::Argb8888 | Format::Xrgb8888 => Some(PixelLayout::Bgrx),
+        Format::Abgr8888 | Format::Xbgr8888 => Some(PixelLayout::Rgbx),
+        _ => None,
+    }
+}
+
+// Anonymous file in the runtime dir, shared with the compositor via its fd only
+pub(crate) fn shm_file() -> Result<File, Box<dyn Error>> {
+    let path =
+        PathBuf::from(std::env::var_os("XDG_RUNTIME_DIR").ok_or("XDG_RUNTIME_DIR is not set")?)
+            .join(format!(
+                "wluma-shm-{}-{}",
+                std::process::id(),
+                SHM_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+
+    Ok(file)
+}
@@ -1,6 +1,8 @@
-use crate::frame::{object::Object, vulkan::Vulkan};
+use super::pacer::Pacer;
+use crate::frame::{object, object::Object, vulkan::Vulkan, Settings};
 use crate::predictor::Controller;
-use std::{cell::RefCell, rc::Rc, thread, time::Duration};
+use std::error::Error;
+use std::{cell::Cell, cell::RefCell, rc::Rc, thread, time::Duration};
 use wayland_client::{
     protocol::{wl_output::WlOutput, wl_registry::WlRegistry},
     Display, EventQueue, GlobalManager, Main,
@@ -13,7 +15,6 @@ use wayland_protocols::wlr::unstable::export_dmabuf::v1::client::{
 use wayland_protocols::unstable::xdg_output::v1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1;
 use wayland_protocols::unstable::xdg_output::v1::client::zxdg_output_v1::Event::Description;
 
-const DELAY_SUCCESS: Duration = Duration::from_millis(100);
 const DELAY_FAILURE: Duration = Duration::from_millis(1000);
 
 #[derive(Clone)]
@@ -24,11 +25,20 @@ pub struct Capturer {
     vulkan: Rc<Vulkan>,
     registry: Main<WlRegistry>,
     xdg_output_manager: Main<ZxdgOutputManagerV1>,
+    output_found: Rc<Cell<bool>>,
+    pacer: Rc<RefCell<Pacer>>,
+    running: Rc<Cell<bool>>,
 }
 
 impl super::Capturer for Capturer {
-    fn run(&self, output_name: &str, controller: Controller) {
+    fn run(
+        &self,
+        output_name: &str,
+        settings: &Settings,
+        controller: Controller,
+    ) -> Result<(), Box<dyn Error>> {
         let controller = Rc::new(RefCell::new(controller));
+        let settings = Rc::new(settings.clone());
 
         self.globals
             .list()
@@ -38,6 +48,7 @@ impl super::Capturer for Capturer {
                 let output = Rc::new(self.registry.bind::<WlOutput>(1, *id));
                 let capturer = Rc::new(self.clone());
                 let controller = controller.clone();
+                let settings = settings.clone();
                 let desired_output = output_name.to_string();
                 self.xdg_output_manager
                     .get_xdg_output(&output)
@@ -48,59 +59,74 @@ impl super::Capturer for Capturer {
                                 description,
                                 desired_output,
                             );
-                            capturer
-                                .clone()
-                                .capture_frame(controller.clone(), output.clone());
+                            capturer.output_found.set(true);
+                            capturer.clone().capture_frame(
+                                controller.clone(),
+                                output.clone(),
+                                settings.clone(),
+                            );
                         }
                         _ => {}
                     });
             });
 
-        loop {
+        self.event_queue
+            .borrow_mut()
+            .sync_roundtrip(&mut (), |_, _, _| {})?;
+
+        if !self.output_found.get() {
+            log::info!("Output '{}' is not connected", output_name);
+            return Ok(());
+        }
+
+        while self.running.get() {
             self.event_queue
                 .borrow_mut()
-                .dispatch(&mut (), |_, _, _| {})
-                .expect("Error running wlroots capturer main loop");
+                .dispatch(&mut (), |_, _, _| {})?;
         }
+
+        Ok(())
     }
 }
 
-impl Default for Capturer {
-    fn default() -> Self {
-        let display = Display::connect_to_env().unwrap();
+impl Capturer {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let display = Display::connect_to_env()?;
         let mut event_queue = display.create_event_queue();
         let attached_display = display.attach(event_queue.token());
         let registry = attached_display.get_registry();
         let globals = GlobalManager::new(&attached_display);
 
-        event_queue.sync_roundtrip(&mut (), |_, _, _| {}).unwrap();
+        event_queue.sync_roundtrip(&mut (), |_, _, _| {})?;
 
         let dmabuf_manager = globals
             .instantiate_exact::<ZwlrExportDmabufManagerV1>(1)
-            .expect("Unable to init export_dmabuf_manager");
+            .map_err(|err| format!("Unable to init export_dmabuf_manager: {}", err))?;
 
         let xdg_output_manager = globals
             .instantiate_exact::<ZxdgOutputManagerV1>(3)
-            .expect("Unable to init xdg_output_manager");
+            .map_err(|err| format!("Unable to init xdg_output_manager: {}", err))?;
 
-        let vulkan = Rc::new(Vulkan::new().expect("Unable to initialize Vulkan"));
+        let vulkan = Rc::new(Vulkan::new()?);
 
-        Self {
+        Ok(Self {
             event_queue: Rc::new(RefCell::new(event_queue)),
             globals,
             registry,
             dmabuf_manager,
             vulkan,
             xdg_output_manager,
-        }
+            output_found: Rc::new(Cell::new(false)),
+            pacer: Rc::new(RefCell::new(Pacer::default())),
+            running: Rc::new(Cell::new(true)),
+        })
     }
-}
 
-impl Capturer {
     fn capture_frame(
         self: Rc<Self>,
         controller: Rc<RefCell<Controller>>,
         output: Rc<Main<WlOutput>>,
+        settings: Rc<Settings>,
     ) {
         let mut frame = Object::default();
         self.dmabuf_manager
@@ -109,41 +135,79 @@ impl Capturer {
                 Event::Frame {
                     width,
                     height,
+                    format,
+                    mod_high,
+                    mod_low,
                     num_objects,
                     ..
                 } => {
-                    frame.set_metadata(width, height, num_objects);
+                    frame.set_metadata(
+                        width,
+                        height,
+                        format,
+                        object::modifier(mod_high, mod_low),
+                        num_objects,
+                    );
                 }
 
                 Event::Object {
-                    index, fd, size, ..
+                    index,
+                    fd,
+                    size,
+                    offset,
+                    stride,
+                    plane_index,
                 } => {
-                    frame.set_object(index, fd, size);
+                    frame.set_object(index, fd, size, offset, stride, plane_index);
                 }
 
                 Event::Ready { .. } => {
-                    let luma = self
-                        .vulkan
-                        .luma_percent(&frame)
-                        .expect("Unable to compute luma percent");
+                    let luma = self.vulkan.luma_percent(&frame, &settings);
+                    data.destroy();
 
-                    controller.borrow_mut().adjust(luma);
+                    let luma = match luma {
+                        Ok(luma) => luma,
+                        Err(err) => {
+                            log::error!("Unable to compute luma percent: {}", err);
+                            thread::sleep(DELAY_FAILURE);
+                            self.clone().capture_frame(
+                                controller.clone(),
+                                output.clone(),
+                                settings.clone(),
+                            );
+                            return;
+                        }
+                    };
 
-                    data.destroy();
+                    if let Err(err) = controller.borrow_mut().adjust(luma) {
+                        log::debug!("Stopping capturer: {}", err);
+                        self.running.set(false);
+                        return;
+                    }
 
-                    thread::sleep(DELAY_SUCCESS);
-                    self.clone().capture_frame(controller.clone(), output.clone());
+                    let settled = controller.borrow().is_settled();
+                    thread::sleep(self.pacer.borrow_mut().update(luma, settled));
+                    self.clone().capture_frame(
+                        controller.clone(),
+                        output.clone(),
+                        settings.clone(),
+                    );
                 }
 
                 Event::Cancel { reason } => {
                     data.destroy();
 
                     if reason == CancelReason::Permanent {
-                        panic!("Frame was cancelled due to a permanent error. If you just disconnected screen, this is not implemented yet.");
+                        log::info!("Frame was cancelled permanently, output might be disconnected");
+                        self.running.set(false);
                     } else {
-                        log::error!("Frame was cancelled due to a temporary error, will try again.");
+                        log::error!("Frame was cancelled temporarily, will try again.");
                         thread::sleep(DELAY_FAILURE);
-                        self.clone().capture_frame(controller.clone(), output.clone());
+                        self.clone().capture_frame(
+                            controller.clone(),
+                            output.clone(),
+                            settings.clone(),
+                        );
                     }
                 }
 
@@ -0,0 +1,230 @@
+use super::Region;
+
+// Pixels at least this light (in percent) count as bright
+const BRIGHT_THRESHOLD: usize = 70;
+
+// Weight of pixels in the corners relative to the ones in the center of the screen
+const CENTER_WEIGHT_FALLOFF: f64 = 0.75;
+
+// Which statistic of a frame is used as its luma
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Metric {
+    #[default]
+    Mean,
+    P10,
+    P50,
+    P90,
+    BrightFraction,
+    CenterWeighted,
+}
+
+// How frames of an output get measured
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub metric: Metric,
+    pub exclude: Vec<Region>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    // Weight of pixels for each perceived lightness percent
+    pub histogram: [f64; 101],
+    pub mean: u8,
+    pub center_weighted_mean: u8,
+}
+
+impl Analysis {
+    // Pixels are (x, y, rgb) within a frame of the given dimensions, None if there are none
+    pub fn new(
+        pixels: impl IntoIterator<Item = (u32, u32, [u8; 3])>,
+        width: u32,
+        height: u32,
+    ) -> Option<Self> {
+        let pixels = pixels
+            .into_iter()
+            .map(|(x, y, rgb)| (x, y, rgb.map(|value| value as f64), 1.0));
+        Self::weighted(pixels, width, height)
+    }
+
+    // Same with a weight for each pixel, e.g. the part of a downscaled pixel that is not excluded
+    pub fn weighted(
+        pixels: impl IntoIterator<Item = (u32, u32, [f64; 3], f64)>,
+        width: u32,
+        height: u32,
+    ) -> Option<Self> {
+        let mut histogram = [0.0; 101];
+        let mut sums = [0.0; 3];
+        let mut weighted_sums = [0.0; 3];
+        let mut count = 0.0;
+        let mut weights = 0.0;
+
+        for (x, y, rgb, weight) in pixels.into_iter().filter(|pixel| pixel.3 > 0.0) {
+            let center_weight = center_weight(x, y, width, height) * weight;
+            for (channel, value) in rgb.iter().enumerate() {
+                sums[channel] += value * weight;
+                weighted_sums[channel] += value * center_weight;
+            }
+            histogram[lightness(rgb).round().clamp(0.0, 100.0) as usize] += weight;
+            count += weight;
+            weights += center_weight;
+        }
+
+        if count == 0.0 {
+            return None;
+        }
+
+        Some(Self {
+            histogram,
+            mean: lightness(sums.map(|sum| sum / count)).round() as u8,
+            center_weighted_mean: lightness(weighted_sums.map(|sum| sum / weights)).round() as u8,
+        })
+    }
+
+    // Lowest lightness that at least `percent` of pixels are not lighter than
+    pub fn percentile(&self, percent: u8) -> u8 {
+        let total = self.histogram.iter().sum::<f64>();
+        // Sums of weights are not exact, which must not push a percentile to the next lightness
+        let target = total * percent.min(100) as f64 / 100.0 * (1.0 - f64::EPSILON * 128.0);
+
+        let mut seen = 0.0;
+        for (lightness, weight) in self.histogram.iter().enumerate() {
+            seen += weight;
+            if seen > 0.0 && seen >= target {
+                return lightness as u8;
+            }
+        }
+
+        100
+    }
+
+    // Percentage of bright pixels
+    pub fn bright_fraction(&self) -> u8 {
+        let total = self.histogram.iter().sum::<f64>();
+        let bright = self.histogram[BRIGHT_THRESHOLD..].iter().sum::<f64>();
+        (bright / total * 100.0).round() as u8
+    }
+}
+
+impl Metric {
+    pub fn value(&self, analysis: &Analysis) -> u8 {
+        match self {
+            Metric::Mean => analysis.mean,
+            Metric::P10 => analysis.percentile(10),
+            Metric::P50 => analysis.percentile(50),
+            Metric::P90 => analysis.percentile(90),
+            Metric::BrightFraction => analysis.bright_fraction(),
+            Metric::CenterWeighted => analysis.center_weighted_mean,
+        }
+    }
+}
+
+pub fn lightness([r, g, b]: [f64; 3]) -> f64 {
+    (0.241 * r * r + 0.691 * g * g + 0.068 * b * b).sqrt() / 255.0 * 100.0
+}
+
+// 1 in the middle of the frame, falling off towards the edges
+fn center_weight(x: u32, y: u32, width: u32, height: u32) -> f64 {
+    let offset = |value: u32, size: u32| (value as f64 + 0.5) / size.max(1) as f64 * 2.0 - 1.0;
+    let distance = offset(x, width).powi(2) + offset(y, height).powi(2);
+    1.0 - CENTER_WEIGHT_FALLOFF * distance.min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: [u8; 3] = [0, 0, 0];
+    const WHITE: [u8; 3] = [255, 255, 255];
+
+    // Black terminal filling the screen with a small bright video in the corner
+    fn terminal_with_video() -> Analysis {
+        let pixels = (0..10)
+            .flat_map(|y| (0..10).map(move |x| (x, y)))
+            .map(|(x, y)| (x, y, if x < 3 && y < 3 { WHITE } else { BLACK }));
+        Analysis::new(pixels, 10, 10).unwrap()
+    }
+
+    #[test]
+    fn test_histogram_counts_pixels_by_lightness() {
+        let analysis = terminal_with_video();
+
+        assert_eq!(91.0, analysis.histogram[0]);
+        assert_eq!(9.0, analysis.histogram[100]);
+        assert_eq!(100.0, analysis.histogram.iter().sum::<f64>());
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let analysis = terminal_with_video();
+
+        assert_eq!(0, analysis.percentile(10));
+        assert_eq!(0, analysis.percentile(50));
+        assert_eq!(0, analysis.percentile(90));
+        assert_eq!(100, analysis.percentile(95));
+        assert_eq!(100, analysis.percentile(100));
+    }
+
+    #[test]
+    fn test_bright_fraction() {
+        assert_eq!(9, terminal_with_video().bright_fraction());
+    }
+
+    #[test]
+    fn test_mean_matches_mean_color() {
+        let analysis = Analysis::new([(0, 0, BLACK), (1, 0, WHITE)], 2, 1).unwrap();
+
+        assert_eq!(50, analysis.mean);
+    }
+
+    #[test]
+    fn test_center_weighted_mean_prefers_center() {
+        let corner = terminal_with_video();
+        let center = Analysis::new(
+            (0..10)
+                .flat_map(|y| (0..10).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    let lit = (3..6).contains(&x) && (3..6).contains(&y);
+                    (x, y, if lit { WHITE } else { BLACK })
+                }),
+            10,
+            10,
+        )
+        .unwrap();
+
+        assert_eq!(corner.mean, center.mean);
+        assert!(center.center_weighted_mean > center.mean);
+        assert!(corner.center_weighted_mean < corner.mean);
+    }
+
+    #[test]
+    fn test_metric_value() {
+        let analysis = terminal_with_video();
+
+        assert_eq!(analysis.mean, Metric::Mean.value(&analysis));
+        assert_eq!(0, Metric::P50.value(&analysis));
+        assert_eq!(9, Metric::BrightFraction.value(&analysis));
+        assert_eq!(
+            analysis.center_weighted_mean,
+            Metric::CenterWeighted.value(&analysis)
+        );
+    }
+
+    #[test]
+    fn test_weighted_pixels() {
+        // Half of the white pixel is excluded, so it counts half as much as the black one
+        let analysis =
+            Analysis::weighted([(0, 0, [0.0; 3], 1.0), (1, 0, [255.0; 3], 0.5)], 2, 1).unwrap();
+
+        assert_eq!([1.0, 0.5], [analysis.histogram[0], analysis.histogram[100]]);
+        assert_eq!(0, analysis.percentile(50));
+        assert_eq!(100, analysis.percentile(90));
+        assert_eq!(33, analysis.bright_fraction());
+        assert_eq!(lightness([85.0; 3]).round() as u8, analysis.mean);
+    }
+
+    #[test]
+    fn test_no_pixels() {
+        assert_eq!(None, Analysis::new([], 10, 10));
+        assert_eq!(None, Analysis::weighted([(0, 0, [255.0; 3], 0.0)], 10, 10));
+    }
+}
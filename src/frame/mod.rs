@@ -1,30 +1,265 @@
-use itertools::Itertools;
+use std::error::Error;
 
 pub mod capturer;
+pub mod luma;
 mod object;
+pub mod region;
 pub mod vulkan;
 
-pub fn compute_perceived_lightness_percent(rgbas: &[u8], has_alpha: bool, pixels: usize) -> u8 {
-    let channels = if has_alpha { 4 } else { 3 };
-
-    let (rs, gs, bs) = rgbas
-        .iter()
-        .take(channels * pixels)
-        .chunks(channels)
-        .into_iter()
-        .map(|mut chunk| {
-            let r = *chunk.next().unwrap();
-            let g = *chunk.next().unwrap();
-            let b = *chunk.next().unwrap();
-            (r as f64, g as f64, b as f64)
+pub use luma::{Analysis, Metric, Settings};
+pub use region::Region;
+
+// Every n-th pixel in both directions is enough to estimate luma of a frame on CPU
+const CPU_SAMPLE_STEP: usize = 4;
+
+// Order of color channels in a 4 bytes pixel, the last byte is alpha or unused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelLayout {
+    Bgrx,
+    Rgbx,
+}
+
+// Rows of y-inverted frames are stored bottom to top
+pub fn compute_shm_lightness_percent(
+    bytes: &[u8],
+    width: usize,
+    height: usize,
+    stride: usize,
+    layout: PixelLayout,
+    y_invert: bool,
+    settings: &Settings,
+) -> Result<u8, Box<dyn Error>> {
+    if width == 0 || height == 0 || stride < width * 4 {
+        return Err("Invalid frame dimensions".into());
+    }
+    if bytes.len() < stride * (height - 1) + width * 4 {
+        return Err("Frame buffer is smaller than the frame".into());
+    }
+
+    let (r, g, b) = match layout {
+        PixelLayout::Bgrx => (2, 1, 0),
+        PixelLayout::Rgbx => (0, 1, 2),
+    };
+
+    let pixels = (0..height)
+        .step_by(CPU_SAMPLE_STEP)
+        .flat_map(|y| {
+            (0..width)
+                .step_by(CPU_SAMPLE_STEP)
+                .map(move |x| (x as u32, y as u32))
         })
-        .reduce(|(rs, gs, bs), (r, g, b)| (rs + r, gs + g, bs + b))
-        .unwrap();
+        .filter(|&(x, y)| !settings.exclude.iter().any(|e| e.contains(x, y)))
+        .map(|(x, y)| {
+            let row = if y_invert {
+                height - 1 - y as usize
+            } else {
+                y as usize
+            };
+            let pixel = row * stride + x as usize * 4;
+            (x, y, [bytes[pixel + r], bytes[pixel + g], bytes[pixel + b]])
+        });
+
+    let analysis =
+        Analysis::new(pixels, width as u32, height as u32).ok_or("Whole frame is excluded")?;
+
+    Ok(settings.metric.value(&analysis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_shm_lightness_percent_respects_channel_order() {
+        // A single pure red pixel
+        let bgrx = [0, 0, 255, 0];
+        let rgbx = [255, 0, 0, 0];
+
+        assert_eq!(
+            49,
+            compute_shm_lightness_percent(
+                &bgrx,
+                1,
+                1,
+                4,
+                PixelLayout::Bgrx,
+                false,
+                &Settings::default()
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            49,
+            compute_shm_lightness_percent(
+                &rgbx,
+                1,
+                1,
+                4,
+                PixelLayout::Rgbx,
+                false,
+                &Settings::default()
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_compute_shm_lightness_percent_skips_stride_padding() {
+        // Sampled rows 0 and 4 are white, everything else including padding is black
+        let mut bytes = vec![0; 5 * 8];
+        bytes[..4].fill(255);
+        bytes[32..36].fill(255);
+
+        assert_eq!(
+            100,
+            compute_shm_lightness_percent(
+                &bytes,
+                1,
+                5,
+                8,
+                PixelLayout::Bgrx,
+                false,
+                &Settings::default()
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_compute_shm_lightness_percent_downsamples() {
+        // 8x8 frame where only sampled pixels are white
+        let mut bytes = vec![0; 8 * 8 * 4];
+        for (x, y) in [(0, 0), (4, 0), (0, 4), (4, 4)] {
+            bytes[(y * 8 + x) * 4..][..4].fill(255);
+        }
+
+        assert_eq!(
+            100,
+            compute_shm_lightness_percent(
+                &bytes,
+                8,
+                8,
+                32,
+                PixelLayout::Rgbx,
+                false,
+                &Settings::default()
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_compute_shm_lightness_percent_skips_excluded_regions() {
+        // 8x8 black frame with a white bar on top, which is excluded
+        let mut bytes = vec![0; 8 * 8 * 4];
+        bytes[..8 * 4].fill(255);
+        let settings = Settings {
+            exclude: vec![Region::new(0, 0, 8, 1)],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            50,
+            compute_shm_lightness_percent(
+                &bytes,
+                8,
+                8,
+                32,
+                PixelLayout::Bgrx,
+                false,
+                &Settings::default()
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            0,
+            compute_shm_lightness_percent(&bytes, 8, 8, 32, PixelLayout::Bgrx, false, &settings)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_compute_shm_lightness_percent_flips_y_inverted_frames() {
+        // 8x8 black frame with a white bar on top, which is stored last when y-inverted,
+        // while the bottom half is excluded
+        let mut bytes = vec![0; 8 * 8 * 4];
+        bytes[7 * 8 * 4..].fill(255);
+        let settings = Settings {
+            exclude: vec![Region::new(0, 4, 8, 4)],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            100,
+            compute_shm_lightness_percent(&bytes, 8, 8, 32, PixelLayout::Bgrx, true, &settings)
+                .unwrap()
+        );
+        assert_eq!(
+            0,
+            compute_shm_lightness_percent(&bytes, 8, 8, 32, PixelLayout::Bgrx, false, &settings)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_compute_shm_lightness_percent_rejects_fully_excluded_frames() {
+        let bytes = vec![0; 8 * 8 * 4];
+
+        assert!(compute_shm_lightness_percent(
+            &bytes,
+            8,
+            8,
+            32,
+            PixelLayout::Bgrx,
+            false,
+            &Settings {
+                exclude: vec![Region::new(0, 0, 8, 8)],
+                ..Default::default()
+            }
+        )
+        .is_err());
+    }
 
-    let pixels = pixels as f64;
-    let (r, g, b) = (rs / pixels, gs / pixels, bs / pixels);
+    #[test]
+    fn test_compute_shm_lightness_percent_uses_metric() {
+        // 8x8 frame with sampled pixels in the top half white
+        let mut bytes = vec![0; 8 * 8 * 4];
+        bytes[..8 * 4 * 4].fill(255);
+        let measure = |metric| {
+            let settings = Settings {
+                metric,
+                ..Default::default()
+            };
+            compute_shm_lightness_percent(&bytes, 8, 8, 32, PixelLayout::Bgrx, false, &settings)
+                .unwrap()
+        };
 
-    let result = (0.241 * r * r + 0.691 * g * g + 0.068 * b * b).sqrt() / 255.0 * 100.0;
+        assert_eq!(0, measure(Metric::P10));
+        assert_eq!(100, measure(Metric::P90));
+        assert_eq!(50, measure(Metric::BrightFraction));
+    }
 
-    result.round() as u8
+    #[test]
+    fn test_compute_shm_lightness_percent_rejects_short_buffers() {
+        assert!(compute_shm_lightness_percent(
+            &[0; 12],
+            2,
+            2,
+            8,
+            PixelLayout::Bgrx,
+            false,
+            &Settings::default()
+        )
+        .is_err());
+        assert!(compute_shm_lightness_percent(
+            &[0; 16],
+            2,
+            2,
+            4,
+            PixelLayout::Bgrx,
+            false,
+            &Settings::default()
+        )
+        .is_err());
+    }
 }
@@ -1,25 +1,91 @@
 use std::os::unix::io::RawFd;
 
+// DRM_FORMAT_MOD_INVALID, used by compositors to signal an implicit modifier
+pub const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
 #[derive(Default)]
 pub struct Object {
     pub width: u32,
     pub height: u32,
+    pub format: u32,
+    pub modifier: u64,
     pub num_objects: u32,
     pub fds: Vec<RawFd>,
     pub sizes: Vec<u32>,
+    pub offsets: Vec<u32>,
+    pub strides: Vec<u32>,
+    pub plane_indices: Vec<u32>,
 }
 
 impl Object {
-    pub fn set_metadata(&mut self, width: u32, height: u32, num_objects: u32) {
+    pub fn set_metadata(
+        &mut self,
+        width: u32,
+        height: u32,
+        format: u32,
+        modifier: u64,
+        num_objects: u32,
+    ) {
         self.width = width;
         self.height = height;
+        self.format = format;
+        self.modifier = modifier;
         self.num_objects = num_objects;
         self.fds.resize(num_objects as usize, 0);
         self.sizes.resize(num_objects as usize, 0);
+        self.offsets.resize(num_objects as usize, 0);
+        self.strides.resize(num_objects as usize, 0);
+        self.plane_indices.resize(num_objects as usize, 0);
     }
 
-    pub fn set_object(&mut self, index: u32, fd: RawFd, size: u32) {
+    pub fn set_object(
+        &mut self,
+        index: u32,
+        fd: RawFd,
+        size: u32,
+        offset: u32,
+        stride: u32,
+        plane_index: u32,
+    ) {
         self.fds[index as usize] = fd;
         self.sizes[index as usize] = size;
+        self.offsets[index as usize] = offset;
+        self.strides[index as usize] = stride;
+        self.plane_indices[index as usize] = plane_index;
+    }
+
+    pub fn has_explicit_modifier(&self) -> bool {
+        self.modifier != DRM_FORMAT_MOD_INVALID
+    }
+}
+
+pub fn modifier(mod_high: u32, mod_low: u32) -> u64 {
+    ((mod_high as u64) << 32) | mod_low as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modifier_combines_high_and_low_bits() {
+        assert_eq!(0, modifier(0, 0));
+        assert_eq!(0x0100_0000_0000_0002, modifier(0x0100_0000, 0x0000_0002));
+        assert_eq!(DRM_FORMAT_MOD_INVALID, modifier(0x00ff_ffff, 0xffff_ffff));
+    }
+
+    #[test]
+    fn test_set_object_stores_plane_layout() {
+        let mut frame = Object::default();
+        frame.set_metadata(1920, 1080, 0x3432_5258, modifier(0x0100_0000, 4), 2);
+        frame.set_object(1, 11, 4096, 8_294_400, 512, 1);
+        frame.set_object(0, 10, 8_298_496, 0, 7680, 0);
+
+        assert_eq!(vec![10, 11], frame.fds);
+        assert_eq!(vec![0, 8_294_400], frame.offsets);
+        assert_eq!(vec![7680, 512], frame.strides);
+        assert_eq!(vec![0, 1], frame.plane_indices);
+        assert!(frame.has_explicit_modifier());
     }
 }
@@ -0,0 +1,85 @@
+// Rectangular area of a frame, in buffer pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x && x - self.x < self.width && y >= self.y && y - self.y < self.height
+    }
+
+    // Maps the region onto a scaled down image, rounding outwards so that nothing excluded leaks in
+    pub fn scale(&self, from: (u32, u32), to: (u32, u32)) -> Option<Region> {
+        let scale = |value: u32, from: u32, to: u32, round_up: bool| {
+            let value = value.min(from) as u64 * to as u64;
+            let scaled = if round_up {
+                value.div_ceil(from as u64)
+            } else {
+                value / from as u64
+            };
+            scaled as u32
+        };
+
+        if from.0 == 0 || from.1 == 0 {
+            return None;
+        }
+
+        let x0 = scale(self.x, from.0, to.0, false);
+        let y0 = scale(self.y, from.1, to.1, false);
+        let x1 = scale(self.x.saturating_add(self.width), from.0, to.0, true);
+        let y1 = scale(self.y.saturating_add(self.height), from.1, to.1, true);
+
+        (x1 > x0 && y1 > y0).then(|| Region::new(x0, y0, x1 - x0, y1 - y0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contains() {
+        let region = Region::new(10, 20, 5, 5);
+
+        assert!(region.contains(10, 20));
+        assert!(region.contains(14, 24));
+        assert!(!region.contains(15, 24));
+        assert!(!region.contains(9, 20));
+    }
+
+    #[test]
+    fn test_scale_rounds_outwards() {
+        let region = Region::new(1, 1, 2, 2);
+
+        assert_eq!(Some(Region::new(0, 0, 2, 2)), region.scale((8, 8), (4, 4)));
+        assert_eq!(
+            Some(Region::new(0, 0, 960, 15)),
+            Region::new(0, 0, 1920, 30).scale((1920, 1080), (960, 540))
+        );
+    }
+
+    #[test]
+    fn test_scale_clamps_to_image() {
+        assert_eq!(
+            Some(Region::new(900, 0, 60, 540)),
+            Region::new(1800, 0, 500, 5000).scale((1920, 1080), (960, 540))
+        );
+        assert_eq!(
+            None,
+            Region::new(2000, 0, 10, 10).scale((1920, 1080), (960, 540))
+        );
+    }
+}
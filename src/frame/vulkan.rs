@@ -1,32 +1,57 @@
-use crate::frame::compute_perceived_lightness_percent;
 use crate::frame::object::Object;
+use crate::frame::{Analysis, Region, Settings};
+use ash::extensions::khr::ExternalMemoryFd;
 use ash::{vk, Device, Entry, Instance};
-use std::cell::RefCell;
+use itertools::Itertools;
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
 use std::default::Default;
 use std::error::Error;
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::mem::ManuallyDrop;
 use std::ops::Drop;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd, OwnedFd, RawFd};
 
 const WLUMA_VERSION: u32 = vk::make_api_version(0, 4, 1, 2);
 const VULKAN_VERSION: u32 = vk::make_api_version(0, 1, 2, 0);
 
-const FINAL_MIP_LEVEL: u32 = 4; // Don't generate mipmaps beyond this level - GPU is doing too poor of a job averaging the colors
-const BUFFER_PIXELS: u64 = 500 * 4; // Pre-allocated buffer size, should be enough to fit FINAL_MIP_LEVEL
+// Image is half the frame size, so each pixel read back averages 4x4 pixels of the frame,
+// which is as many as get sampled on CPU
+const READBACK_MIP_LEVEL: u32 = 1;
 const FENCES_TIMEOUT_NS: u64 = 1_000_000_000;
 
 pub struct Vulkan {
     _entry: Entry, // must keep reference to prevent early memory release
     instance: Instance,
+    physical_device: vk::PhysicalDevice,
     device: Device,
-    buffer: vk::Buffer,
-    buffer_memory: vk::DeviceMemory,
+    device_memory_properties: vk::PhysicalDeviceMemoryProperties,
+    modifiers_supported: bool,
     command_pool: vk::CommandPool,
     command_buffers: Vec<vk::CommandBuffer>,
     queue: vk::Queue,
     fence: vk::Fence,
     image: RefCell<Option<vk::Image>>,
     image_memory: RefCell<Option<vk::DeviceMemory>>,
-    image_resolution: RefCell<Option<(u32, u32)>>,
+    image_dimensions: RefCell<Option<(u32, u32, u32)>>,
+    buffer: RefCell<Option<vk::Buffer>>,
+    buffer_memory: RefCell<Option<vk::DeviceMemory>>,
+    zero_buffer: RefCell<Option<(vk::Buffer, vk::DeviceMemory, u64)>>,
+    external_memory_fd: ExternalMemoryFd,
+    exported_image: RefCell<Option<(vk::Image, vk::DeviceMemory)>>,
+    // Checksum of the last mip read back, with the luma it had
+    last_readback: Cell<Option<(u64, u8)>>,
+}
+
+// Linear image shared with the compositor as a DMA-BUF, for capturers that provide the buffers
+pub struct ExportedImage {
+    pub fd: OwnedFd,
+    pub size: u32,
+    pub offset: u32,
+    pub stride: u32,
 }
 
 impl Vulkan {
@@ -63,16 +88,30 @@ impl Vulkan {
             .queue_priorities(&[1.0])
             .build()];
 
-        let device_extensions = &[
+        // Older drivers lack explicit modifiers, frames are then imported with implicit ones
+        let supported_extensions =
+            unsafe { instance.enumerate_device_extension_properties(physical_device)? };
+        let modifiers_supported = supported_extensions.iter().any(|extension| {
+            let name = unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) };
+            name == vk::ExtImageDrmFormatModifierFn::name()
+        });
+        if !modifiers_supported {
+            log::debug!("Vulkan driver does not support explicit DRM format modifiers");
+        }
+
+        let mut device_extensions = vec![
             vk::KhrExternalMemoryFn::name().as_ptr(),
             vk::KhrExternalMemoryFdFn::name().as_ptr(),
             vk::ExtExternalMemoryDmaBufFn::name().as_ptr(),
         ];
+        if modifiers_supported {
+            device_extensions.push(vk::ExtImageDrmFormatModifierFn::name().as_ptr());
+        }
         let features = vk::PhysicalDeviceFeatures::builder();
 
         let device_create_info = vk::DeviceCreateInfo::builder()
             .queue_create_infos(queue_info)
-            .enabled_extension_names(device_extensions)
+            .enabled_extension_names(&device_extensions)
             .enabled_features(&features);
 
         let device = unsafe { instance.create_device(physical_device, &device_create_info, None)? };
@@ -92,90 +131,146 @@ impl Vulkan {
         let command_buffers =
             unsafe { device.allocate_command_buffers(&command_buffer_allocate_info)? };
 
-        let buffer_info = vk::BufferCreateInfo::builder()
-            .size(BUFFER_PIXELS)
-            .usage(vk::BufferUsageFlags::TRANSFER_DST)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE);
-
-        let buffer = unsafe { device.create_buffer(&buffer_info, None)? };
-
-        let buffer_memory_req = unsafe { device.get_buffer_memory_requirements(buffer) };
-
         let device_memory_properties =
             unsafe { instance.get_physical_device_memory_properties(physical_device) };
 
-        let memory_type_index = find_memory_type_index(
-            &buffer_memory_req,
-            &device_memory_properties,
-            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-        )
-        .ok_or("Unable to find suitable memory type for the buffer")?;
-
-        let allocate_info = vk::MemoryAllocateInfo {
-            allocation_size: buffer_memory_req.size,
-            memory_type_index,
-            ..Default::default()
-        };
-
-        let buffer_memory = unsafe { device.allocate_memory(&allocate_info, None)? };
-        unsafe {
-            device.bind_buffer_memory(buffer, buffer_memory, 0)?;
-        }
-
         let fence_create_info = vk::FenceCreateInfo::builder();
         let fence = unsafe { device.create_fence(&fence_create_info, None)? };
 
+        let external_memory_fd = ExternalMemoryFd::new(&instance, &device);
+
         Ok(Self {
             _entry: entry,
             instance,
+            physical_device,
             device,
-            buffer,
-            buffer_memory,
+            device_memory_properties,
+            modifiers_supported,
             command_pool,
             command_buffers,
             queue,
             fence,
             image: RefCell::new(None),
             image_memory: RefCell::new(None),
-            image_resolution: RefCell::new(None),
+            image_dimensions: RefCell::new(None),
+            buffer: RefCell::new(None),
+            buffer_memory: RefCell::new(None),
+            zero_buffer: RefCell::new(None),
+            external_memory_fd,
+            exported_image: RefCell::new(None),
+            last_readback: Cell::new(None),
         })
     }
 
-    pub fn luma_percent(&self, frame: &Object) -> Result<u8, Box<dyn Error>> {
-        assert_eq!(
-            1, frame.num_objects,
-            "Frames with multiple objects are not supported yet, use WLR_DRM_NO_MODIFIERS=1 as described in README and follow issue #8"
-        );
+    // Modifiers that frames of a DRM format can be imported with, for capturers to negotiate
+    pub fn import_modifiers(&self, format: u32) -> Result<Vec<u64>, Box<dyn Error>> {
+        if !self.modifiers_supported {
+            return Ok(vec![]);
+        }
+        let format = vk_format(format)?;
 
-        if self.image.borrow().is_none() {
+        let mut modifier_list = vk::DrmFormatModifierPropertiesListEXT::default();
+        let mut format_properties = vk::FormatProperties2::builder().push_next(&mut modifier_list);
+        unsafe {
+            self.instance.get_physical_device_format_properties2(
+                self.physical_device,
+                format,
+                &mut format_properties,
+            );
+        }
+
+        let mut modifier_properties = vec![
+            vk::DrmFormatModifierPropertiesEXT::default();
+            modifier_list.drm_format_modifier_count as usize
+        ];
+        let mut modifier_list = vk::DrmFormatModifierPropertiesListEXT::builder()
+            .drm_format_modifier_properties(&mut modifier_properties);
+        let mut format_properties = vk::FormatProperties2::builder().push_next(&mut modifier_list);
+        unsafe {
+            self.instance.get_physical_device_format_properties2(
+                self.physical_device,
+                format,
+                &mut format_properties,
+            );
+        }
+
+        Ok(blittable_modifiers(&modifier_properties))
+    }
+
+    pub fn luma_percent(&self, frame: &Object, settings: &Settings) -> Result<u8, Box<dyn Error>> {
+        let (frame_image, frame_image_memories) = self.init_frame_image(frame)?;
+
+        let luma = self.frame_image_luma(frame, &frame_image, settings);
+
+        unsafe {
+            // Commands that failed or timed out might still use the frame image
+            if luma.is_err() {
+                let _ = self.device.device_wait_idle();
+            }
+            self.device.destroy_image(frame_image, None);
+            for frame_image_memory in frame_image_memories {
+                self.device.free_memory(frame_image_memory, None);
+            }
+        }
+
+        luma
+    }
+
+    fn frame_image_luma(
+        &self,
+        frame: &Object,
+        frame_image: &vk::Image,
+        settings: &Settings,
+    ) -> Result<u8, Box<dyn Error>> {
+        if needs_rebuild(*self.image_dimensions.borrow(), frame) {
+            if self.image.borrow().is_some() {
+                log::debug!(
+                    "Frame resolution changed to {}x{}, recreating Vulkan resources",
+                    frame.width,
+                    frame.height
+                );
+            }
+            self.destroy_image();
             self.init_image(frame)?;
         }
-        assert_eq!(
-            (frame.width, frame.height),
-            self.image_resolution.borrow().unwrap(),
-            "Handling screen resolution change is not supported yet"
-        );
 
         let image = self
             .image
             .borrow()
             .ok_or("Unable to borrow the Vulkan image")?;
-
-        let (frame_image, frame_image_memory) = self.init_frame_image(frame)?;
+        let buffer = self
+            .buffer
+            .borrow()
+            .ok_or("Unable to borrow the Vulkan buffer")?;
+        let buffer_memory = self
+            .buffer_memory
+            .borrow()
+            .ok_or("Unable to borrow the Vulkan buffer memory")?;
+
+        // Excluded regions are painted black on the first mip level, before they get averaged
+        let (width, height, _) = image_dimensions(frame);
+        let exclude = settings
+            .exclude
+            .iter()
+            .filter_map(|region| region.scale((frame.width, frame.height), (width, height)))
+            .collect_vec();
+        let mask = self
+            .zero_buffer(&exclude)?
+            .map(|zero_buffer| (zero_buffer, exclude.as_slice()));
 
         self.begin_commands()?;
 
         let (target_mip_level, mip_width, mip_height) =
-            self.generate_mipmaps(frame, &frame_image, &image);
+            self.generate_mipmaps(frame, frame_image, &image, mask);
 
-        self.copy_mipmap(&image, target_mip_level, mip_width, mip_height);
+        self.copy_mipmap(&image, &buffer, target_mip_level, mip_width, mip_height);
 
         self.submit_commands()?;
 
         let pixels = mip_width as usize * mip_height as usize;
         let rgbas = unsafe {
             let buffer_pointer = self.device.map_memory(
-                self.buffer_memory,
+                buffer_memory,
                 0,
                 vk::WHOLE_SIZE,
                 vk::MemoryMapFlags::empty(),
@@ -183,24 +278,158 @@ impl Vulkan {
             std::slice::from_raw_parts(buffer_pointer as *mut u8, pixels * 4)
         };
 
-        let result = compute_perceived_lightness_percent(rgbas, true, pixels);
+        // Static screens read back the same mip, which is much cheaper to hash than to analyse
+        let mut hasher = DefaultHasher::new();
+        rgbas.hash(&mut hasher);
+        let checksum = hasher.finish();
+
+        let luma =
+            match self.last_readback.get() {
+                Some((last_checksum, luma)) if last_checksum == checksum => Ok(luma),
+                _ => {
+                    // Black excluded pixels got averaged in, the rest of each pixel is recovered from
+                    // its visible part, which is also how much it counts
+                    let visible = visible_fractions(
+                        &exclude,
+                        (width, height),
+                        target_mip_level,
+                        (mip_width, mip_height),
+                    );
+                    Analysis::weighted(
+                        rgbas.chunks_exact(4).zip(visible).enumerate().map(
+                            |(i, (rgba, visible))| {
+                                let (x, y) = (i as u32 % mip_width, i as u32 / mip_width);
+                                let rgb =
+                                    [rgba[0], rgba[1], rgba[2]].map(|value| value as f64 / visible);
+                                (x, y, rgb.map(|value| value.min(255.0)), visible)
+                            },
+                        ),
+                        mip_width,
+                        mip_height,
+                    )
+                    .map(|analysis| settings.metric.value(&analysis))
+                    .ok_or("Whole frame is excluded")
+                }
+            };
 
         unsafe {
-            self.device.unmap_memory(self.buffer_memory);
-            self.device.reset_fences(&[self.fence])?;
-            self.device.destroy_image(frame_image, None);
-            self.device.free_memory(frame_image_memory, None);
+            self.device.unmap_memory(buffer_memory);
         }
 
-        Ok(result)
+        let luma = luma?;
+        self.last_readback.set(Some((checksum, luma)));
+        Ok(luma)
+    }
+
+    // Only one image is exported at a time, a new one replaces the previous
+    pub fn export_image(
+        &self,
+        width: u32,
+        height: u32,
+        format: u32,
+    ) -> Result<ExportedImage, Box<dyn Error>> {
+        self.destroy_exported_image();
+
+        let mut external_memory_info = vk::ExternalMemoryImageCreateInfo::builder()
+            .handle_types(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT);
+
+        // Linear tiling is what DRM_FORMAT_MOD_LINEAR describes, which every compositor accepts
+        let image_create_info = vk::ImageCreateInfo::builder()
+            .push_next(&mut external_memory_info)
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(vk_format(format)?)
+            .extent(vk::Extent3D {
+                width,
+                height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .tiling(vk::ImageTiling::LINEAR)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .usage(vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+        let image = unsafe { self.device.create_image(&image_create_info, None)? };
+        let image_memory_req = unsafe { self.device.get_image_memory_requirements(image) };
+
+        let memory_type_index = find_memory_type_index(
+            &image_memory_req,
+            &self.device_memory_properties,
+            vk::MemoryPropertyFlags::empty(),
+        );
+        let memory_type_index = match memory_type_index {
+            Some(index) => index,
+            None => {
+                unsafe { self.device.destroy_image(image, None) };
+                return Err("Unable to find memory type to export".into());
+            }
+        };
+
+        let mut export_memory_info = vk::ExportMemoryAllocateInfo::builder()
+            .handle_types(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT);
+        let mut dedicated_memory_info = vk::MemoryDedicatedAllocateInfo::builder().image(image);
+        let image_allocate_info = vk::MemoryAllocateInfo::builder()
+            .push_next(&mut export_memory_info)
+            .push_next(&mut dedicated_memory_info)
+            .allocation_size(image_memory_req.size)
+            .memory_type_index(memory_type_index);
+
+        let image_memory = match unsafe { self.device.allocate_memory(&image_allocate_info, None) }
+        {
+            Ok(memory) => memory,
+            Err(err) => {
+                unsafe { self.device.destroy_image(image, None) };
+                return Err(err.into());
+            }
+        };
+        self.exported_image
+            .borrow_mut()
+            .replace((image, image_memory));
+
+        unsafe { self.device.bind_image_memory(image, image_memory, 0)? };
+
+        let layout = unsafe {
+            self.device.get_image_subresource_layout(
+                image,
+                vk::ImageSubresource {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    array_layer: 0,
+                },
+            )
+        };
+
+        let get_fd_info = vk::MemoryGetFdInfoKHR::builder()
+            .memory(image_memory)
+            .handle_type(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT);
+        let fd = unsafe { self.external_memory_fd.get_memory_fd(&get_fd_info)? };
+
+        Ok(ExportedImage {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            size: image_memory_req.size.try_into()?,
+            offset: layout.offset.try_into()?,
+            stride: layout.row_pitch.try_into()?,
+        })
+    }
+
+    fn destroy_exported_image(&self) {
+        if let Some((image, image_memory)) = self.exported_image.borrow_mut().take() {
+            unsafe {
+                self.device.destroy_image(image, None);
+                self.device.free_memory(image_memory, None);
+            }
+        }
     }
 
     fn init_image(&self, frame: &Object) -> Result<(), Box<dyn Error>> {
         let (width, height, mip_levels) = image_dimensions(frame);
 
+        // Blits convert from the frame format, so the readback is always in RGBA order
         let image_create_info = vk::ImageCreateInfo::builder()
             .image_type(vk::ImageType::TYPE_2D)
-            .format(vk::Format::B8G8R8A8_UNORM)
+            .format(vk::Format::R8G8B8A8_UNORM)
             .extent(vk::Extent3D {
                 width,
                 height,
@@ -227,25 +456,172 @@ impl Vulkan {
             self.device.bind_image_memory(image, image_memory, 0)?;
         }
 
+        let (_, buffer_width, buffer_height) = readback_mip_dimensions(width, height, mip_levels);
+
+        let (buffer, buffer_memory) = self.create_host_buffer(
+            buffer_width as u64 * buffer_height as u64 * 4,
+            vk::BufferUsageFlags::TRANSFER_DST,
+        )?;
+
         self.image.borrow_mut().replace(image);
         self.image_memory.borrow_mut().replace(image_memory);
-        self.image_resolution
+        self.image_dimensions
             .borrow_mut()
-            .replace((frame.width, frame.height));
+            .replace((width, height, mip_levels));
+        self.buffer.borrow_mut().replace(buffer);
+        self.buffer_memory.borrow_mut().replace(buffer_memory);
         Ok(())
     }
 
+    fn create_host_buffer(
+        &self,
+        size: u64,
+        usage: vk::BufferUsageFlags,
+    ) -> Result<(vk::Buffer, vk::DeviceMemory), Box<dyn Error>> {
+        let buffer_info = vk::BufferCreateInfo::builder()
+            .size(size)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+        let buffer = unsafe { self.device.create_buffer(&buffer_info, None)? };
+
+        let buffer_memory_req = unsafe { self.device.get_buffer_memory_requirements(buffer) };
+
+        let memory_type_index = find_memory_type_index(
+            &buffer_memory_req,
+            &self.device_memory_properties,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )
+        .ok_or("Unable to find suitable memory type for the buffer")?;
+
+        let allocate_info = vk::MemoryAllocateInfo {
+            allocation_size: buffer_memory_req.size,
+            memory_type_index,
+            ..Default::default()
+        };
+
+        let buffer_memory = unsafe { self.device.allocate_memory(&allocate_info, None)? };
+        unsafe {
+            self.device.bind_buffer_memory(buffer, buffer_memory, 0)?;
+        }
+
+        Ok((buffer, buffer_memory))
+    }
+
+    // Source of black pixels for excluded regions, big enough for the largest one of them
+    fn zero_buffer(&self, regions: &[Region]) -> Result<Option<vk::Buffer>, Box<dyn Error>> {
+        let size = match regions
+            .iter()
+            .map(|region| region.width as u64 * region.height as u64 * 4)
+            .max()
+        {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+
+        if let Some((buffer, _, capacity)) = *self.zero_buffer.borrow() {
+            if capacity >= size {
+                return Ok(Some(buffer));
+            }
+        }
+
+        self.destroy_zero_buffer();
+        let (buffer, buffer_memory) =
+            self.create_host_buffer(size, vk::BufferUsageFlags::TRANSFER_SRC)?;
+
+        unsafe {
+            let buffer_pointer = self.device.map_memory(
+                buffer_memory,
+                0,
+                vk::WHOLE_SIZE,
+                vk::MemoryMapFlags::empty(),
+            )?;
+            std::ptr::write_bytes(buffer_pointer as *mut u8, 0, size as usize);
+            self.device.unmap_memory(buffer_memory);
+        }
+
+        self.zero_buffer
+            .borrow_mut()
+            .replace((buffer, buffer_memory, size));
+        Ok(Some(buffer))
+    }
+
+    fn destroy_zero_buffer(&self) {
+        if let Some((buffer, buffer_memory, _)) = self.zero_buffer.borrow_mut().take() {
+            unsafe {
+                self.device.destroy_buffer(buffer, None);
+                self.device.free_memory(buffer_memory, None);
+            }
+        }
+    }
+
+    fn destroy_image(&self) {
+        unsafe {
+            if let Some(image) = self.image.borrow_mut().take() {
+                self.device.destroy_image(image, None);
+            }
+            if let Some(image_memory) = self.image_memory.borrow_mut().take() {
+                self.device.free_memory(image_memory, None);
+            }
+            if let Some(buffer) = self.buffer.borrow_mut().take() {
+                self.device.destroy_buffer(buffer, None);
+            }
+            if let Some(buffer_memory) = self.buffer_memory.borrow_mut().take() {
+                self.device.free_memory(buffer_memory, None);
+            }
+        }
+        self.image_dimensions.borrow_mut().take();
+        self.last_readback.take();
+    }
+
     fn init_frame_image(
         &self,
         frame: &Object,
-    ) -> Result<(vk::Image, vk::DeviceMemory), Box<dyn Error>> {
+    ) -> Result<(vk::Image, Vec<vk::DeviceMemory>), Box<dyn Error>> {
+        // Every fd is closed when dropped, unless Vulkan imports it and takes ownership
+        let mut fds = frame
+            .fds
+            .iter()
+            .map(|&fd| Some(unsafe { OwnedFd::from_raw_fd(fd) }))
+            .collect_vec();
+
+        let format = vk_format(frame.format)?;
+
+        // Objects are not guaranteed to arrive in plane order
+        let planes = (0..frame.num_objects as usize)
+            .sorted_by_key(|&i| frame.plane_indices[i])
+            .collect_vec();
+
+        // Planes can either share one DMA-BUF (e.g. compression metadata next to the main surface),
+        // or live in separate ones, in which case each plane is bound to its own memory
+        let disjoint = planes.len() > 1 && !is_single_buffer(&frame.fds)?;
+
+        let plane_layouts = planes
+            .iter()
+            .map(|&i| vk::SubresourceLayout {
+                offset: frame.offsets[i].into(),
+                row_pitch: frame.strides[i].into(),
+                ..Default::default()
+            })
+            .collect_vec();
+
         let mut frame_image_memory_info = vk::ExternalMemoryImageCreateInfo::builder()
             .handle_types(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT);
 
-        let frame_image_create_info = vk::ImageCreateInfo::builder()
+        let mut frame_image_modifier_info =
+            vk::ImageDrmFormatModifierExplicitCreateInfoEXT::builder()
+                .drm_format_modifier(frame.modifier)
+                .plane_layouts(&plane_layouts);
+
+        let mut frame_image_create_info = vk::ImageCreateInfo::builder()
             .push_next(&mut frame_image_memory_info)
+            .flags(if disjoint {
+                vk::ImageCreateFlags::DISJOINT
+            } else {
+                vk::ImageCreateFlags::empty()
+            })
             .image_type(vk::ImageType::TYPE_2D)
-            .format(vk::Format::R8G8B8A8_UNORM)
+            .format(format)
             .extent(vk::Extent3D {
                 width: frame.width,
                 height: frame.height,
@@ -259,28 +635,92 @@ impl Vulkan {
             .usage(vk::ImageUsageFlags::TRANSFER_SRC)
             .sharing_mode(vk::SharingMode::EXCLUSIVE);
 
-        let frame_image = unsafe { self.device.create_image(&frame_image_create_info, None)? };
+        if frame.has_explicit_modifier() && self.modifiers_supported {
+            frame_image_create_info = frame_image_create_info
+                .push_next(&mut frame_image_modifier_info)
+                .tiling(vk::ImageTiling::DRM_FORMAT_MODIFIER_EXT);
+        }
 
-        let mut frame_import_memory_info = vk::ImportMemoryFdInfoKHR::builder()
-            .handle_type(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT)
-            .fd(frame.fds[0]);
+        let frame_image = unsafe { self.device.create_image(&frame_image_create_info, None)? };
 
-        let frame_image_allocate_info = vk::MemoryAllocateInfo::builder()
-            .push_next(&mut frame_import_memory_info)
-            .allocation_size(frame.sizes[0].into())
-            .memory_type_index(0);
+        let memory_planes = if disjoint { planes } else { vec![planes[0]] };
+
+        let mut frame_image_memories = Vec::with_capacity(memory_planes.len());
+        let imported = memory_planes.iter().try_for_each(|&i| {
+            let mut frame_import_memory_info = vk::ImportMemoryFdInfoKHR::builder()
+                .handle_type(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT)
+                .fd(frame.fds[i]);
+
+            let frame_image_allocate_info = vk::MemoryAllocateInfo::builder()
+                .push_next(&mut frame_import_memory_info)
+                .allocation_size(frame.sizes[i].into())
+                .memory_type_index(0);
+
+            let memory = unsafe {
+                self.device
+                    .allocate_memory(&frame_image_allocate_info, None)?
+            };
+            if let Some(fd) = fds[i].take() {
+                let _ = fd.into_raw_fd();
+            }
+            frame_image_memories.push(memory);
+            Ok(())
+        });
+
+        let bound = imported
+            .and_then(|_| self.bind_frame_image(frame_image, &frame_image_memories, disjoint));
+        if let Err(err) = bound {
+            unsafe {
+                self.device.destroy_image(frame_image, None);
+                for frame_image_memory in frame_image_memories {
+                    self.device.free_memory(frame_image_memory, None);
+                }
+            }
+            return Err(err);
+        }
 
-        let frame_image_memory = unsafe {
-            self.device
-                .allocate_memory(&frame_image_allocate_info, None)?
-        };
+        Ok((frame_image, frame_image_memories))
+    }
 
-        unsafe {
-            self.device
-                .bind_image_memory(frame_image, frame_image_memory, 0)?;
+    fn bind_frame_image(
+        &self,
+        frame_image: vk::Image,
+        frame_image_memories: &[vk::DeviceMemory],
+        disjoint: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if disjoint {
+            let mut bind_plane_infos = (0..frame_image_memories.len())
+                .map(|plane| {
+                    vk::BindImagePlaneMemoryInfo::builder()
+                        .plane_aspect(memory_plane_aspect(plane))
+                        .build()
+                })
+                .collect_vec();
+
+            let bind_infos = frame_image_memories
+                .iter()
+                .zip(bind_plane_infos.iter_mut())
+                .map(|(memory, bind_plane_info)| {
+                    vk::BindImageMemoryInfo::builder()
+                        .push_next(bind_plane_info)
+                        .image(frame_image)
+                        .memory(*memory)
+                        .memory_offset(0)
+                        .build()
+                })
+                .collect_vec();
+
+            unsafe {
+                self.device.bind_image_memory2(&bind_infos)?;
+            }
+        } else {
+            unsafe {
+                self.device
+                    .bind_image_memory(frame_image, frame_image_memories[0], 0)?;
+            }
         }
 
-        Ok((frame_image, frame_image_memory))
+        Ok(())
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -385,6 +825,7 @@ impl Vulkan {
         frame: &Object,
         frame_image: &vk::Image,
         image: &vk::Image,
+        mask: Option<(vk::Buffer, &[Region])>,
     ) -> (u32, u32, u32) {
         let (mut mip_width, mut mip_height, mip_levels) = image_dimensions(frame);
 
@@ -421,7 +862,11 @@ impl Vulkan {
             0,
         );
 
-        let target_mip_level = mip_levels - FINAL_MIP_LEVEL;
+        if let Some((zero_buffer, regions)) = mask {
+            self.mask(image, zero_buffer, regions);
+        }
+
+        let target_mip_level = mip_levels - 1;
         for i in 1..=target_mip_level {
             self.add_barrier(
                 image,
@@ -455,7 +900,63 @@ impl Vulkan {
         (target_mip_level, mip_width, mip_height)
     }
 
-    fn copy_mipmap(&self, image: &vk::Image, mip_level: u32, width: u32, height: u32) {
+    fn mask(&self, image: &vk::Image, zero_buffer: vk::Buffer, regions: &[Region]) {
+        // The blit has to land before the excluded regions are painted over
+        self.add_barrier(
+            image,
+            0,
+            1,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::TRANSFER,
+        );
+
+        let copies = regions
+            .iter()
+            .map(|region| {
+                vk::BufferImageCopy::builder()
+                    .image_subresource(
+                        vk::ImageSubresourceLayers::builder()
+                            .aspect_mask(vk::ImageAspectFlags::COLOR)
+                            .mip_level(0)
+                            .layer_count(1)
+                            .build(),
+                    )
+                    .image_offset(vk::Offset3D {
+                        x: region.x as i32,
+                        y: region.y as i32,
+                        z: 0,
+                    })
+                    .image_extent(vk::Extent3D {
+                        width: region.width,
+                        height: region.height,
+                        depth: 1,
+                    })
+                    .build()
+            })
+            .collect_vec();
+
+        unsafe {
+            self.device.cmd_copy_buffer_to_image(
+                self.command_buffers[0],
+                zero_buffer,
+                *image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &copies,
+            );
+        }
+    }
+
+    fn copy_mipmap(
+        &self,
+        image: &vk::Image,
+        buffer: &vk::Buffer,
+        mip_level: u32,
+        width: u32,
+        height: u32,
+    ) {
         self.add_barrier(
             image,
             mip_level,
@@ -487,7 +988,7 @@ impl Vulkan {
                 self.command_buffers[0],
                 *image,
                 vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
-                self.buffer,
+                *buffer,
                 &[buffer_image_copy.build()],
             );
         }
@@ -513,6 +1014,8 @@ impl Vulkan {
         let submit_info = vk::SubmitInfo::builder().command_buffers(&self.command_buffers);
 
         unsafe {
+            // Reset here, a previous wait might have failed before the fence was signaled
+            self.device.reset_fences(&[self.fence])?;
             self.device
                 .queue_submit(self.queue, &[submit_info.build()], self.fence)?;
             self.device
@@ -529,17 +1032,14 @@ impl Drop for Vulkan {
             self.device
                 .device_wait_idle()
                 .expect("Unable to wait for device to become idle");
+        }
 
-            if let Some(image) = *self.image.borrow() {
-                self.device.destroy_image(image, None);
-            }
-            if let Some(image_memory) = *self.image_memory.borrow() {
-                self.device.free_memory(image_memory, None);
-            }
+        self.destroy_image();
+        self.destroy_zero_buffer();
+        self.destroy_exported_image();
 
+        unsafe {
             self.device.destroy_fence(self.fence, None);
-            self.device.destroy_buffer(self.buffer, None);
-            self.device.free_memory(self.buffer_memory, None);
             self.device
                 .free_command_buffers(self.command_pool, &self.command_buffers);
             self.device.destroy_command_pool(self.command_pool, None);
@@ -553,7 +1053,106 @@ fn image_dimensions(frame: &Object) -> (u32, u32, u32) {
     let width = frame.width / 2;
     let height = frame.height / 2;
     let mip_levels = f64::max(width.into(), height.into()).log2().floor() as u32 + 1;
-    (width, height, mip_levels)
+    (width, height, mip_levels.min(READBACK_MIP_LEVEL + 1))
+}
+
+fn needs_rebuild(current_dimensions: Option<(u32, u32, u32)>, frame: &Object) -> bool {
+    current_dimensions != Some(image_dimensions(frame))
+}
+
+fn readback_mip_dimensions(width: u32, height: u32, mip_levels: u32) -> (u32, u32, u32) {
+    let target_mip_level = mip_levels - 1;
+    let mip_width = (width >> target_mip_level).max(1);
+    let mip_height = (height >> target_mip_level).max(1);
+    (target_mip_level, mip_width, mip_height)
+}
+
+// Part of each pixel read back that is not excluded, given regions on the first mip level
+fn visible_fractions(
+    exclude: &[Region],
+    (width, height): (u32, u32),
+    mip_level: u32,
+    (mip_width, mip_height): (u32, u32),
+) -> Vec<f64> {
+    let mut fractions = vec![1.0; mip_width as usize * mip_height as usize];
+    let block = 1 << mip_level;
+
+    for region in exclude
+        .iter()
+        .filter_map(|region| region.scale((width, height), (mip_width, mip_height)))
+    {
+        for (x, y) in (region.y..region.y + region.height)
+            .flat_map(|y| (region.x..region.x + region.width).map(move |x| (x, y)))
+        {
+            let pixels = (y * block..((y + 1) * block).min(height))
+                .flat_map(|py| (x * block..((x + 1) * block).min(width)).map(move |px| (px, py)))
+                .collect_vec();
+            let visible = pixels
+                .iter()
+                .filter(|(px, py)| !exclude.iter().any(|e| e.contains(*px, *py)))
+                .count();
+            fractions[(y * mip_width + x) as usize] = if pixels.is_empty() {
+                0.0
+            } else {
+                visible as f64 / pixels.len() as f64
+            };
+        }
+    }
+
+    fractions
+}
+
+// Vulkan formats name components from the most significant bit, DRM fourccs from the least,
+// e.g. XR24 is stored as B, G, R, X bytes which is B8G8R8A8 in Vulkan
+pub fn vk_format(fourcc: u32) -> Result<vk::Format, Box<dyn Error>> {
+    match &fourcc.to_le_bytes() {
+        b"XR24" | b"AR24" => Ok(vk::Format::B8G8R8A8_UNORM),
+        b"XB24" | b"AB24" => Ok(vk::Format::R8G8B8A8_UNORM),
+        b"XR30" | b"AR30" => Ok(vk::Format::A2R10G10B10_UNORM_PACK32),
+        b"XB30" | b"AB30" => Ok(vk::Format::A2B10G10R10_UNORM_PACK32),
+        b"XB48" | b"AB48" => Ok(vk::Format::R16G16B16A16_UNORM),
+        b"XB4H" | b"AB4H" => Ok(vk::Format::R16G16B16A16_SFLOAT),
+        bytes => Err(format!(
+            "Unsupported frame format '{}'",
+            String::from_utf8_lossy(bytes)
+        )
+        .into()),
+    }
+}
+
+// Frames are only ever blitted from, into the image that mipmaps are generated in
+fn blittable_modifiers(properties: &[vk::DrmFormatModifierPropertiesEXT]) -> Vec<u64> {
+    properties
+        .iter()
+        .filter(|properties| {
+            properties
+                .drm_format_modifier_tiling_features
+                .contains(vk::FormatFeatureFlags::BLIT_SRC)
+        })
+        .map(|properties| properties.drm_format_modifier)
+        .collect()
+}
+
+fn is_single_buffer(fds: &[RawFd]) -> Result<bool, Box<dyn Error>> {
+    let inodes = fds
+        .iter()
+        .map(|&fd| {
+            // Only peek at the fd, its ownership is decided later
+            let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+            file.metadata().map(|metadata| metadata.ino())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(inodes.iter().all_equal())
+}
+
+fn memory_plane_aspect(plane: usize) -> vk::ImageAspectFlags {
+    match plane {
+        0 => vk::ImageAspectFlags::MEMORY_PLANE_0_EXT,
+        1 => vk::ImageAspectFlags::MEMORY_PLANE_1_EXT,
+        2 => vk::ImageAspectFlags::MEMORY_PLANE_2_EXT,
+        _ => vk::ImageAspectFlags::MEMORY_PLANE_3_EXT,
+    }
 }
 
 fn find_memory_type_index(
@@ -570,3 +1169,105 @@ fn find_memory_type_index(
         })
         .map(|(index, _)| index as _)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(width: u32, height: u32) -> Object {
+        let mut frame = Object::default();
+        frame.set_metadata(width, height, 0, 0, 1);
+        frame
+    }
+
+    #[test]
+    fn test_image_dimensions() {
+        assert_eq!((960, 540, 2), image_dimensions(&frame(1920, 1080)));
+        assert_eq!((540, 960, 2), image_dimensions(&frame(1080, 1920)));
+        assert_eq!((1920, 1200, 2), image_dimensions(&frame(3840, 2400)));
+        assert_eq!((1, 1, 1), image_dimensions(&frame(2, 3)));
+    }
+
+    #[test]
+    fn test_vk_format() {
+        let fourcc = |code: &[u8; 4]| u32::from_le_bytes(*code);
+
+        assert!(vk_format(fourcc(b"XR24")).unwrap() == vk::Format::B8G8R8A8_UNORM);
+        assert!(vk_format(fourcc(b"AB24")).unwrap() == vk::Format::R8G8B8A8_UNORM);
+        assert!(vk_format(fourcc(b"XR30")).unwrap() == vk::Format::A2R10G10B10_UNORM_PACK32);
+        assert_eq!(0x34325258, fourcc(b"XR24"));
+        assert!(vk_format(fourcc(b"NV12")).is_err());
+    }
+
+    #[test]
+    fn test_blittable_modifiers() {
+        let properties = |modifier, features| vk::DrmFormatModifierPropertiesEXT {
+            drm_format_modifier: modifier,
+            drm_format_modifier_plane_count: 1,
+            drm_format_modifier_tiling_features: features,
+        };
+
+        assert_eq!(
+            vec![0, 0x0100_0000_0000_0002],
+            blittable_modifiers(&[
+                properties(0, vk::FormatFeatureFlags::BLIT_SRC),
+                properties(0x0100_0000_0000_0001, vk::FormatFeatureFlags::TRANSFER_SRC),
+                properties(
+                    0x0100_0000_0000_0002,
+                    vk::FormatFeatureFlags::BLIT_SRC | vk::FormatFeatureFlags::TRANSFER_SRC
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_needs_rebuild_when_no_image_exists_yet() {
+        assert!(needs_rebuild(None, &frame(1920, 1080)));
+    }
+
+    #[test]
+    fn test_needs_rebuild_when_resolution_changes() {
+        let current = Some(image_dimensions(&frame(1920, 1080)));
+
+        assert!(!needs_rebuild(current, &frame(1920, 1080)));
+        assert!(needs_rebuild(current, &frame(1080, 1920)));
+        assert!(needs_rebuild(current, &frame(2560, 1440)));
+        assert!(needs_rebuild(current, &frame(1280, 720)));
+    }
+
+    #[test]
+    fn test_needs_rebuild_ignores_odd_pixel_difference() {
+        // Image is half the frame size, so both frames map to the same image
+        let current = Some(image_dimensions(&frame(1920, 1080)));
+
+        assert!(!needs_rebuild(current, &frame(1921, 1081)));
+    }
+
+    #[test]
+    fn test_readback_mip_dimensions() {
+        assert_eq!((1, 480, 270), readback_mip_dimensions(960, 540, 2));
+        assert_eq!((1, 2, 1), readback_mip_dimensions(4, 1, 2));
+        assert_eq!((0, 1, 1), readback_mip_dimensions(1, 1, 1));
+    }
+
+    #[test]
+    fn test_visible_fractions() {
+        // Two columns of an 8x4 image are excluded, which covers half of the second pixel
+        let exclude = [Region::new(3, 0, 2, 4)];
+
+        assert_eq!(
+            vec![1.0, 0.5, 0.5, 1.0, 1.0, 0.5, 0.5, 1.0],
+            visible_fractions(&exclude, (8, 4), 1, (4, 2))
+        );
+    }
+
+    #[test]
+    fn test_visible_fractions_of_overlapping_regions() {
+        let exclude = [Region::new(0, 0, 2, 1), Region::new(1, 0, 1, 2)];
+
+        assert_eq!(
+            vec![0.25, 1.0],
+            visible_fractions(&exclude, (4, 2), 1, (2, 1))
+        );
+    }
+}
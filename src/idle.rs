@@ -0,0 +1,290 @@
+use crate::protocols::ext_idle_notify::client::{
+    ext_idle_notification_v1, ext_idle_notifier_v1::ExtIdleNotifierV1,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::{cell::RefCell, rc::Rc, thread, time::Duration};
+use wayland_client::{
+    protocol::{wl_output::WlOutput, wl_seat::WlSeat},
+    Display, GlobalEvent, GlobalManager,
+};
+use wayland_protocols::unstable::xdg_output::v1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1;
+use wayland_protocols::unstable::xdg_output::v1::client::zxdg_output_v1::Event::Description;
+use wayland_protocols::wlr::unstable::output_power_management::v1::client::{
+    zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1,
+    zwlr_output_power_v1::{self, Mode},
+};
+
+// Output and whether it needs to stop adapting brightness
+type Subscription = (String, Sender<bool>);
+
+struct Subscriber {
+    output_name: String,
+    paused_tx: Sender<bool>,
+    paused: Option<bool>,
+}
+
+#[derive(Default)]
+struct Output {
+    description: Option<String>,
+    off: bool,
+}
+
+// What is known about the session, keyed by wl_output global names
+#[derive(Default)]
+struct State {
+    idle: bool,
+    outputs: HashMap<u32, Output>,
+    subscribers: Vec<Subscriber>,
+}
+
+impl State {
+    // Shared by the Wayland loop and the thread that takes new subscribers
+    fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+        state.lock().expect("Idle watcher panicked")
+    }
+
+    fn paused(&self, output_name: &str) -> bool {
+        self.idle
+            || self.outputs.values().any(|output| {
+                output.off
+                    && output
+                        .description
+                        .as_ref()
+                        .is_some_and(|description| description.contains(output_name))
+            })
+    }
+
+    // Only changes are sent, a subscriber that is gone belonged to a disconnected output
+    fn notify(&mut self) {
+        let subscribers = std::mem::take(&mut self.subscribers);
+        self.subscribers = subscribers
+            .into_iter()
+            .filter_map(|mut subscriber| {
+                let paused = self.paused(&subscriber.output_name);
+                if subscriber.paused != Some(paused) {
+                    subscriber.paused = Some(paused);
+                    subscriber.paused_tx.send(paused).ok()?;
+                }
+                Some(subscriber)
+            })
+            .collect();
+    }
+}
+
+pub struct Watcher {
+    subscribe_rx: Receiver<Subscription>,
+    timeout: Duration,
+}
+
+impl Watcher {
+    pub fn new(subscribe_rx: Receiver<Subscription>, timeout: Duration) -> Self {
+        Self {
+            subscribe_rx,
+            timeout,
+        }
+    }
+
+    pub fn run(self) -> Result<(), Box<dyn Error>> {
+        let display = Display::connect_to_env()?;
+        let mut event_queue = display.create_event_queue();
+        let attached_display = display.attach(event_queue.token());
+        let registry = attached_display.get_registry();
+
+        let state = Arc::new(Mutex::new(State::default()));
+
+        // Wayland loop blocks until there are events, so subscribers are taken in on their own
+        {
+            let state = state.clone();
+            let subscribe_rx = self.subscribe_rx;
+            thread::Builder::new()
+                .name("idle-subscribe".to_string())
+                .spawn(move || {
+                    for (output_name, paused_tx) in subscribe_rx {
+                        let mut state = State::lock(&state);
+                        state.subscribers.push(Subscriber {
+                            output_name,
+                            paused_tx,
+                            paused: None,
+                        });
+                        state.notify();
+                    }
+                })?;
+        }
+
+        // Outputs come and go, they are bound in the main loop once all managers are known
+        let added = Rc::new(RefCell::new(Vec::new()));
+        let globals = {
+            let added = added.clone();
+            let state = state.clone();
+            GlobalManager::new_with_cb(&attached_display, move |event, _, _| match event {
+                GlobalEvent::New { id, interface, .. } if interface == "wl_output" => {
+                    added.borrow_mut().push(id);
+                }
+                GlobalEvent::Removed { id, interface } if interface == "wl_output" => {
+                    State::lock(&state).outputs.remove(&id);
+                }
+                _ => {}
+            })
+        };
+
+        event_queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+
+        let xdg_output_manager = globals.instantiate_exact::<ZxdgOutputManagerV1>(3)?;
+        let power_manager = match globals.instantiate_exact::<ZwlrOutputPowerManagerV1>(1) {
+            Ok(power_manager) => Some(power_manager),
+            Err(_) => {
+                log::warn!("Output power management is not supported, DPMS will be ignored");
+                None
+            }
+        };
+
+        // Has to be shorter than the timeout after which swayidle and alike start dimming the screen
+        let _idle_notification = match globals.instantiate_exact::<ExtIdleNotifierV1>(1) {
+            Ok(notifier) if !self.timeout.is_zero() => {
+                let seat = globals.instantiate_exact::<WlSeat>(1)?;
+                let notification =
+                    notifier.get_idle_notification(self.timeout.as_millis() as u32, &seat);
+                let state = state.clone();
+                notification.quick_assign(move |_, event, _| match event {
+                    ext_idle_notification_v1::Event::Idled => {
+                        log::debug!("Session is idle");
+                        State::lock(&state).idle = true;
+                    }
+                    ext_idle_notification_v1::Event::Resumed => {
+                        log::debug!("Session is active again");
+                        State::lock(&state).idle = false;
+                    }
+                });
+                Some(notification)
+            }
+            Ok(_) => None,
+            Err(_) => {
+                log::warn!("Idle notifications are not supported, idle session will be ignored");
+                None
+            }
+        };
+
+        loop {
+            for id in added.borrow_mut().drain(..) {
+                let output = registry.bind::<WlOutput>(1, id);
+                State::lock(&state).outputs.insert(id, Output::default());
+
+                let description_state = state.clone();
+                xdg_output_manager
+                    .get_xdg_output(&output)
+                    .quick_assign(move |_, event, _| {
+                        if let Description { description } = event {
+                            if let Some(output) =
+                                State::lock(&description_state).outputs.get_mut(&id)
+                            {
+                                output.description = Some(description);
+                            }
+                        }
+                    });
+
+                if let Some(power_manager) = &power_manager {
+                    let power_state = state.clone();
+                    power_manager
+                        .get_output_power(&output)
+                        .quick_assign(move |power, event, _| match event {
+                            zwlr_output_power_v1::Event::Mode { mode } => {
+                                if let Some(output) = State::lock(&power_state).outputs.get_mut(&id)
+                                {
+                                    output.off = mode == Mode::Off;
+                                }
+                            }
+                            zwlr_output_power_v1::Event::Failed => power.destroy(),
+                            _ => {}
+                        });
+                }
+            }
+
+            event_queue.dispatch(&mut (), |_, _, _| {})?;
+            State::lock(&state).notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn subscribe(state: &mut State, output_name: &str) -> Receiver<bool> {
+        let (paused_tx, paused_rx) = mpsc::channel();
+        state.subscribers.push(Subscriber {
+            output_name: output_name.to_string(),
+            paused_tx,
+            paused: None,
+        });
+        paused_rx
+    }
+
+    fn output(description: &str, off: bool) -> Output {
+        Output {
+            description: Some(description.to_string()),
+            off,
+        }
+    }
+
+    #[test]
+    fn test_idle_pauses_all_outputs() {
+        let mut state = State::default();
+        let edp = subscribe(&mut state, "eDP-1");
+        let dell = subscribe(&mut state, "DELL");
+
+        state.notify();
+        state.idle = true;
+        state.notify();
+
+        assert_eq!(vec![false, true], edp.try_iter().collect::<Vec<_>>());
+        assert_eq!(vec![false, true], dell.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_powered_off_output_pauses_only_itself() {
+        let mut state = State::default();
+        state
+            .outputs
+            .insert(1, output("eDP-1 'Sharp' (eDP-1)", false));
+        state
+            .outputs
+            .insert(2, output("DP-1 'Dell Inc. DELL P2415Q' (DP-1)", true));
+        let edp = subscribe(&mut state, "eDP-1");
+        let dell = subscribe(&mut state, "DELL P2415Q");
+
+        state.notify();
+
+        assert_eq!(vec![false], edp.try_iter().collect::<Vec<_>>());
+        assert_eq!(vec![true], dell.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_notify_sends_only_changes() {
+        let mut state = State::default();
+        let edp = subscribe(&mut state, "eDP-1");
+
+        state.notify();
+        state.notify();
+        state.idle = true;
+        state.notify();
+        state.notify();
+        state.idle = false;
+        state.notify();
+
+        assert_eq!(vec![false, true, false], edp.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_notify_forgets_dead_subscribers() {
+        let mut state = State::default();
+        drop(subscribe(&mut state, "eDP-1"));
+
+        state.notify();
+
+        assert!(state.subscribers.is_empty());
+    }
+}
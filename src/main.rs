@@ -1,12 +1,21 @@
-use itertools::Itertools;
+// Needed by the generated bindings in `protocols`
+#[macro_use]
+extern crate bitflags;
+
+use std::collections::HashMap;
+use std::error::Error;
 use std::sync::mpsc;
+use std::time::Duration;
 
 mod als;
 mod brightness;
 mod config;
 mod device_file;
 mod frame;
+mod idle;
 mod predictor;
+mod protocols;
+mod supervisor;
 
 fn main() {
     let panic_hook = std::panic::take_hook();
@@ -27,111 +36,233 @@ fn main() {
 
     log::debug!("Using {:#?}", config);
 
-    let als_txs = config
-        .output
-        .iter()
-        .filter_map(|output| {
-            let output = output.clone();
-
-            let (als_tx, als_rx) = mpsc::channel();
-            let (user_tx, user_rx) = mpsc::channel();
-            let (prediction_tx, prediction_rx) = mpsc::channel();
+    let (als_subscribe_tx, als_subscribe_rx) = mpsc::channel();
 
-            let (output_name, output_capturer) = match output.clone() {
-                config::Output::Backlight(cfg) => (cfg.name, cfg.capturer),
-                config::Output::DdcUtil(cfg) => (cfg.name, cfg.capturer),
-            };
+    std::thread::Builder::new()
+        .name("als".to_string())
+        .spawn(move || {
+            let sources = config
+                .als
+                .into_iter()
+                .enumerate()
+                .filter_map(|(i, source)| {
+                    let color_thresholds = match &source.als {
+                        config::Als::Iio {
+                            color_thresholds, ..
+                        } => color_thresholds.clone(),
+                        _ => HashMap::new(),
+                    };
+                    match als_source(source.als, &config.als_smoothing.hysteresis) {
+                        Ok((als, scale)) => Some(
+                            als::controller::Source::new(
+                                als,
+                                scale,
+                                source.weight,
+                                source.priority,
+                            )
+                            .with_color_thresholds(color_thresholds),
+                        ),
+                        Err(err) => {
+                            log::error!("Unable to initialize ALS source #{}: {}", i + 1, err);
+                            None
+                        }
+                    }
+                })
+                .collect();
 
-            let brightness = match output {
-                config::Output::Backlight(cfg) => {
-                    brightness::Backlight::new(&cfg.path, cfg.min_brightness)
-                        .map(|b| Box::new(b) as Box<dyn brightness::Brightness + Send>)
-                }
-                config::Output::DdcUtil(cfg) => {
-                    brightness::DdcUtil::new(&cfg.name, cfg.min_brightness)
-                        .map(|b| Box::new(b) as Box<dyn brightness::Brightness + Send>)
-                }
+            let filter = match config.als_smoothing.filter {
+                config::AlsFilter::None => als::controller::Filter::None,
+                config::AlsFilter::Ema { alpha } => als::controller::Filter::ema(alpha),
+                config::AlsFilter::Median { window } => als::controller::Filter::median(window),
             };
 
-            match brightness {
-                Ok(b) => {
-                    let thread_name = format!("backlight-{}", output_name);
-                    std::thread::Builder::new()
-                        .name(thread_name.clone())
-                        .spawn(move || {
-                            brightness::Controller::new(b, user_tx, prediction_rx).run();
-                        })
-                        .unwrap_or_else(|_| panic!("Unable to start thread: {}", thread_name));
-
-                    let thread_name = format!("predictor-{}", output_name);
-                    std::thread::Builder::new()
-                        .name(thread_name.clone())
-                        .spawn(move || {
-                            let frame_capturer: Box<dyn frame::capturer::Capturer> =
-                                match output_capturer {
-                                    config::Capturer::Pipewire => {
-                                        Box::<frame::capturer::pipewire::Capturer>::default()
-                                    }
-                                    config::Capturer::Wlroots => {
-                                        Box::<frame::capturer::wlroots::Capturer>::default()
-                                    }
-                                    config::Capturer::None => {
-                                        Box::<frame::capturer::none::Capturer>::default()
-                                    }
-                                };
-
-                            let controller = predictor::Controller::new(
-                                prediction_tx,
-                                user_rx,
-                                als_rx,
-                                true,
-                                &output_name,
-                            );
-                            frame_capturer.run(&output_name, controller)
-                        })
-                        .unwrap_or_else(|_| panic!("Unable to start thread: {}", thread_name));
-
-                    Some(als_tx)
-                }
-                Err(err) => {
-                    log::warn!(
-                        "Skipping '{}' as it might be disconnected: {}",
-                        output_name,
-                        err
-                    );
-
-                    None
-                }
-            }
+            als::controller::Controller::new(sources, filter, als_subscribe_rx).run();
         })
-        .collect_vec();
+        .expect("Unable to start thread: als");
+
+    let (idle_subscribe_tx, idle_subscribe_rx) = mpsc::channel();
+    let idle_timeout = Duration::from_secs(config.idle.timeout);
 
     std::thread::Builder::new()
-        .name("als".to_string())
+        .name("idle".to_string())
         .spawn(move || {
-            let als: Box<dyn als::Als> = match config.als {
-                config::Als::Iio { path, thresholds } => Box::new(
-                    als::iio::Als::new(&path, thresholds)
-                        .expect("Unable to initialize ALS IIO sensor"),
-                ),
-                config::Als::Time { thresholds } => Box::new(als::time::Als::new(thresholds)),
-                config::Als::Webcam { video, thresholds } => Box::new({
-                    let (webcam_tx, webcam_rx) = mpsc::channel();
-                    std::thread::Builder::new()
-                        .name("als-webcam".to_string())
-                        .spawn(move || {
-                            als::webcam::Webcam::new(webcam_tx, video).run();
-                        })
-                        .expect("Unable to start thread: als-webcam");
-                    als::webcam::Als::new(webcam_rx, thresholds)
-                }),
-                config::Als::None => Box::<als::none::Als>::default(),
-            };
-
-            als::controller::Controller::new(als, als_txs).run();
+            if let Err(err) = idle::Watcher::new(idle_subscribe_rx, idle_timeout).run() {
+                log::warn!(
+                    "Unable to watch idle state, brightness is adapted also when idle: {}",
+                    err
+                );
+            }
         })
-        .expect("Unable to start thread: als");
+        .expect("Unable to start thread: idle");
 
     log::info!("Continue adjusting brightness and wluma will learn your preference over time.");
-    std::thread::park();
+    supervisor::Supervisor::new(config.output, als_subscribe_tx, idle_subscribe_tx).run();
+}
+
+// Backend of an ALS source and how its readings are mapped
+fn als_source(
+    als: config::Als,
+    hysteresis: &HashMap<u64, u64>,
+) -> Result<(Box<dyn als::Als>, als::Scale), Box<dyn Error>> {
+    let calibration = |calibration: config::IioCalibration| als::iio::Calibration {
+        x: calibration.x,
+        y: calibration.y,
+        z: calibration.z,
+    };
+    let scale = |thresholds, continuous| {
+        if continuous {
+            als::Scale::Continuous
+        } else {
+            als::Scale::Profiles {
+                thresholds,
+                hysteresis: hysteresis.clone(),
+            }
+        }
+    };
+
+    Ok(match als {
+        config::Als::Iio {
+            path,
+            thresholds,
+            mode: config::IioMode::Poll,
+            continuous,
+            calibration: iio_calibration,
+            ..
+        } => (
+            Box::new(als::iio::Als::new(&path, calibration(iio_calibration))?),
+            scale(thresholds, continuous),
+        ),
+        config::Als::Iio {
+            path,
+            thresholds,
+            mode: config::IioMode::Buffered,
+            continuous,
+            calibration: iio_calibration,
+            ..
+        } => (
+            Box::new({
+                let (value_tx, value_rx) = mpsc::channel();
+                let mut buffer =
+                    als::iio::buffer::Buffer::new(&path, calibration(iio_calibration))?;
+                std::thread::Builder::new()
+                    .name("als-iio".to_string())
+                    .spawn(move || {
+                        if let Err(err) = buffer.run(value_tx) {
+                            log::error!("Unable to read ALS IIO buffer: {}", err);
+                        }
+                    })
+                    .expect("Unable to start thread: als-iio");
+                als::iio::buffer::Als::new(value_rx)
+            }),
+            scale(thresholds, continuous),
+        ),
+        config::Als::Dbus {
+            thresholds,
+            continuous,
+        } => (
+            Box::new(als::dbus::Als::new(&zbus::blocking::Connection::system()?)?),
+            scale(thresholds, continuous),
+        ),
+        config::Als::Time { thresholds } => {
+            (Box::<als::time::Als>::default(), scale(thresholds, false))
+        }
+        config::Als::Solar {
+            latitude,
+            longitude,
+            profiles,
+        } => (
+            Box::new(als::solar::Als::new(latitude, longitude)),
+            scale(
+                als::solar::Als::thresholds(
+                    profiles.night,
+                    profiles.twilight,
+                    profiles.golden_hour,
+                    profiles.day,
+                ),
+                false,
+            ),
+        ),
+        config::Als::Webcam {
+            video,
+            thresholds,
+            persistent,
+            compensate,
+        } => (
+            Box::new({
+                let (webcam_tx, webcam_rx) = mpsc::channel();
+                std::thread::Builder::new()
+                    .name("als-webcam".to_string())
+                    .spawn(move || {
+                        als::webcam::Webcam::new(webcam_tx, video, persistent, compensate).run();
+                    })
+                    .expect("Unable to start thread: als-webcam");
+                als::webcam::Als::new(webcam_rx)
+            }),
+            scale(thresholds, false),
+        ),
+        config::Als::Command {
+            command,
+            interval,
+            thresholds,
+            continuous,
+        } => (
+            Box::new({
+                let (value_tx, value_rx) = mpsc::channel();
+                let command = als::external::Command::new(command, Duration::from_secs(interval));
+                std::thread::Builder::new()
+                    .name("als-command".to_string())
+                    .spawn(move || command.run(value_tx))
+                    .expect("Unable to start thread: als-command");
+                als::external::Als::new(value_rx)
+            }),
+            scale(thresholds, continuous),
+        ),
+        config::Als::Fifo {
+            path,
+            thresholds,
+            continuous,
+        } => (
+            Box::new({
+                let (value_tx, value_rx) = mpsc::channel();
+                let fifo = als::external::Fifo::new(&path)?;
+                std::thread::Builder::new()
+                    .name("als-fifo".to_string())
+                    .spawn(move || {
+                        if let Err(err) = fifo.run(value_tx) {
+                            log::error!("Unable to read ALS FIFO: {}", err);
+                        }
+                    })
+                    .expect("Unable to start thread: als-fifo");
+                als::external::Als::new(value_rx)
+            }),
+            scale(thresholds, continuous),
+        ),
+        config::Als::Socket {
+            path,
+            thresholds,
+            continuous,
+        } => (
+            Box::new({
+                let (value_tx, value_rx) = mpsc::channel();
+                let socket = als::external::Socket::new(&path)?;
+                std::thread::Builder::new()
+                    .name("als-socket".to_string())
+                    .spawn(move || {
+                        if let Err(err) = socket.run(value_tx) {
+                            log::error!("Unable to accept ALS socket connections: {}", err);
+                        }
+                    })
+                    .expect("Unable to start thread: als-socket");
+                als::external::Als::new(value_rx)
+            }),
+            scale(thresholds, continuous),
+        ),
+        config::Als::None => (
+            Box::<als::none::Als>::default(),
+            als::Scale::Profiles {
+                thresholds: HashMap::from([(0, "none".to_string())]),
+                hysteresis: HashMap::new(),
+            },
+        ),
+    })
 }
@@ -1,32 +1,114 @@
+use crate::als::Lux;
 use crate::predictor::data::{Data, Entry};
 use itertools::Itertools;
-use std::sync::mpsc::{Receiver, Sender};
-use std::time::Duration;
+use std::error::Error;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 const INITIAL_TIMEOUT_SECS: u64 = 5;
+const INITIAL_POLL_MS: u64 = 10;
 const PENDING_COOLDOWN_RESET: u8 = 15;
 const NEXT_ALS_COOLDOWN_RESET: u8 = 15;
+const RESUME_COOLDOWN_RESET: u8 = 5;
+const PAUSED_POLL_MS: u64 = 500;
+const NEAREST_ENTRIES: usize = 4;
+const DISTANCE_POWER: i32 = 2;
+
+// Initial value that the ALS or brightness controller did not send
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitError {
+    Timeout(&'static str),
+    Disconnected(&'static str),
+}
+
+impl std::fmt::Display for InitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InitError::Timeout(what) => write!(f, "Did not receive initial {} value in time", what),
+            InitError::Disconnected(what) => {
+                write!(
+                    f,
+                    "{} controller is gone before sending initial value",
+                    what
+                )
+            }
+        }
+    }
+}
+
+impl Error for InitError {}
+
+// Both values are waited for at once, so that a late one does not hold the other one back
+fn recv_initial<A, B>(
+    als_rx: &Receiver<A>,
+    user_rx: &Receiver<B>,
+    timeout: Duration,
+) -> (Result<A, InitError>, Result<B, InitError>) {
+    let deadline = Instant::now() + timeout;
+    let mut als = None;
+    let mut brightness = None;
+
+    while als.is_none() || brightness.is_none() {
+        if Instant::now() >= deadline {
+            break;
+        }
+        if als.is_none() {
+            als = try_recv_initial(als_rx, "ALS");
+        }
+        if brightness.is_none() {
+            brightness = try_recv_initial(user_rx, "brightness");
+        }
+        if als.is_none() || brightness.is_none() {
+            thread::sleep(Duration::from_millis(INITIAL_POLL_MS));
+        }
+    }
+
+    (
+        als.unwrap_or(Err(InitError::Timeout("ALS"))),
+        brightness.unwrap_or(Err(InitError::Timeout("brightness"))),
+    )
+}
+
+fn try_recv_initial<T>(rx: &Receiver<T>, what: &'static str) -> Option<Result<T, InitError>> {
+    match rx.try_recv() {
+        Ok(value) => Some(Ok(value)),
+        Err(TryRecvError::Empty) => None,
+        Err(TryRecvError::Disconnected) => Some(Err(InitError::Disconnected(what))),
+    }
+}
 
 pub struct Controller {
     prediction_tx: Sender<u64>,
     user_rx: Receiver<u64>,
-    als_rx: Receiver<String>,
+    als_rx: Receiver<Lux>,
+    paused_rx: Receiver<bool>,
+    paused: bool,
+    resume_cooldown: u8,
     pending_cooldown: u8,
     pending: Option<Entry>,
     data: Data,
     stateful: bool,
+    initialized: bool,
+    initial_timeout: Duration,
     initial_brightness: Option<u64>,
-    last_als: Option<String>,
-    next_als: Option<String>,
+    awaiting_brightness: bool,
+    last_als: Option<Lux>,
+    next_als: Option<Lux>,
     next_als_cooldown: u8,
+    last_predicted: Option<(Lux, u8)>,
     output_name: String,
+    // Output whose learned data is used, and when it was saved as far as known
+    followed: Option<(String, Option<SystemTime>)>,
+    disconnected: bool,
 }
 
 impl Controller {
     pub fn new(
         prediction_tx: Sender<u64>,
         user_rx: Receiver<u64>,
-        als_rx: Receiver<String>,
+        als_rx: Receiver<Lux>,
+        paused_rx: Receiver<bool>,
         stateful: bool,
         output_name: &str,
     ) -> Self {
@@ -40,48 +122,113 @@ impl Controller {
             prediction_tx,
             user_rx,
             als_rx,
+            paused_rx,
+            paused: false,
+            resume_cooldown: 0,
             pending_cooldown: 0,
             pending: None,
             data,
             stateful,
+            initialized: false,
+            initial_timeout: Duration::from_secs(INITIAL_TIMEOUT_SECS),
             initial_brightness: None,
+            awaiting_brightness: false,
             last_als: None,
             next_als: None,
             next_als_cooldown: 0,
+            last_predicted: None,
             output_name: output_name.to_string(),
+            followed: None,
+            disconnected: false,
         }
     }
 
-    pub fn adjust(&mut self, luma: u8) {
-        if self.last_als.is_none() {
-            // ALS controller is expected to send the initial value on this channel asap
-            self.last_als = self
-                .als_rx
-                .recv_timeout(Duration::from_secs(INITIAL_TIMEOUT_SECS))
-                .map_or_else(
-                    |_| panic!("Did not receive initial ALS value in time"),
-                    Some,
-                );
+    // Predicts with what was learned for another output, without ever changing it
+    pub fn following(mut self, output_name: &str) -> Self {
+        self.stateful = false;
+        self.followed = Some((output_name.to_string(), None));
+        self.reload_followed();
+        self
+    }
+
+    // Followed output keeps learning, its data is picked up whenever it is saved again
+    fn reload_followed(&mut self) {
+        let (output_name, modified) = match &self.followed {
+            Some((output_name, modified)) => (output_name.clone(), *modified),
+            None => return,
+        };
 
-            // Brightness controller is expected to send the initial value on this channel asap
-            let initial_brightness = self
-                .user_rx
-                .recv_timeout(Duration::from_secs(INITIAL_TIMEOUT_SECS))
-                .map_or_else(
-                    |_| panic!("Did not receive initial brightness value in time"),
-                    Some,
+        let saved = Data::modified(&output_name);
+        if saved.is_none() || saved == modified {
+            return;
+        }
+
+        match Data::read(&output_name) {
+            Ok(data) if data.normalized => {
+                log::debug!(
+                    "[{}] Using learned data of '{}'",
+                    self.output_name,
+                    output_name
                 );
+                self.data = data;
+                self.last_predicted = None;
+            }
+            Ok(_) => log::warn!(
+                "[{}] Unable to follow '{}' until its learned data is converted to percent",
+                self.output_name,
+                output_name
+            ),
+            Err(err) => {
+                // Most likely caught while being saved, so it is read again next time
+                log::debug!(
+                    "[{}] Unable to read data of '{}': {}",
+                    self.output_name,
+                    output_name,
+                    err
+                );
+                return;
+            }
+        }
+        self.followed = Some((output_name, saved));
+    }
 
-            // If there are no learned entries yet, we will use this as the first data point,
-            // assuming that user is happy with the current brightness settings
-            if self.data.entries.is_empty() {
-                self.initial_brightness = initial_brightness;
-            };
+    pub fn adjust(&mut self, luma: u8) -> Result<(), Box<dyn Error>> {
+        if !self.initialized {
+            self.initialize();
+        }
+
+        if self.wait_while_paused() {
+            // Luma was captured before the pause and is stale by now
+            return self.connection_status();
         }
 
+        self.reload_followed();
+
+        if self.last_als.is_none() {
+            match self.als_rx.try_iter().last() {
+                Some(als) => {
+                    log::info!("[{}] Received initial ALS value", self.output_name);
+                    self.last_als = Some(als);
+                    self.last_predicted = None;
+                }
+                None => {
+                    self.predict_degraded(luma);
+                    return self.connection_status();
+                }
+            }
+        }
+
+        // Continuous values are never exactly the same, so only noticeable changes count
+        let is_close = |als: &Option<Lux>, new_als: &Lux| {
+            als.as_ref().is_some_and(|als| als.is_close(new_als))
+        };
         match self.als_rx.try_iter().last() {
-            new_als @ Some(_) if self.next_als != new_als => {
-                self.next_als = new_als;
+            Some(new_als) if is_close(&self.last_als, &new_als) => {
+                self.next_als = None;
+                self.next_als_cooldown = 0;
+            }
+            Some(new_als) if !is_close(&self.next_als, &new_als) => {
+                self.next_als = Some(new_als);
                 self.next_als_cooldown = NEXT_ALS_COOLDOWN_RESET;
             }
             _ if self.next_als_cooldown > 1 => {
@@ -96,19 +243,147 @@ impl Controller {
 
         let lux = &self.last_als.clone().expect("ALS value must be known");
         self.process(lux, luma);
+
+        self.connection_status()
+    }
+
+    // ALS and brightness controllers are expected to send their initial values asap, the ones
+    // that are late anyway are picked up as soon as they come
+    fn initialize(&mut self) {
+        self.initialized = true;
+
+        let (als, brightness) = recv_initial(&self.als_rx, &self.user_rx, self.initial_timeout);
+
+        match als {
+            Ok(als) => self.last_als = Some(als),
+            Err(err) => log::warn!(
+                "[{}] {}, predicting for the fallback profile until it comes",
+                self.output_name,
+                err
+            ),
+        }
+
+        match brightness {
+            Ok(brightness) => self.set_initial_brightness(brightness),
+            Err(err) => {
+                log::warn!("[{}] {}", self.output_name, err);
+                self.awaiting_brightness = true;
+            }
+        }
+    }
+
+    // If there are no learned entries yet, we will use this as the first data point,
+    // assuming that user is happy with the current brightness settings
+    fn set_initial_brightness(&mut self, brightness: u64) {
+        if self.data.entries.is_empty() {
+            self.initial_brightness = Some(brightness);
+        }
+    }
+
+    // Nothing can be learned without knowing the ALS value, but predictions for the conditions
+    // the user is usually in are better than none
+    fn predict_degraded(&mut self, luma: u8) {
+        if self.next_user_brightness().is_some() {
+            log::debug!(
+                "[{}] Ignoring brightness change without ALS value",
+                self.output_name
+            );
+        }
+
+        if let Some(lux) = self.fallback_als() {
+            if self.last_predicted.as_ref() != Some(&(lux.clone(), luma)) {
+                self.last_predicted = Some((lux.clone(), luma));
+                self.predict(&lux, luma);
+            }
+        }
     }
 
-    fn process(&mut self, lux: &str, luma: u8) {
+    // ALS value with the most learned entries, or the middle one of those
+    fn fallback_als(&self) -> Option<Lux> {
+        let counts = self.data.entries.iter().map(|entry| &entry.lux).counts();
+        let most = *counts.values().max()?;
+        let candidates = counts
+            .into_iter()
+            .filter(|(_, count)| *count == most)
+            .map(|(lux, _)| lux)
+            .sorted()
+            .collect_vec();
+
+        Some(candidates[(candidates.len() - 1) / 2].clone())
+    }
+
+    fn connection_status(&self) -> Result<(), Box<dyn Error>> {
+        if self.disconnected {
+            Err(format!("Brightness controller of '{}' is gone", self.output_name).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Blocks while the session is idle or the output is off, returns whether it did. Brightness
+    // changes in the meantime come from the idle manager (e.g. swayidle dimming the screen) and
+    // must not be learned, neither the ones right after resuming when it restores the brightness.
+    fn wait_while_paused(&mut self) -> bool {
+        if let Some(paused) = self.paused_rx.try_iter().last() {
+            self.paused = paused;
+        }
+        if !self.paused {
+            return false;
+        }
+
+        log::debug!("[{}] Pausing while idle", self.output_name);
+        self.pending = None;
+        self.pending_cooldown = 0;
+
+        while self.paused && !self.disconnected {
+            match self
+                .paused_rx
+                .recv_timeout(Duration::from_millis(PAUSED_POLL_MS))
+            {
+                Ok(paused) => self.paused = paused,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => self.paused = false,
+            }
+            self.next_user_brightness();
+            // Only the environment at the time of resuming matters
+            if let Some(als) = self.als_rx.try_iter().last() {
+                self.last_als = Some(als);
+                self.next_als = None;
+            }
+        }
+
+        log::debug!("[{}] Resuming", self.output_name);
+        self.resume_cooldown = RESUME_COOLDOWN_RESET;
+        // Brightness might have been changed meanwhile, so the prediction has to be applied again
+        self.last_predicted = None;
+        true
+    }
+
+    // Nothing is pending, so calling adjust() again with the same luma would not change anything
+    pub fn is_settled(&self) -> bool {
+        self.last_als.is_some()
+            && self.resume_cooldown == 0
+            && self.pending.is_none()
+            && self.pending_cooldown == 0
+            && self.next_als_cooldown == 0
+    }
+
+    fn process(&mut self, lux: &Lux, luma: u8) {
         let initial_brightness = self.initial_brightness.take();
-        let user_changed_brightness = self.user_rx.try_iter().last().or(initial_brightness);
+        let user_changed_brightness = self.next_user_brightness().or(initial_brightness);
+
+        if self.resume_cooldown > 0 {
+            self.resume_cooldown -= 1;
+            return;
+        }
 
         if let Some(brightness) = user_changed_brightness {
             self.pending = match &self.pending {
                 // First time we notice user adjusting brightness, freeze lux and luma...
-                None => Some(Entry::new(lux, luma, brightness)),
+                None => Some(Entry::new(lux.clone(), luma, brightness)),
                 // ... but as user keeps changing brightness,
                 // allow some time for them to reach the desired brightness level for the pending lux and luma
-                Some(Entry { lux, luma, .. }) => Some(Entry::new(lux, *luma, brightness)),
+                Some(Entry { lux, luma, .. }) => Some(Entry::new(lux.clone(), *luma, brightness)),
             };
             // Every time user changed brightness, reset the cooldown period
             self.pending_cooldown = PENDING_COOLDOWN_RESET;
@@ -116,25 +391,46 @@ impl Controller {
             self.pending_cooldown -= 1;
         } else if self.pending.is_some() {
             self.learn();
-        } else {
+        } else if self.last_predicted.as_ref() != Some(&(lux.clone(), luma)) {
+            // Same conditions would lead to the same prediction, which is already being applied
+            self.last_predicted = Some((lux.clone(), luma));
             self.predict(lux, luma);
         }
     }
 
+    fn next_user_brightness(&mut self) -> Option<u64> {
+        let mut brightness = None;
+        loop {
+            match self.user_rx.try_recv() {
+                Ok(value) if self.awaiting_brightness => {
+                    // Late initial value is not a change made by the user
+                    self.awaiting_brightness = false;
+                    self.set_initial_brightness(value);
+                }
+                Ok(value) => brightness = Some(value),
+                Err(TryRecvError::Empty) => return brightness,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnected = true;
+                    return brightness;
+                }
+            }
+        }
+    }
+
     fn learn(&mut self) {
         let pending = self.pending.take().expect("No pending entry to learn");
         log::debug!("[{}] Learning {:?}", self.output_name, pending);
+        self.last_predicted = None;
 
         self.data.entries.retain(|entry| {
-            let different_env = entry.lux != pending.lux;
+            let same_env = entry.lux.is_close(&pending.lux);
+            let different_env = !same_env;
 
-            let same_env_darker_screen = entry.lux == pending.lux
-                && entry.luma < pending.luma
-                && entry.brightness >= pending.brightness;
+            let same_env_darker_screen =
+                same_env && entry.luma < pending.luma && entry.brightness >= pending.brightness;
 
-            let same_env_brighter_screen = entry.lux == pending.lux
-                && entry.luma > pending.luma
-                && entry.brightness <= pending.brightness;
+            let same_env_brighter_screen =
+                same_env && entry.luma > pending.luma && entry.brightness <= pending.brightness;
 
             different_env || same_env_darker_screen || same_env_brighter_screen
         });
@@ -150,55 +446,53 @@ impl Controller {
         }
     }
 
-    fn predict(&mut self, lux: &str, luma: u8) {
-        let entries = self
+    fn predict(&mut self, lux: &Lux, luma: u8) {
+        // Only the same profile is relevant, while numeric lux values are interpolated
+        let points = self
             .data
             .entries
             .iter()
-            .filter(|e| e.lux == lux)
+            .filter_map(|entry| {
+                let lux_distance = entry.lux.distance(lux)?;
+                let luma_distance = luma as f64 - entry.luma as f64;
+                let distance = luma_distance.hypot(lux_distance);
+                Some((entry.brightness as f64, distance))
+            })
             .collect_vec();
 
-        if entries.is_empty() {
+        if points.is_empty() {
             return;
         }
 
-        let points = entries
-            .iter()
-            .map(|entry| {
-                let distance = (luma as f64 - entry.luma as f64).abs();
-                (entry.brightness as f64, distance)
-            })
+        // Only the nearest entries take part, so distant ones neither drag the prediction
+        // towards the mean nor grow the weights out of range
+        let nearest = points
+            .into_iter()
+            .sorted_by(|a, b| a.1.total_cmp(&b.1))
+            .take(NEAREST_ENTRIES)
             .collect_vec();
 
-        let points = points
-            .iter()
-            .enumerate()
-            .map(|(i, p)| {
-                let other_distances: f64 = points[0..i]
+        let prediction = match nearest.iter().find(|p| p.1 == 0.0) {
+            Some(exact) => exact.0,
+            None => {
+                let weights = nearest
                     .iter()
-                    .chain(&points[i + 1..])
-                    .map(|p| p.1)
-                    .product();
-                (p.0, p.1, other_distances)
-            })
-            .collect_vec();
-
-        let distance_denominator: f64 = points
-            .iter()
-            .map(|p| p.1)
-            .combinations(points.len() - 1)
-            .map(|c| c.iter().product::<f64>())
-            .sum();
-
-        let prediction = points
-            .iter()
-            .map(|p| p.0 * p.2 / distance_denominator)
-            .sum::<f64>() as u64;
+                    .map(|p| p.1.powi(-DISTANCE_POWER))
+                    .collect_vec();
+                let total: f64 = weights.iter().sum();
+                nearest
+                    .iter()
+                    .zip(&weights)
+                    .map(|(p, weight)| p.0 * weight / total)
+                    .sum::<f64>()
+            }
+        }
+        .round() as u64;
 
         log::trace!("Prediction: {} (lux: {}, luma: {})", prediction, lux, luma);
-        self.prediction_tx
-            .send(prediction)
-            .expect("Unable to send predicted brightness value, channel is dead");
+        if self.prediction_tx.send(prediction).is_err() {
+            self.disconnected = true;
+        }
     }
 }
 
@@ -218,9 +512,11 @@ mod tests {
         let (als_tx, als_rx) = mpsc::channel();
         let (user_tx, user_rx) = mpsc::channel();
         let (prediction_tx, prediction_rx) = mpsc::channel();
-        als_tx.send(ALS_BRIGHT.to_string())?;
+        als_tx.send(ALS_BRIGHT.into())?;
         user_tx.send(0)?;
-        let controller = Controller::new(prediction_tx, user_rx, als_rx, false, "Dell 1");
+        let (_, paused_rx) = mpsc::channel();
+        let controller =
+            Controller::new(prediction_tx, user_rx, als_rx, paused_rx, false, "Dell 1");
         Ok((controller, user_tx, prediction_rx))
     }
 
@@ -230,7 +526,7 @@ mod tests {
 
         // User changes brightness to value 33 for a given lux and luma
         user_tx.send(33)?;
-        controller.process(ALS_DIM, 66);
+        controller.process(&ALS_DIM.into(), 66);
 
         assert_eq!(Some(Entry::new(ALS_DIM, 66, 33)), controller.pending);
         assert_eq!(PENDING_COOLDOWN_RESET, controller.pending_cooldown);
@@ -244,14 +540,14 @@ mod tests {
 
         // User initiates brightness change for a given lux and luma to value 33...
         user_tx.send(33)?;
-        controller.process(ALS_DIM, 66);
+        controller.process(&ALS_DIM.into(), 66);
         // then quickly continues increasing it to 34 (while lux and luma might already be different)...
         user_tx.send(34)?;
-        controller.process(ALS_BRIGHT, 36);
+        controller.process(&ALS_BRIGHT.into(), 36);
         // and even faster to 36 (which is the indended brightness value they wish to learn for the initial lux and luma)
         user_tx.send(35)?;
         user_tx.send(36)?;
-        controller.process(ALS_DARK, 16);
+        controller.process(&ALS_DARK.into(), 16);
 
         assert_eq!(Some(Entry::new(ALS_DIM, 66, 36)), controller.pending);
         assert_eq!(PENDING_COOLDOWN_RESET, controller.pending_cooldown);
@@ -265,21 +561,21 @@ mod tests {
 
         // User changes brightness to a desired value
         user_tx.send(33)?;
-        controller.process(ALS_DIM, 66);
+        controller.process(&ALS_DIM.into(), 66);
         user_tx.send(33)?;
-        controller.process(ALS_BRIGHT, 36);
+        controller.process(&ALS_BRIGHT.into(), 36);
         user_tx.send(35)?;
-        controller.process(ALS_DARK, 16);
+        controller.process(&ALS_DARK.into(), 16);
 
         for i in 1..=PENDING_COOLDOWN_RESET {
             // User doesn't change brightness anymore, so even if lux or luma change, we are in cooldown period
-            controller.process(ALS_BRIGHT, i);
+            controller.process(&ALS_BRIGHT.into(), i);
             assert_eq!(PENDING_COOLDOWN_RESET - i, controller.pending_cooldown);
             assert_eq!(Some(Entry::new(ALS_DIM, 66, 35)), controller.pending);
         }
 
         // One final process will trigger the learning
-        controller.process(ALS_DARK, 17);
+        controller.process(&ALS_DARK.into(), 17);
 
         assert_eq!(None, controller.pending);
         assert_eq!(0, controller.pending_cooldown);
@@ -306,13 +602,7 @@ mod tests {
 
         let all_als = vec![ALS_DARK, ALS_DIM, ALS_BRIGHT];
         let all_combinations: HashSet<_> = iproduct!(-1i32..=1, -1i32..=1, -1i32..=1)
-            .map(|(i, j, k)| {
-                Entry::new(
-                    all_als[(1 + i) as usize].clone(),
-                    (20 + j) as u8,
-                    (30 + k) as u64,
-                )
-            })
+            .map(|(i, j, k)| Entry::new(all_als[(1 + i) as usize], (20 + j) as u8, (30 + k) as u64))
             .collect();
 
         let to_be_deleted: HashSet<_> = vec![
@@ -356,13 +646,133 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_process_notices_disconnected_brightness_controller() -> Result<(), Box<dyn Error>> {
+        let (mut controller, user_tx, _) = setup()?;
+
+        controller.process(&ALS_DIM.into(), 66);
+        assert!(!controller.disconnected);
+
+        drop(user_tx);
+        controller.process(&ALS_DIM.into(), 66);
+        assert!(controller.disconnected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_skips_repeated_predictions() -> Result<(), Box<dyn Error>> {
+        let (mut controller, _, prediction_rx) = setup()?;
+        controller.data.entries = vec![Entry::new(ALS_DIM, 10, 15)];
+        controller.user_rx.try_recv()?;
+
+        controller.process(&ALS_DIM.into(), 20);
+        controller.process(&ALS_DIM.into(), 20);
+        assert_eq!(vec![15], prediction_rx.try_iter().collect_vec());
+
+        controller.process(&ALS_DIM.into(), 21);
+        controller.process(&ALS_DARK.into(), 21);
+        assert_eq!(vec![15], prediction_rx.try_iter().collect_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_predicts_again_after_learning() -> Result<(), Box<dyn Error>> {
+        let (mut controller, user_tx, prediction_rx) = setup()?;
+        controller.data.entries = vec![Entry::new(ALS_DIM, 10, 15)];
+        controller.last_als = Some(ALS_DIM.into());
+        controller.user_rx.try_recv()?;
+
+        controller.process(&ALS_DIM.into(), 20);
+        assert!(controller.is_settled());
+
+        user_tx.send(40)?;
+        controller.process(&ALS_DIM.into(), 20);
+        assert!(!controller.is_settled());
+        for _ in 0..=PENDING_COOLDOWN_RESET {
+            controller.process(&ALS_DIM.into(), 20);
+        }
+        assert!(controller.is_settled());
+        controller.process(&ALS_DIM.into(), 20);
+
+        assert_eq!(vec![15, 40], prediction_rx.try_iter().collect_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_adjust_drains_als_while_paused() -> Result<(), Box<dyn Error>> {
+        let (als_tx, als_rx) = mpsc::channel();
+        let (_user_tx, user_rx) = mpsc::channel();
+        let (prediction_tx, _prediction_rx) = mpsc::channel();
+        let (paused_tx, paused_rx) = mpsc::channel();
+        let mut controller =
+            Controller::new(prediction_tx, user_rx, als_rx, paused_rx, false, "Dell 1");
+        controller.initialized = true;
+
+        paused_tx.send(true)?;
+        let als = std::thread::spawn(move || {
+            als_tx.send(ALS_DARK.into()).unwrap();
+            als_tx.send(ALS_BRIGHT.into()).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            paused_tx.send(false).unwrap();
+            als_tx
+        });
+        controller.adjust(10)?;
+        let als_tx = als.join().unwrap();
+
+        assert_eq!(Some(ALS_BRIGHT.into()), controller.last_als);
+        assert_eq!(None, controller.als_rx.try_recv().ok());
+        drop(als_tx);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_adjust_ignores_brightness_changes_while_paused() -> Result<(), Box<dyn Error>> {
+        let (als_tx, als_rx) = mpsc::channel();
+        let (user_tx, user_rx) = mpsc::channel();
+        let (prediction_tx, prediction_rx) = mpsc::channel();
+        let (paused_tx, paused_rx) = mpsc::channel();
+        let mut controller =
+            Controller::new(prediction_tx, user_rx, als_rx, paused_rx, false, "Dell 1");
+        controller.data.entries = vec![Entry::new(ALS_DIM, 10, 50)];
+        als_tx.send(ALS_DIM.into())?;
+        user_tx.send(50)?;
+        controller.adjust(10)?;
+        assert_eq!(vec![50], prediction_rx.try_iter().collect_vec());
+
+        // Screen gets dimmed by the idle manager, and restored once the user is back
+        paused_tx.send(true)?;
+        let idle_manager = std::thread::spawn(move || {
+            user_tx.send(5).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            paused_tx.send(false).unwrap();
+            user_tx
+        });
+        controller.adjust(10)?;
+        let user_tx = idle_manager.join().unwrap();
+        user_tx.send(45)?;
+
+        for _ in 0..=RESUME_COOLDOWN_RESET {
+            controller.adjust(10)?;
+        }
+
+        assert_eq!(None, controller.pending);
+        assert_eq!(vec![Entry::new(ALS_DIM, 10, 50)], controller.data.entries);
+        assert_eq!(vec![50], prediction_rx.try_iter().collect_vec());
+
+        Ok(())
+    }
+
     #[test]
     fn test_predict_no_data_points() -> Result<(), Box<dyn Error>> {
         let (mut controller, _, prediction_rx) = setup()?;
         controller.data.entries = vec![];
 
         // predict() should not be called with no data, but just in case confirm we don't panic
-        controller.predict(ALS_DIM, 20);
+        controller.predict(&ALS_DIM.into(), 20);
 
         assert_eq!(true, prediction_rx.try_recv().is_err());
 
@@ -378,7 +788,7 @@ mod tests {
         ];
 
         // predict() should not be called with no data, but just in case confirm we don't panic
-        controller.predict(ALS_DIM, 20);
+        controller.predict(&ALS_DIM.into(), 20);
 
         assert_eq!(true, prediction_rx.try_recv().is_err());
 
@@ -390,7 +800,7 @@ mod tests {
         let (mut controller, _, prediction_rx) = setup()?;
         controller.data.entries = vec![Entry::new(ALS_DIM, 10, 15)];
 
-        controller.predict(ALS_DIM, 20);
+        controller.predict(&ALS_DIM.into(), 20);
 
         assert_eq!(15, prediction_rx.try_recv()?);
         Ok(())
@@ -401,7 +811,7 @@ mod tests {
         let (mut controller, _, prediction_rx) = setup()?;
         controller.data.entries = vec![Entry::new(ALS_DIM, 10, 15), Entry::new(ALS_DIM, 20, 30)];
 
-        controller.predict(ALS_DIM, 20);
+        controller.predict(&ALS_DIM.into(), 20);
 
         assert_eq!(30, prediction_rx.try_recv()?);
         Ok(())
@@ -416,13 +826,13 @@ mod tests {
             Entry::new(ALS_DIM, 100, 100),
         ];
 
-        // Approximated using weighted distance to all known points:
+        // Approximated using inverse squared distance to the nearest known points:
         // dist1 = sqrt((x1 - x2)^2 + (y1 - y2)^2)
-        // weight1 = (1/dist1) / (1/dist1 + 1/dist2 + 1/dist3)
+        // weight1 = (1/dist1^2) / (1/dist1^2 + 1/dist2^2 + 1/dist3^2)
         // prediction = weight1*brightness1 + weight2*brightness2 + weight3*brightness
-        controller.predict(ALS_DIM, 50);
+        controller.predict(&ALS_DIM.into(), 50);
 
-        assert_eq!(43, prediction_rx.try_recv()?);
+        assert_eq!(39, prediction_rx.try_recv()?);
         Ok(())
     }
 
@@ -437,9 +847,208 @@ mod tests {
             Entry::new(ALS_BRIGHT, 51, 100),
         ];
 
-        controller.predict(ALS_DIM, 50);
+        controller.predict(&ALS_DIM.into(), 50);
+
+        assert_eq!(39, prediction_rx.try_recv()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_predict_interpolates_between_lux_values() -> Result<(), Box<dyn Error>> {
+        let (mut controller, _, prediction_rx) = setup()?;
+        controller.data.entries = vec![
+            Entry::new(Lux::Value(10), 50, 20),
+            Entry::new(Lux::Value(1000), 50, 80),
+            Entry::new(ALS_DIM, 50, 100),
+        ];
+
+        controller.predict(&Lux::Value(10), 50);
+        controller.predict(&Lux::Value(100), 50);
+        controller.predict(&Lux::Value(1000), 50);
+
+        assert_eq!(vec![20, 49, 80], prediction_rx.try_iter().collect_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn test_predict_with_many_entries() -> Result<(), Box<dyn Error>> {
+        let (mut controller, _, prediction_rx) = setup()?;
+        controller.data.entries = (0..250)
+            .map(|i| Entry::new(Lux::Value(i * 40), (i % 100) as u8, 20 + (i % 60)))
+            .collect();
+
+        controller.predict(&Lux::Value(5000), 50);
+
+        let prediction = prediction_rx.try_recv()?;
+        assert!((20..80).contains(&prediction));
+        Ok(())
+    }
+
+    #[test]
+    fn test_learn_replaces_entries_with_close_lux_values() -> Result<(), Box<dyn Error>> {
+        let (mut controller, _, _) = setup()?;
+        controller.data.entries = vec![
+            Entry::new(Lux::Value(100), 20, 40),
+            Entry::new(Lux::Value(490), 20, 40),
+        ];
+        controller.pending = Some(Entry::new(Lux::Value(500), 20, 60));
+
+        controller.learn();
 
-        assert_eq!(43, prediction_rx.try_recv()?);
+        assert_eq!(
+            vec![
+                Entry::new(Lux::Value(100), 20, 40),
+                Entry::new(Lux::Value(500), 20, 60)
+            ],
+            controller.data.entries
+        );
         Ok(())
     }
+
+    #[test]
+    fn test_adjust_ignores_small_lux_changes() -> Result<(), Box<dyn Error>> {
+        let (als_tx, als_rx) = mpsc::channel();
+        let (user_tx, user_rx) = mpsc::channel();
+        let (prediction_tx, _prediction_rx) = mpsc::channel();
+        let (_, paused_rx) = mpsc::channel();
+        let mut controller =
+            Controller::new(prediction_tx, user_rx, als_rx, paused_rx, false, "Dell 1");
+        als_tx.send(Lux::Value(500))?;
+        user_tx.send(50)?;
+        controller.adjust(10)?;
+
+        als_tx.send(Lux::Value(510))?;
+        controller.adjust(10)?;
+        assert_eq!(None, controller.next_als);
+
+        als_tx.send(Lux::Value(2000))?;
+        for _ in 0..=NEXT_ALS_COOLDOWN_RESET {
+            controller.adjust(10)?;
+            als_tx.send(Lux::Value(1990))?;
+        }
+        assert_eq!(Some(Lux::Value(2000)), controller.last_als);
+
+        Ok(())
+    }
+
+    // Controller that waits only briefly for the initial values, which are not sent yet
+    fn setup_slow() -> (Controller, Sender<Lux>, Sender<u64>, Receiver<u64>) {
+        let (als_tx, als_rx) = mpsc::channel();
+        let (user_tx, user_rx) = mpsc::channel();
+        let (prediction_tx, prediction_rx) = mpsc::channel();
+        let (_, paused_rx) = mpsc::channel();
+        let mut controller =
+            Controller::new(prediction_tx, user_rx, als_rx, paused_rx, false, "Dell 1");
+        controller.initial_timeout = Duration::from_millis(30);
+        (controller, als_tx, user_tx, prediction_rx)
+    }
+
+    #[test]
+    fn test_recv_initial_waits_for_both_channels_at_once() {
+        let (als_tx, als_rx) = mpsc::channel();
+        let (user_tx, user_rx) = mpsc::channel();
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(150));
+            als_tx.send(Lux::Value(42)).unwrap();
+            std::thread::sleep(Duration::from_millis(150));
+            user_tx.send(42).unwrap();
+            (als_tx, user_tx)
+        });
+
+        let (als, brightness) = recv_initial(&als_rx, &user_rx, Duration::from_millis(400));
+        assert_eq!(Ok(Lux::Value(42)), als);
+        assert_eq!(Ok(42), brightness);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn test_recv_initial_fails_on_timeout_and_disconnect() {
+        let (als_tx, als_rx) = mpsc::channel::<Lux>();
+        let (user_tx, user_rx) = mpsc::channel::<u64>();
+        let started = Instant::now();
+        assert_eq!(
+            (
+                Err(InitError::Timeout("ALS")),
+                Err(InitError::Timeout("brightness"))
+            ),
+            recv_initial(&als_rx, &user_rx, Duration::from_millis(200))
+        );
+        // one deadline for both of them
+        assert!(started.elapsed() < Duration::from_millis(400));
+
+        drop(als_tx);
+        user_tx.send(42).unwrap();
+        assert_eq!(
+            (Err(InitError::Disconnected("ALS")), Ok(42)),
+            recv_initial(&als_rx, &user_rx, Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn test_adjust_predicts_for_fallback_profile_until_als_comes() -> Result<(), Box<dyn Error>> {
+        let (mut controller, als_tx, user_tx, prediction_rx) = setup_slow();
+        controller.data.entries = vec![
+            Entry::new(ALS_DIM, 10, 20),
+            Entry::new(ALS_DIM, 30, 40),
+            Entry::new(ALS_BRIGHT, 10, 80),
+        ];
+        user_tx.send(50)?;
+
+        controller.adjust(10)?;
+        // Changes are not learned, as it is not known what light they were made in
+        user_tx.send(60)?;
+        controller.adjust(10)?;
+        assert_eq!(None, controller.pending);
+        assert!(!controller.is_settled());
+
+        let sensor = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            als_tx.send(ALS_BRIGHT.into()).unwrap();
+            als_tx
+        });
+        let _als_tx = sensor.join().unwrap();
+        controller.adjust(10)?;
+
+        assert_eq!(vec![20, 80], prediction_rx.try_iter().collect_vec());
+        assert_eq!(Some(ALS_BRIGHT.into()), controller.last_als);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_adjust_uses_late_initial_brightness() -> Result<(), Box<dyn Error>> {
+        let (mut controller, als_tx, user_tx, _) = setup_slow();
+        als_tx.send(ALS_DIM.into())?;
+
+        controller.adjust(10)?;
+        assert_eq!(None, controller.pending);
+
+        user_tx.send(33)?;
+        controller.adjust(10)?;
+        controller.adjust(10)?;
+
+        assert_eq!(Some(Entry::new(ALS_DIM, 10, 33)), controller.pending);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fallback_als_has_most_entries() {
+        let (mut controller, ..) = setup_slow();
+        assert_eq!(None, controller.fallback_als());
+
+        controller.data.entries = vec![
+            Entry::new(ALS_DARK, 10, 20),
+            Entry::new(ALS_DIM, 10, 30),
+            Entry::new(ALS_DIM, 20, 40),
+        ];
+        assert_eq!(Some(ALS_DIM.into()), controller.fallback_als());
+
+        controller.data.entries = vec![
+            Entry::new(Lux::Value(10), 10, 20),
+            Entry::new(Lux::Value(100), 10, 30),
+            Entry::new(Lux::Value(1000), 10, 40),
+        ];
+        assert_eq!(Some(Lux::Value(100)), controller.fallback_als());
+    }
 }
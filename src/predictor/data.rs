@@ -1,17 +1,22 @@
+use crate::als::Lux;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fs::{File, OpenOptions};
 use std::path::PathBuf;
+use std::time::SystemTime;
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub struct Data {
     pub output_name: String,
     pub entries: Vec<Entry>,
+    // Brightness used to be learned in raw units of the device instead of percent
+    #[serde(default)]
+    pub normalized: bool,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone)]
 pub struct Entry {
-    pub lux: String,
+    pub lux: Lux,
     pub luma: u8,
     pub brightness: u64,
 }
@@ -21,15 +26,48 @@ impl Data {
         Self {
             output_name: output_name.to_string(),
             entries: Vec::default(),
+            normalized: true,
         }
     }
 
     pub fn load(output_name: &str) -> Self {
-        Self::path(output_name)
+        Self::read(output_name).unwrap_or_else(|_| Self::new(output_name))
+    }
+
+    pub fn read(output_name: &str) -> Result<Self, Box<dyn Error>> {
+        let file = Self::read_file(Self::path(output_name)?)?;
+        Ok(serde_yaml::from_reader(file)?)
+    }
+
+    // Tells whether the data was saved again since it was last read
+    pub fn modified(output_name: &str) -> Option<SystemTime> {
+        std::fs::metadata(Self::path(output_name).ok()?)
+            .and_then(|metadata| metadata.modified())
             .ok()
-            .and_then(|path| Self::read_file(path).ok())
-            .and_then(|file| serde_yaml::from_reader(file).ok())
-            .unwrap_or_else(|| Self::new(output_name))
+    }
+
+    // Converts brightness learned in raw units of the device, only once
+    pub fn normalize(
+        output_name: &str,
+        percent: impl Fn(u64) -> u64,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut data = Self::load(output_name);
+        if data.normalize_entries(percent) {
+            data.save()?;
+        }
+        Ok(())
+    }
+
+    fn normalize_entries(&mut self, percent: impl Fn(u64) -> u64) -> bool {
+        if self.normalized {
+            return false;
+        }
+
+        self.entries
+            .iter_mut()
+            .for_each(|entry| entry.brightness = percent(entry.brightness));
+        self.normalized = true;
+        true
     }
 
     pub fn save(&self) -> Result<(), Box<dyn Error>> {
@@ -61,11 +99,33 @@ impl Data {
 }
 
 impl Entry {
-    pub fn new(lux: &str, luma: u8, brightness: u64) -> Self {
+    pub fn new(lux: impl Into<Lux>, luma: u8, brightness: u64) -> Self {
         Self {
-            lux: lux.to_string(),
+            lux: lux.into(),
             luma,
             brightness,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_entries_only_once() {
+        let mut data: Data = serde_yaml::from_str(
+            "output_name: eDP-1\nentries:\n- lux: dim\n  luma: 20\n  brightness: 6000\n",
+        )
+        .unwrap();
+
+        assert!(data.normalize_entries(|raw| raw / 100));
+        assert!(!data.normalize_entries(|raw| raw / 100));
+        assert_eq!(vec![Entry::new("dim", 20, 60)], data.entries);
+    }
+
+    #[test]
+    fn test_new_data_is_normalized() {
+        assert!(Data::new("eDP-1").normalized);
+    }
+}
@@ -2,3 +2,4 @@ mod controller;
 mod data;
 
 pub use controller::Controller;
+pub use data::Data;
@@ -0,0 +1,37 @@
+// Client bindings for protocols vendored in `protocols/`, laid out the same way as in wayland-protocols
+macro_rules! wayland_protocol(
+    ($name: expr, [$($import: ident),*], [$(($prot_name: ident, $prot_import: ident)),*]) => {
+        #[allow(dead_code, non_camel_case_types, unused_unsafe, unused_variables)]
+        #[allow(non_upper_case_globals, non_snake_case, unused_imports, static_mut_refs)]
+        #[allow(missing_docs, clippy::all)]
+        pub mod client {
+            pub(crate) use wayland_client::{Main, Attached, Proxy, ProxyMap, AnonymousObject};
+            pub(crate) use wayland_commons::map::{Object, ObjectMetadata};
+            pub(crate) use wayland_commons::{Interface, MessageGroup};
+            pub(crate) use wayland_commons::wire::{Argument, MessageDesc, ArgumentType, Message};
+            pub(crate) use wayland_commons::smallvec;
+            pub(crate) use wayland_client::protocol::{$($import),*};
+            pub(crate) use wayland_client::sys;
+            $(
+                pub(crate) use crate::protocols::$prot_name::client::$prot_import;
+            )*
+            include!(concat!(env!("OUT_DIR"), "/", $name, "_client_api.rs"));
+        }
+    }
+);
+
+pub mod ext_idle_notify {
+    wayland_protocol!("ext-idle-notify-v1", [wl_seat], []);
+}
+
+pub mod ext_image_capture_source {
+    wayland_protocol!("ext-image-capture-source-v1", [wl_output], []);
+}
+
+pub mod ext_image_copy_capture {
+    wayland_protocol!(
+        "ext-image-copy-capture-v1",
+        [wl_buffer, wl_output, wl_pointer, wl_shm],
+        [(ext_image_capture_source, ext_image_capture_source_v1)]
+    );
+}
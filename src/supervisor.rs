@@ -0,0 +1,436 @@
+use crate::als::Lux;
+use crate::config;
+use crate::{brightness, frame, predictor};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use wayland_client::{Display, GlobalEvent, GlobalManager};
+
+const UDEV_POLL_MS: u64 = 1000;
+const STOPPING_POLL_MS: u64 = 200;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event {
+    OutputAdded,
+    OutputRemoved,
+    DeviceAdded,
+    DeviceChanged,
+    DeviceRemoved,
+}
+
+impl Event {
+    fn might_disconnect(&self) -> bool {
+        matches!(
+            self,
+            Event::OutputRemoved | Event::DeviceChanged | Event::DeviceRemoved
+        )
+    }
+}
+
+struct Running {
+    // Dropping the stop channel shuts down both threads of an output
+    stop_tx: Option<Sender<()>>,
+    brightness_thread: JoinHandle<()>,
+    predictor_thread: JoinHandle<()>,
+}
+
+impl Running {
+    fn stop(&mut self) {
+        self.stop_tx.take();
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.stop_tx.is_none()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.brightness_thread.is_finished() && self.predictor_thread.is_finished()
+    }
+
+    fn is_failed(&self) -> bool {
+        self.brightness_thread.is_finished() || self.predictor_thread.is_finished()
+    }
+}
+
+pub struct Supervisor {
+    outputs: Vec<config::Output>,
+    running: HashMap<String, Running>,
+    // Outputs that could not be started, until something that might bring them back happens
+    absent: HashSet<String>,
+    als_subscribe_tx: Sender<Sender<Lux>>,
+    idle_subscribe_tx: Sender<(String, Sender<bool>)>,
+}
+
+impl Supervisor {
+    pub fn new(
+        outputs: Vec<config::Output>,
+        als_subscribe_tx: Sender<Sender<Lux>>,
+        idle_subscribe_tx: Sender<(String, Sender<bool>)>,
+    ) -> Self {
+        Self {
+            outputs,
+            running: HashMap::new(),
+            absent: HashSet::new(),
+            als_subscribe_tx,
+            idle_subscribe_tx,
+        }
+    }
+
+    pub fn run(&mut self) {
+        let (event_tx, event_rx) = mpsc::channel();
+
+        let wayland_tx = event_tx.clone();
+        std::thread::Builder::new()
+            .name("watch-wayland".to_string())
+            .spawn(move || {
+                if let Err(err) = watch_wayland(wayland_tx) {
+                    log::warn!(
+                        "Unable to watch Wayland outputs, hotplug is limited: {}",
+                        err
+                    );
+                }
+            })
+            .expect("Unable to start thread: watch-wayland");
+
+        std::thread::Builder::new()
+            .name("watch-udev".to_string())
+            .spawn(move || {
+                if let Err(err) = watch_udev(event_tx) {
+                    log::warn!("Unable to watch udev events, hotplug is limited: {}", err);
+                }
+            })
+            .expect("Unable to start thread: watch-udev");
+
+        self.reconcile(None);
+        self.watch(event_rx);
+    }
+
+    fn watch(&mut self, event_rx: Receiver<Event>) {
+        loop {
+            // Outputs that are shutting down are respawned as soon as both of their threads are gone
+            let event = if self.running.values().any(Running::is_stopping) {
+                event_rx.recv_timeout(Duration::from_millis(STOPPING_POLL_MS))
+            } else {
+                event_rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            };
+
+            match event {
+                Ok(event) => {
+                    log::debug!("Hotplug event: {:?}", event);
+                    self.reconcile(Some(event));
+                }
+                Err(RecvTimeoutError::Timeout) => self.reconcile(None),
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    fn reconcile(&mut self, event: Option<Event>) {
+        // Either half of an output stops when the other one does, wait for both to be gone
+        // before starting it again, so that it never runs twice
+        for running in self.running.values_mut() {
+            if running.is_failed() {
+                running.stop();
+            }
+        }
+
+        if let Some(event) = &event {
+            for output in &self.outputs {
+                if let Some(running) = self.running.get_mut(output_name(output)) {
+                    if !running.is_stopping()
+                        && might_disconnect(event, output)
+                        && !is_connected(output)
+                    {
+                        running.stop();
+                    }
+                }
+            }
+        }
+
+        self.running.retain(|_, running| !running.is_finished());
+
+        for output in self.outputs.clone() {
+            let name = output_name(&output);
+            if self.running.contains_key(name)
+                || (self.absent.contains(name) && !might_connect(event.as_ref(), &output))
+            {
+                continue;
+            }
+
+            if self.spawn(output.clone()) {
+                self.absent.remove(output_name(&output));
+            } else {
+                self.absent.insert(output_name(&output).to_string());
+            }
+        }
+    }
+
+    fn spawn(&mut self, output: config::Output) -> bool {
+        let (als_tx, als_rx) = mpsc::channel();
+        let (user_tx, user_rx) = mpsc::channel();
+        let (prediction_tx, prediction_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let (paused_tx, paused_rx) = mpsc::channel();
+
+        let (output_name, output_capturer, exclude, luma, transition, curve, follow) =
+            match output.clone() {
+                config::Output::Backlight(cfg) => (
+                    cfg.name,
+                    cfg.capturer,
+                    cfg.exclude,
+                    cfg.luma,
+                    cfg.transition,
+                    cfg.curve,
+                    None,
+                ),
+                config::Output::DdcUtil(cfg) => (
+                    cfg.name,
+                    cfg.capturer,
+                    cfg.exclude,
+                    cfg.luma,
+                    cfg.transition,
+                    cfg.curve,
+                    None,
+                ),
+                config::Output::Gamma(cfg) => (
+                    cfg.name,
+                    cfg.capturer,
+                    cfg.exclude,
+                    cfg.luma,
+                    cfg.transition,
+                    cfg.curve,
+                    cfg.follow,
+                ),
+            };
+        let transition = brightness::Transition {
+            brighten: Duration::from_millis(transition.brighten),
+            dim: Duration::from_millis(transition.dim),
+            easing: match transition.easing {
+                config::Easing::Linear => brightness::Easing::Linear,
+                config::Easing::EaseInOut => brightness::Easing::EaseInOut,
+                config::Easing::Exponential => brightness::Easing::Exponential,
+            },
+        };
+        let luma_settings = frame::Settings {
+            metric: luma,
+            exclude,
+        };
+
+        let brightness = match output {
+            config::Output::Backlight(cfg) => {
+                brightness::Backlight::new(&cfg.path, cfg.min_brightness).map(|b| {
+                    let max = b.max_brightness();
+                    (Box::new(b) as Box<dyn brightness::Brightness + Send>, max)
+                })
+            }
+            config::Output::DdcUtil(cfg) => brightness::DdcUtil::new(&cfg.name, cfg.min_brightness)
+                .map(|b| {
+                    let max = b.max_brightness();
+                    (Box::new(b) as Box<dyn brightness::Brightness + Send>, max)
+                }),
+            config::Output::Gamma(cfg) => brightness::Gamma::new(&cfg.name, cfg.min_brightness)
+                .map(|b| {
+                    let max = b.max_brightness();
+                    (Box::new(b) as Box<dyn brightness::Brightness + Send>, max)
+                }),
+        };
+
+        let (brightness, max_brightness) = match brightness {
+            Ok(b) => b,
+            Err(err) => {
+                log::warn!(
+                    "Skipping '{}' as it might be disconnected: {}",
+                    output_name,
+                    err
+                );
+                return false;
+            }
+        };
+
+        // Brightness is learned in percent of a perceptual scale, so that it means the same
+        // on every device
+        let scale = brightness::Scale::new(
+            match curve {
+                config::Curve::Gamma { gamma } => brightness::Curve::Gamma(gamma),
+                config::Curve::Logarithmic { base } => brightness::Curve::Logarithmic(base),
+            },
+            max_brightness,
+        );
+        if let Err(err) = predictor::Data::normalize(&output_name, |raw| scale.percent(raw)) {
+            log::warn!(
+                "Unable to convert learned data of '{}' to percent: {}",
+                output_name,
+                err
+            );
+        }
+        let brightness = Box::new(brightness::Perceptual::new(brightness, scale));
+
+        let thread_name = format!("backlight-{}", output_name);
+        let brightness_thread = std::thread::Builder::new()
+            .name(thread_name.clone())
+            .spawn(move || {
+                brightness::Controller::new(brightness, user_tx, prediction_rx, transition)
+                    .run(stop_rx);
+            })
+            .unwrap_or_else(|_| panic!("Unable to start thread: {}", thread_name));
+
+        let thread_name = format!("predictor-{}", output_name);
+        let predictor_output_name = output_name.clone();
+        let predictor_thread = std::thread::Builder::new()
+            .name(thread_name.clone())
+            .spawn(move || {
+                let frame_capturer: Result<Box<dyn frame::capturer::Capturer>, Box<dyn Error>> =
+                    match output_capturer {
+                        config::Capturer::Pipewire => {
+                            frame::capturer::pipewire::Capturer::new().map(|c| Box::new(c) as _)
+                        }
+                        config::Capturer::Screencopy => {
+                            frame::capturer::screencopy::Capturer::new().map(|c| Box::new(c) as _)
+                        }
+                        config::Capturer::ExtImageCopy => {
+                            frame::capturer::ext_image_copy::Capturer::new()
+                                .map(|c| Box::new(c) as _)
+                        }
+                        config::Capturer::Wlroots => {
+                            frame::capturer::wlroots::Capturer::new().map(|c| Box::new(c) as _)
+                        }
+                        config::Capturer::None => {
+                            Ok(Box::<frame::capturer::none::Capturer>::default())
+                        }
+                    };
+
+                // Learned data is persisted on disk, so it survives the output being reconnected
+                let controller = predictor::Controller::new(
+                    prediction_tx,
+                    user_rx,
+                    als_rx,
+                    paused_rx,
+                    true,
+                    &predictor_output_name,
+                );
+                let controller = match follow {
+                    Some(follow) => controller.following(&follow),
+                    None => controller,
+                };
+
+                // Only this output stops, the supervisor starts it again on the next event
+                let result = frame_capturer.and_then(|frame_capturer| {
+                    frame_capturer.run(&predictor_output_name, &luma_settings, controller)
+                });
+                if let Err(err) = result {
+                    log::error!(
+                        "Unable to capture frames of '{}': {}",
+                        predictor_output_name,
+                        err
+                    );
+                }
+            })
+            .unwrap_or_else(|_| panic!("Unable to start thread: {}", thread_name));
+
+        self.als_subscribe_tx
+            .send(als_tx)
+            .expect("Unable to subscribe to ALS values, channel is dead");
+
+        // Idle watcher is not essential, it is gone if the compositor does not support it
+        let _ = self
+            .idle_subscribe_tx
+            .send((output_name.clone(), paused_tx));
+
+        log::debug!("Started output '{}'", output_name);
+        self.running.insert(
+            output_name,
+            Running {
+                stop_tx: Some(stop_tx),
+                brightness_thread,
+                predictor_thread,
+            },
+        );
+        true
+    }
+}
+
+fn output_name(output: &config::Output) -> &str {
+    match output {
+        config::Output::Backlight(cfg) => &cfg.name,
+        config::Output::DdcUtil(cfg) => &cfg.name,
+        config::Output::Gamma(cfg) => &cfg.name,
+    }
+}
+
+// Enumerating DDC displays is slow and blocks every other DDC user, while DRM events come with
+// every mode set, so monitors are only looked for once their Wayland output is gone
+fn might_disconnect(event: &Event, output: &config::Output) -> bool {
+    match output {
+        config::Output::DdcUtil(_) => *event == Event::OutputRemoved,
+        _ => event.might_disconnect(),
+    }
+}
+
+// For the same reason, missing monitors are only looked for again once something was plugged in
+fn might_connect(event: Option<&Event>, output: &config::Output) -> bool {
+    match output {
+        config::Output::DdcUtil(_) => {
+            matches!(event, Some(Event::OutputAdded) | Some(Event::DeviceAdded))
+        }
+        _ => true,
+    }
+}
+
+fn is_connected(output: &config::Output) -> bool {
+    match output {
+        config::Output::Backlight(cfg) => Path::new(&cfg.path).exists(),
+        config::Output::DdcUtil(cfg) => brightness::DdcUtil::is_connected(&cfg.name),
+        config::Output::Gamma(cfg) => brightness::Gamma::is_connected(&cfg.name),
+    }
+}
+
+fn watch_wayland(event_tx: Sender<Event>) -> Result<(), Box<dyn Error>> {
+    let display = Display::connect_to_env()?;
+    let mut event_queue = display.create_event_queue();
+    let attached_display = display.attach(event_queue.token());
+
+    let _globals = GlobalManager::new_with_cb(&attached_display, move |event, _, _| {
+        let event = match event {
+            GlobalEvent::New { interface, .. } if interface == "wl_output" => Event::OutputAdded,
+            GlobalEvent::Removed { interface, .. } if interface == "wl_output" => {
+                Event::OutputRemoved
+            }
+            _ => return,
+        };
+
+        event_tx
+            .send(event)
+            .expect("Unable to send hotplug event, channel is dead");
+    });
+
+    loop {
+        event_queue.dispatch(&mut (), |_, _, _| {})?;
+    }
+}
+
+fn watch_udev(event_tx: Sender<Event>) -> Result<(), Box<dyn Error>> {
+    let socket = udev::MonitorBuilder::new()?
+        .match_subsystem("backlight")?
+        .match_subsystem("leds")?
+        .match_subsystem("drm")?
+        .listen()?;
+
+    loop {
+        for event in socket.iter() {
+            let event = match event.event_type() {
+                udev::EventType::Add => Event::DeviceAdded,
+                udev::EventType::Remove => Event::DeviceRemoved,
+                _ => Event::DeviceChanged,
+            };
+
+            event_tx
+                .send(event)
+                .expect("Unable to send hotplug event, channel is dead");
+        }
+
+        thread::sleep(Duration::from_millis(UDEV_POLL_MS));
+    }
+}